@@ -19,6 +19,7 @@ use std::io::Read;
 use std::result::Result;
 use std::vec::Vec;
 
+use chrono::TimeZone as _;
 use thiserror::Error;
 
 use crate::content_hash::ContentHash;
@@ -127,6 +128,25 @@ impl Timestamp {
             tz_offset: datetime.offset().local_minus_utc() / 60,
         }
     }
+
+    /// Converts to the local time at `tz_offset`. Returns `None` if the
+    /// timestamp is out of the range `chrono` supports. An invalid offset is
+    /// treated as UTC.
+    pub fn to_datetime(&self) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+        let utc = match chrono::Utc.timestamp_opt(
+            self.timestamp.0.div_euclid(1000),
+            self.timestamp.0.rem_euclid(1000) as u32 * 1000000,
+        ) {
+            chrono::LocalResult::None => {
+                return None;
+            }
+            chrono::LocalResult::Single(x) => x,
+            chrono::LocalResult::Ambiguous(y, _z) => y,
+        };
+        let offset = chrono::FixedOffset::east_opt(self.tz_offset * 60)
+            .unwrap_or_else(|| chrono::FixedOffset::east_opt(0).unwrap());
+        Some(utc.with_timezone(&offset))
+    }
 }
 
 content_hash! {
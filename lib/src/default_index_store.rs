@@ -304,6 +304,14 @@ pub struct IndexPosition(u32);
 
 impl IndexPosition {
     pub const MAX: Self = IndexPosition(u32::MAX);
+
+    pub(crate) fn to_usize(self) -> usize {
+        self.0 as usize
+    }
+
+    pub(crate) fn from_usize(pos: usize) -> Self {
+        IndexPosition(u32::try_from(pos).unwrap())
+    }
 }
 
 // SmallVec reuses two pointer-size fields as inline area, which meas we can
@@ -837,6 +845,25 @@ impl<'a> CompositeIndex<'a> {
         self.0.segment_num_parent_commits() + self.0.segment_num_commits()
     }
 
+    /// Returns all commits in the index that have no indexed children, in
+    /// descending position order. Unlike the heads of a view, this includes
+    /// hidden commits.
+    pub fn all_heads(&self) -> Vec<CommitId> {
+        let num_commits = self.num_commits();
+        let mut is_head = vec![true; num_commits as usize];
+        for pos in 0..num_commits {
+            let entry = self.entry_by_pos(IndexPosition(pos));
+            for parent_pos in entry.parent_positions() {
+                is_head[parent_pos.0 as usize] = false;
+            }
+        }
+        (0..num_commits)
+            .rev()
+            .filter(|pos| is_head[*pos as usize])
+            .map(|pos| self.entry_by_pos(IndexPosition(pos)).commit_id())
+            .collect()
+    }
+
     pub fn stats(&self) -> IndexStats {
         let num_commits = self.num_commits();
         let mut num_merges = 0;
@@ -1141,6 +1168,11 @@ pub struct IndexStats {
     pub levels: Vec<IndexLevelStats>,
 }
 
+/// Wrapper to order index entries by position ascending, so an entry added
+/// to the index later compares greater. A `BinaryHeap` of these therefore
+/// pops descendants before their ancestors.
+///
+/// Entries of different indexes shouldn't be compared.
 #[derive(Clone, Eq, PartialEq)]
 pub struct IndexEntryByPosition<'a>(pub IndexEntry<'a>);
 
@@ -2373,6 +2405,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn index_entry_by_position_ordering() {
+        let mut new_change_id = change_id_generator();
+        let mut index = MutableIndexImpl::full(3, 16);
+        // Commit ids are in the opposite order of positions
+        let id_0 = CommitId::from_hex("222222");
+        let id_1 = CommitId::from_hex("111111");
+        let id_2 = CommitId::from_hex("000000");
+        index.add_commit_data(id_0.clone(), new_change_id(), &[]);
+        index.add_commit_data(id_1.clone(), new_change_id(), &[id_0.clone()]);
+        index.add_commit_data(id_2.clone(), new_change_id(), &[]);
+        let index = index.as_composite();
+        let entry_by_pos = |id: &CommitId| IndexEntryByPosition(index.entry_by_id(id).unwrap());
+
+        assert!(entry_by_pos(&id_0) < entry_by_pos(&id_1));
+        assert!(entry_by_pos(&id_1) < entry_by_pos(&id_2));
+        assert_eq!(
+            entry_by_pos(&id_1).cmp(&entry_by_pos(&id_1)),
+            Ordering::Equal
+        );
+        assert_eq!(
+            entry_by_pos(&id_2).cmp(&entry_by_pos(&id_0)),
+            Ordering::Greater
+        );
+
+        // Max-heap pops the highest position first
+        let mut heap = BinaryHeap::from([
+            entry_by_pos(&id_1),
+            entry_by_pos(&id_2),
+            entry_by_pos(&id_0),
+        ]);
+        let popped_ids = iter::from_fn(|| heap.pop())
+            .map(|entry| entry.0.commit_id())
+            .collect_vec();
+        assert_eq!(popped_ids, vec![id_2, id_1, id_0]);
+    }
+
     #[test]
     #[allow(clippy::redundant_clone)] // allow id_n.clone()
     fn neighbor_commit_ids() {
@@ -2673,6 +2742,34 @@ mod tests {
         assert_eq!(index.common_ancestors(&[id_1, id_2], &[id_3]), vec![id_0]);
     }
 
+    #[test]
+    fn test_common_ancestors_disjoint() {
+        let mut new_change_id = change_id_generator();
+        let mut index = MutableIndexImpl::full(3, 16);
+        // 2 3
+        // | |
+        // 0 1
+        let id_0 = CommitId::from_hex("000000");
+        let id_1 = CommitId::from_hex("111111");
+        let id_2 = CommitId::from_hex("222222");
+        let id_3 = CommitId::from_hex("333333");
+        index.add_commit_data(id_0.clone(), new_change_id(), &[]);
+        index.add_commit_data(id_1.clone(), new_change_id(), &[]);
+        index.add_commit_data(id_2.clone(), new_change_id(), &[id_0.clone()]);
+        index.add_commit_data(id_3.clone(), new_change_id(), &[id_1.clone()]);
+
+        assert_eq!(index.common_ancestors(&[id_0], &[id_1]), vec![]);
+        assert_eq!(
+            index.common_ancestors(&[id_2.clone()], &[id_3.clone()]),
+            vec![]
+        );
+        assert_eq!(index.common_ancestors(&[id_2.clone()], &[]), vec![]);
+        assert_eq!(
+            index.common_ancestors(&[id_2.clone(), id_3], &[id_2.clone()]),
+            vec![id_2]
+        );
+    }
+
     #[test]
     fn test_common_ancestors_criss_cross() {
         let mut new_change_id = change_id_generator();
@@ -2956,6 +3053,74 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_walk_revs_take_until_roots() {
+        let mut new_change_id = change_id_generator();
+        let mut index = MutableIndexImpl::full(3, 16);
+        // 6
+        // |\
+        // 4 5
+        // | |
+        // 3 |
+        // |/
+        // 2
+        // |
+        // 1
+        // |
+        // 0
+        let id_0 = CommitId::from_hex("000000");
+        let id_1 = CommitId::from_hex("111111");
+        let id_2 = CommitId::from_hex("222222");
+        let id_3 = CommitId::from_hex("333333");
+        let id_4 = CommitId::from_hex("444444");
+        let id_5 = CommitId::from_hex("555555");
+        let id_6 = CommitId::from_hex("666666");
+        index.add_commit_data(id_0.clone(), new_change_id(), &[]);
+        index.add_commit_data(id_1.clone(), new_change_id(), &[id_0.clone()]);
+        index.add_commit_data(id_2.clone(), new_change_id(), &[id_1.clone()]);
+        index.add_commit_data(id_3.clone(), new_change_id(), &[id_2.clone()]);
+        index.add_commit_data(id_4.clone(), new_change_id(), &[id_3.clone()]);
+        index.add_commit_data(id_5.clone(), new_change_id(), &[id_2.clone()]);
+        index.add_commit_data(id_6.clone(), new_change_id(), &[id_4.clone(), id_5.clone()]);
+
+        let walk_commit_ids = |heads: &[CommitId], roots: &[CommitId]| {
+            let index = index.as_composite();
+            let head_positions = to_positions_vec(index, heads);
+            let root_positions = to_positions_vec(index, roots);
+            index
+                .walk_revs(&head_positions, &[])
+                .take_until_roots(&root_positions)
+                .map(|entry| entry.commit_id())
+                .collect_vec()
+        };
+
+        // Entries below the lowest root aren't visited
+        assert_eq!(
+            walk_commit_ids(&[&id_6].map(Clone::clone), &[&id_4].map(Clone::clone)),
+            [&id_6, &id_5, &id_4].map(Clone::clone)
+        );
+        assert_eq!(
+            walk_commit_ids(
+                &[&id_6].map(Clone::clone),
+                &[&id_5, &id_3].map(Clone::clone)
+            ),
+            [&id_6, &id_5, &id_4, &id_3].map(Clone::clone)
+        );
+        assert_eq!(
+            walk_commit_ids(&[&id_6].map(Clone::clone), &[&id_6].map(Clone::clone)),
+            [&id_6].map(Clone::clone)
+        );
+
+        // No roots
+        assert_eq!(walk_commit_ids(&[&id_6].map(Clone::clone), &[]), []);
+
+        // The whole history is visited if the root is at the bottom
+        assert_eq!(
+            walk_commit_ids(&[&id_4].map(Clone::clone), &[&id_0].map(Clone::clone)),
+            [&id_4, &id_3, &id_2, &id_1, &id_0].map(Clone::clone)
+        );
+    }
+
     #[test]
     fn test_walk_revs_descendants_filtered_by_generation() {
         let mut new_change_id = change_id_generator();
@@ -12,27 +12,41 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
 use std::cmp::{Ordering, Reverse};
-use std::collections::{BinaryHeap, HashSet};
-use std::fmt;
-use std::iter::Peekable;
-use std::ops::Range;
-use std::sync::Arc;
-
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::iter::{self, Peekable};
+use std::ops::{ControlFlow, Range};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fmt, slice, thread};
+
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
 use itertools::Itertools;
+use thiserror::Error;
 
-use crate::backend::{ChangeId, CommitId, MillisSinceEpoch};
+use crate::backend::{
+    BackendResult, ChangeId, CommitId, MillisSinceEpoch, ObjectId, Signature, TreeId,
+};
+use crate::commit::Commit;
 use crate::default_index_store::{
     CompositeIndex, IndexEntry, IndexEntryByPosition, IndexPosition, RevWalk,
 };
-use crate::default_revset_graph_iterator::RevsetGraphIterator;
+use crate::default_revset_graph_iterator::{
+    RevsetGraphIterator, RevsetGraphMergedIterator, RevsetGraphWithChangeIdsIterator,
+};
 use crate::id_prefix::IdIndex;
 use crate::index::{HexPrefix, Index, PrefixResolution};
-use crate::matchers::{EverythingMatcher, Matcher, PrefixMatcher, Visit};
+use crate::matchers::{
+    CaseInsensitivePrefixMatcher, EverythingMatcher, Matcher, PrefixMatcher, Visit,
+};
+use crate::op_store::OperationId;
 use crate::repo_path::RepoPath;
 use crate::revset::{
-    ChangeIdIndex, ResolvedExpression, ResolvedPredicateExpression, Revset, RevsetEvaluationError,
-    RevsetFilterPredicate, RevsetGraphEdge, GENERATION_RANGE_FULL,
+    ChangeIdIndex, LatestTimestamp, ResolvedExpression, ResolvedPredicateExpression, Revset,
+    RevsetEvaluationError, RevsetFilterPredicate, RevsetGraphEdge, GENERATION_RANGE_FULL,
 };
 use crate::rewrite;
 use crate::store::Store;
@@ -50,10 +64,22 @@ impl<T: ToPredicateFn + ?Sized> ToPredicateFn for Box<T> {
     }
 }
 
+impl<T: ToPredicateFn + ?Sized> ToPredicateFn for Rc<T> {
+    fn to_predicate_fn(&self) -> Box<dyn FnMut(&IndexEntry<'_>) -> bool + '_> {
+        <T as ToPredicateFn>::to_predicate_fn(self)
+    }
+}
+
 trait InternalRevset<'index>: fmt::Debug + ToPredicateFn {
     // All revsets currently iterate in order of descending index position
     fn iter(&self) -> Box<dyn Iterator<Item = IndexEntry<'index>> + '_>;
 
+    /// Bounds on the number of entries, with the same meaning as
+    /// `Iterator::size_hint()`. Only eagerly evaluated sets know their size.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, None)
+    }
+
     fn into_predicate<'a>(self: Box<Self>) -> Box<dyn ToPredicateFn + 'a>
     where
         Self: 'a;
@@ -64,6 +90,166 @@ pub struct RevsetImpl<'index> {
     index: CompositeIndex<'index>,
 }
 
+/// Positions of an evaluated revset, built by `RevsetImpl::to_membership()`.
+pub struct RevsetMembership<'index> {
+    positions: HashSet<IndexPosition>,
+    index: CompositeIndex<'index>,
+}
+
+impl RevsetMembership<'_> {
+    /// Returns true if the commit is in the set. Commits missing from the
+    /// index are never in the set.
+    pub fn contains(&self, id: &CommitId) -> bool {
+        self.index
+            .commit_id_to_pos(id)
+            .map_or(false, |pos| self.positions.contains(&pos))
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+/// Index positions of an evaluated revset stored as one bit per position,
+/// built by `RevsetImpl::to_bitmap()`. Set operations over bitmaps are much
+/// cheaper than evaluating the corresponding revset expressions again.
+///
+/// Bitmaps to be combined must have been built against the same index.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RevsetBitmap {
+    // Trailing zero words are trimmed, so that equal sets compare equal.
+    words: Vec<u64>,
+}
+
+impl RevsetBitmap {
+    fn from_words(mut words: Vec<u64>) -> Self {
+        while words.last() == Some(&0) {
+            words.pop();
+        }
+        RevsetBitmap { words }
+    }
+
+    fn insert(&mut self, pos: IndexPosition) {
+        let (word, bit) = (pos.to_usize() / 64, pos.to_usize() % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << bit;
+    }
+
+    pub fn contains(&self, pos: IndexPosition) -> bool {
+        let (word, bit) = (pos.to_usize() / 64, pos.to_usize() % 64);
+        self.words.get(word).map_or(false, |w| w & (1 << bit) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn union(&self, other: &RevsetBitmap) -> RevsetBitmap {
+        let (longer, shorter) = if self.words.len() >= other.words.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let mut words = longer.words.clone();
+        for (w, o) in words.iter_mut().zip(&shorter.words) {
+            *w |= o;
+        }
+        RevsetBitmap::from_words(words)
+    }
+
+    pub fn intersection(&self, other: &RevsetBitmap) -> RevsetBitmap {
+        let words = iter::zip(&self.words, &other.words)
+            .map(|(w, o)| w & o)
+            .collect();
+        RevsetBitmap::from_words(words)
+    }
+
+    pub fn difference(&self, other: &RevsetBitmap) -> RevsetBitmap {
+        let mut words = self.words.clone();
+        for (w, o) in words.iter_mut().zip(&other.words) {
+            *w &= !o;
+        }
+        RevsetBitmap::from_words(words)
+    }
+
+    /// Iterates positions in descending order, which is the order of the
+    /// internal revsets.
+    pub fn iter(&self) -> impl Iterator<Item = IndexPosition> + '_ {
+        self.words.iter().enumerate().rev().flat_map(|(i, &w)| {
+            (0..64)
+                .rev()
+                .filter(move |bit| w & (1 << bit) != 0)
+                .map(move |bit| IndexPosition::from_usize(i * 64 + bit))
+        })
+    }
+}
+
+/// Evaluated commit ids of a revset, and the operation they were evaluated
+/// at. This can be stored to reuse the result from another process.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RevsetSnapshot {
+    pub operation_id: OperationId,
+    /// Commit ids in the order of [`Revset::iter()`].
+    pub commit_ids: Vec<CommitId>,
+}
+
+#[derive(Debug, Error)]
+pub enum RevsetSnapshotError {
+    #[error("Invalid revset snapshot: {0}")]
+    InvalidFormat(String),
+    #[error("Revset snapshot was taken at operation {} but the current operation is {}", .snapshot.hex(), .current.hex())]
+    OperationMismatch {
+        snapshot: OperationId,
+        current: OperationId,
+    },
+    #[error("Commit {} in revset snapshot is not indexed", .0.hex())]
+    CommitNotIndexed(CommitId),
+}
+
+impl RevsetSnapshot {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "operation_id": self.operation_id.hex(),
+            "commit_ids": self.commit_ids.iter().map(|id| id.hex()).collect_vec(),
+        })
+    }
+
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, RevsetSnapshotError> {
+        let invalid = |message: &str| RevsetSnapshotError::InvalidFormat(message.to_owned());
+        let parse_hex = |value: &serde_json::Value| {
+            value
+                .as_str()
+                .and_then(|hex| hex::decode(hex).ok())
+                .ok_or_else(|| invalid("expected hex string"))
+        };
+        let operation_id = OperationId::new(parse_hex(
+            json.get("operation_id")
+                .ok_or_else(|| invalid("missing operation_id"))?,
+        )?);
+        let commit_ids = json
+            .get("commit_ids")
+            .and_then(|value| value.as_array())
+            .ok_or_else(|| invalid("missing commit_ids"))?
+            .iter()
+            .map(|value| parse_hex(value).map(CommitId::new))
+            .try_collect()?;
+        Ok(RevsetSnapshot {
+            operation_id,
+            commit_ids,
+        })
+    }
+}
+
 impl<'index> RevsetImpl<'index> {
     fn new(
         revset: Box<dyn InternalRevset<'index> + 'index>,
@@ -78,6 +264,500 @@ impl<'index> RevsetImpl<'index> {
     pub fn iter_graph_impl(&self) -> RevsetGraphIterator<'_, 'index> {
         RevsetGraphIterator::new(self.inner.iter())
     }
+
+    /// Like `iter_graph()`, but also yields the change id of each commit
+    /// without building a `ChangeIdIndex`.
+    pub fn iter_graph_with_change_ids(&self) -> RevsetGraphWithChangeIdsIterator<'_, 'index> {
+        self.iter_graph_impl().with_change_ids()
+    }
+
+    /// Iterates the graphs of this and the `other` revset side by side, with
+    /// one row per commit in either set. Both revsets must be evaluated
+    /// against the same index.
+    pub fn iter_graph_merged<'a>(
+        &'a self,
+        other: &'a RevsetImpl<'index>,
+    ) -> RevsetGraphMergedIterator<'a, 'index> {
+        RevsetGraphMergedIterator::new(self.iter_graph_impl(), other.iter_graph_impl())
+    }
+
+    /// Collects commit ids in the set for O(1) membership tests.
+    pub fn to_commit_id_set(&self) -> HashSet<CommitId> {
+        // HashSet::from_iter() reserves capacity based on the size hint, which is
+        // exact for eagerly-evaluated sets.
+        self.inner.iter().map(|entry| entry.commit_id()).collect()
+    }
+
+    /// Returns the commits in the set that are also in `ids`, in the order of
+    /// the set. Ids not in the set are ignored.
+    pub fn intersect_commit_ids(&self, ids: &HashSet<CommitId>) -> Vec<CommitId> {
+        self.inner
+            .iter()
+            .map(|entry| entry.commit_id())
+            .filter(|id| ids.contains(id))
+            .collect()
+    }
+
+    /// Collects index positions in the set. This is cheaper than
+    /// `to_commit_id_set()` since no commit id has to be allocated.
+    pub fn to_position_set(&self) -> HashSet<IndexPosition> {
+        self.inner.iter().map(|entry| entry.position()).collect()
+    }
+
+    /// Collects index positions in the set as a bitmap, to be combined with
+    /// the bitmaps of other sets evaluated against the same index.
+    pub fn to_bitmap(&self) -> RevsetBitmap {
+        let mut bitmap = RevsetBitmap::default();
+        for entry in self.inner.iter() {
+            bitmap.insert(entry.position());
+        }
+        bitmap
+    }
+
+    /// Creates a set of the commits in the `bitmap`, which must have been built
+    /// against the same `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bitmap contains positions outside the index.
+    pub fn from_bitmap(bitmap: &RevsetBitmap, index: CompositeIndex<'index>) -> Self {
+        let index_entries = bitmap.iter().map(|pos| index.entry_by_pos(pos)).collect();
+        RevsetImpl::new(Box::new(EagerRevset { index_entries }), index)
+    }
+
+    /// Returns true if both sets contain the same commits. The sets are
+    /// walked side by side, stopping at the first commit only one of them
+    /// contains.
+    ///
+    /// Both sets must have been evaluated against the same index, since
+    /// commits are compared by index position.
+    pub fn set_eq(&self, other: &RevsetImpl<'_>) -> bool {
+        self.inner
+            .iter()
+            .map(|entry| entry.position())
+            .eq(other.inner.iter().map(|entry| entry.position()))
+    }
+
+    /// Hashes the commit ids in the set, in the order of `iter()`. Unlike
+    /// `set_eq()`, the result can be compared with sets evaluated against
+    /// other indexes, or stored as a cache key.
+    pub fn content_fingerprint(&self) -> [u8; 32] {
+        let mut hasher = Blake2b::<U32>::new();
+        for entry in self.inner.iter() {
+            let commit_id = entry.commit_id();
+            let bytes = commit_id.as_bytes();
+            // Prefix the length so adjacent ids can't be confused
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Materializes the set for repeated O(1) membership tests by commit id.
+    pub fn to_membership(&self) -> RevsetMembership<'index> {
+        RevsetMembership {
+            positions: self.to_position_set(),
+            index: self.index,
+        }
+    }
+
+    /// Iterates commit ids in batches of `batch` ids, in the same order as
+    /// `iter()`. Only the last batch may be shorter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `batch` is 0.
+    pub fn iter_chunked(&self, batch: usize) -> Box<dyn Iterator<Item = Vec<CommitId>> + '_> {
+        assert!(batch > 0, "batch size must be non-zero");
+        let mut entries = self.inner.iter();
+        Box::new(iter::from_fn(move || {
+            let ids = entries
+                .by_ref()
+                .take(batch)
+                .map(|entry| entry.commit_id())
+                .collect_vec();
+            (!ids.is_empty()).then_some(ids)
+        }))
+    }
+
+    /// Counts commits in the set by author name and email.
+    ///
+    /// Authors are listed in the order they first appear in the set. Since
+    /// every commit has to be loaded from the `store`, this is expensive for
+    /// large sets. If `limit` is specified, only that many commits from the
+    /// start of the set are visited.
+    pub fn count_authors(
+        &self,
+        store: &Arc<Store>,
+        limit: Option<usize>,
+    ) -> BackendResult<Vec<((String, String), usize)>> {
+        let mut counts: Vec<((String, String), usize)> = vec![];
+        let mut author_indices: HashMap<(String, String), usize> = HashMap::new();
+        for entry in self.inner.iter().take(limit.unwrap_or(usize::MAX)) {
+            let commit = store.get_commit(&entry.commit_id())?;
+            let author = commit.author();
+            match author_indices.entry((author.name.clone(), author.email.clone())) {
+                Entry::Occupied(entry) => counts[*entry.get()].1 += 1,
+                Entry::Vacant(entry) => {
+                    counts.push((entry.key().clone(), 1));
+                    entry.insert(counts.len() - 1);
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Returns the commits in the set sorted by committer timestamp, newest
+    /// first. Commits with the same timestamp are ordered by index position.
+    ///
+    /// Since every commit has to be loaded from the `store` before the first
+    /// one can be returned, this is expensive for large sets.
+    pub fn iter_by_date(&self, store: &Arc<Store>) -> BackendResult<Vec<CommitId>> {
+        let mut commits: Vec<(MillisSinceEpoch, CommitId)> = vec![];
+        for entry in self.inner.iter() {
+            let commit_id = entry.commit_id();
+            let commit = store.get_commit(&commit_id)?;
+            commits.push((commit.committer().timestamp.timestamp.clone(), commit_id));
+        }
+        // The sort is stable, and the entries are already ordered by position
+        commits.sort_by(|(timestamp1, _), (timestamp2, _)| timestamp2.cmp(timestamp1));
+        Ok(commits
+            .into_iter()
+            .map(|(_, commit_id)| commit_id)
+            .collect())
+    }
+
+    /// Classifies each commit in the set by the base it descends from.
+    ///
+    /// A base is considered a descendant of itself. If a commit descends from
+    /// more than one base, the one listed first in `bases` is chosen. Commits
+    /// not descending from any base are mapped to `None`. Bases not in the
+    /// index are ignored.
+    pub fn partition_by_bases(&self, bases: &[CommitId]) -> HashMap<CommitId, Option<CommitId>> {
+        let member_positions = self
+            .inner
+            .iter()
+            .map(|entry| entry.position())
+            .collect_vec();
+        let mut label_by_base_pos: HashMap<IndexPosition, usize> = HashMap::new();
+        for (i, base) in bases.iter().enumerate() {
+            if let Some(pos) = self.index.commit_id_to_pos(base) {
+                label_by_base_pos.entry(pos).or_insert(i);
+            }
+        }
+        let base_positions = label_by_base_pos.keys().copied().collect_vec();
+
+        // Propagate labels from parents to children in ascending position
+        // order. Only ancestors of the set members that are not older than the
+        // oldest base need to be visited.
+        let entries = self
+            .index
+            .walk_revs(&member_positions, &[])
+            .take_until_roots(&base_positions)
+            .collect_vec();
+        let mut label_by_pos: HashMap<IndexPosition, usize> = HashMap::new();
+        for entry in entries.iter().rev() {
+            // A base may descend from another base listed before it
+            let label = entry
+                .parent_positions()
+                .iter()
+                .filter_map(|parent_pos| label_by_pos.get(parent_pos))
+                .chain(label_by_base_pos.get(&entry.position()))
+                .min()
+                .copied();
+            if let Some(label) = label {
+                label_by_pos.insert(entry.position(), label);
+            }
+        }
+
+        self.inner
+            .iter()
+            .map(|entry| {
+                let base = label_by_pos
+                    .get(&entry.position())
+                    .map(|&label| bases[label].clone());
+                (entry.commit_id(), base)
+            })
+            .collect()
+    }
+
+    /// Finds a chain of parent edges from the `start` commit to its ancestor
+    /// `target`, visiting only commits in the set.
+    ///
+    /// The returned positions start with `start` and end with `target`.
+    /// Returns `None` if either commit isn't in the set, or if no such path
+    /// exists within the set.
+    pub fn walk_to(&self, start: &CommitId, target: &CommitId) -> Option<Vec<IndexPosition>> {
+        let start_pos = self.index.commit_id_to_pos(start)?;
+        let target_pos = self.index.commit_id_to_pos(target)?;
+        let positions = self.to_position_set();
+        if !positions.contains(&start_pos) || !positions.contains(&target_pos) {
+            return None;
+        }
+
+        // Visit descendants first so that the walk can stop at the target's
+        // position. Each visited position remembers the child it was reached
+        // from.
+        let mut child_by_pos = HashMap::new();
+        let mut work = BinaryHeap::from([start_pos]);
+        while let Some(pos) = work.pop() {
+            if pos == target_pos {
+                let mut path = vec![pos];
+                while let Some(&child_pos) = child_by_pos.get(path.last().unwrap()) {
+                    path.push(child_pos);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for parent_pos in self.index.entry_by_pos(pos).parent_positions() {
+                if parent_pos >= target_pos
+                    && positions.contains(&parent_pos)
+                    && !child_by_pos.contains_key(&parent_pos)
+                {
+                    child_by_pos.insert(parent_pos, pos);
+                    work.push(parent_pos);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the number of parent edges from `a` and `b` down to their
+    /// nearest common ancestor, summed over both sides. This is 0 if the
+    /// commits are the same, and 1 if one is a parent of the other.
+    ///
+    /// The ancestors are walked in the whole index, not only within the set.
+    /// Returns `None` if either commit isn't indexed, or if they have no
+    /// common ancestor.
+    pub fn closest_common_ancestor_distance(&self, a: &CommitId, b: &CommitId) -> Option<u32> {
+        let a_pos = self.index.commit_id_to_pos(a)?;
+        let b_pos = self.index.commit_id_to_pos(b)?;
+        let a_distances = self.ancestor_distances(a_pos);
+        let mut best: Option<u32> = None;
+        // Walk the ancestors of `b` breadth first, so the walk can stop once
+        // its own distance can't improve on the best sum found.
+        let mut visited = HashSet::from([b_pos]);
+        let mut level = vec![b_pos];
+        let mut b_distance = 0;
+        while !level.is_empty() && best.map_or(true, |best| b_distance < best) {
+            for pos in &level {
+                if let Some(a_distance) = a_distances.get(pos) {
+                    let sum = a_distance + b_distance;
+                    best = Some(best.map_or(sum, |best| best.min(sum)));
+                }
+            }
+            b_distance += 1;
+            let mut next_level = vec![];
+            for pos in level {
+                for parent_pos in self.index.entry_by_pos(pos).parent_positions() {
+                    if visited.insert(parent_pos) {
+                        next_level.push(parent_pos);
+                    }
+                }
+            }
+            level = next_level;
+        }
+        best
+    }
+
+    /// Finds the ancestor of `id` closest in parent edges whose commit id is in
+    /// `labeled`, such as the targets of branches and tags. The commit itself
+    /// is considered first. Among ancestors at the same distance, the one with
+    /// the greatest index position wins.
+    ///
+    /// Like `closest_common_ancestor_distance()`, the ancestors are walked in
+    /// the whole index. Returns `None` if `id` isn't indexed or none of its
+    /// ancestors is labeled.
+    pub fn nearest_labeled_ancestor(
+        &self,
+        id: &CommitId,
+        labeled: &HashSet<CommitId>,
+    ) -> Option<CommitId> {
+        let start_pos = self.index.commit_id_to_pos(id)?;
+        let labeled_positions: HashSet<_> = labeled
+            .iter()
+            .filter_map(|id| self.index.commit_id_to_pos(id))
+            .collect();
+        let mut visited = HashSet::from([start_pos]);
+        let mut level = vec![start_pos];
+        while !level.is_empty() {
+            if let Some(&pos) = level
+                .iter()
+                .filter(|pos| labeled_positions.contains(pos))
+                .max()
+            {
+                return Some(self.index.entry_by_pos(pos).commit_id());
+            }
+            let mut next_level = vec![];
+            for pos in level {
+                for parent_pos in self.index.entry_by_pos(pos).parent_positions() {
+                    if visited.insert(parent_pos) {
+                        next_level.push(parent_pos);
+                    }
+                }
+            }
+            level = next_level;
+        }
+        None
+    }
+
+    /// Maps each ancestor of `start` to its shortest number of parent edges
+    /// from `start`.
+    fn ancestor_distances(&self, start: IndexPosition) -> HashMap<IndexPosition, u32> {
+        let mut distances = HashMap::from([(start, 0)]);
+        let mut work = VecDeque::from([start]);
+        while let Some(pos) = work.pop_front() {
+            let distance = distances[&pos] + 1;
+            for parent_pos in self.index.entry_by_pos(pos).parent_positions() {
+                if let Entry::Vacant(entry) = distances.entry(parent_pos) {
+                    entry.insert(distance);
+                    work.push_back(parent_pos);
+                }
+            }
+        }
+        distances
+    }
+
+    /// Returns the generation number of the commit, which is 0 for the root
+    /// commit and one more than the greatest generation of its parents
+    /// otherwise.
+    ///
+    /// The commit only has to be in the index, not in the set. Returns `None`
+    /// if it isn't indexed.
+    pub fn generation_of(&self, id: &CommitId) -> Option<u32> {
+        self.index
+            .entry_by_id(id)
+            .map(|entry| entry.generation_number())
+    }
+
+    /// Groups commits in the set by generation number. Commits within each
+    /// group are in the order of `iter()`.
+    ///
+    /// Only generations of commits in the set are included, so the keys
+    /// aren't contiguous unless the set is.
+    pub fn by_generation(&self) -> BTreeMap<u32, Vec<CommitId>> {
+        let mut layers: BTreeMap<u32, Vec<CommitId>> = BTreeMap::new();
+        for entry in self.inner.iter() {
+            layers
+                .entry(entry.generation_number())
+                .or_default()
+                .push(entry.commit_id());
+        }
+        layers
+    }
+
+    /// Returns the commits in the set with at least one parent outside the
+    /// set, in the order of `iter()`.
+    ///
+    /// Unlike `roots()`, which excludes commits having any ancestor in the set,
+    /// this only looks at the immediate parents. For example, in `a | c` where
+    /// `c` is a child of `b` and `b` a child of `a`, both commits are in the
+    /// frontier, but only `a` is a root. The root commit has no parents, so it
+    /// is never in the frontier.
+    pub fn frontier(&self) -> Vec<CommitId> {
+        let entries = self.inner.iter().collect_vec();
+        // Visit parents before children, so that each parent in the set has
+        // been seen by the time its children are.
+        let mut seen_positions = HashSet::with_capacity(entries.len());
+        let mut frontier = vec![];
+        for entry in entries.iter().rev() {
+            if entry
+                .parent_positions()
+                .iter()
+                .any(|pos| !seen_positions.contains(pos))
+            {
+                frontier.push(entry.commit_id());
+            }
+            seen_positions.insert(entry.position());
+        }
+        frontier.reverse();
+        frontier
+    }
+
+    /// Returns the commits in the set that aren't a parent of any other commit
+    /// in the set, in the order of `iter()`.
+    ///
+    /// This is the counterpart of `frontier()` in the child direction. Unlike
+    /// `heads()`, which excludes commits having any descendant in the set,
+    /// this only looks at the immediate children. For example, in `a | c`
+    /// where `c` is a grandchild of `a`, both commits are leaves, but only `c`
+    /// is a head. Use `roots()` or `frontier()` for the other end of the set.
+    pub fn leaves(&self) -> Vec<CommitId> {
+        // Children are visited before their parents
+        let mut parent_positions = HashSet::new();
+        let mut leaves = vec![];
+        for entry in self.inner.iter() {
+            if !parent_positions.contains(&entry.position()) {
+                leaves.push(entry.commit_id());
+            }
+            parent_positions.extend(entry.parent_positions());
+        }
+        leaves
+    }
+
+    /// Wraps the set so that `callback` is called with the number of entries
+    /// emitted so far after every `interval` entries. Since the set is lazily
+    /// evaluated, this reports the progress of a slow query as its result is
+    /// consumed. The count restarts for each iteration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is 0.
+    pub fn with_progress(self, interval: usize, callback: impl Fn(usize) + 'index) -> Self {
+        assert!(interval > 0, "progress interval must be non-zero");
+        RevsetImpl {
+            inner: Box::new(ProgressRevset {
+                inner: self.inner,
+                interval,
+                callback: Box::new(callback),
+            }),
+            index: self.index,
+        }
+    }
+
+    /// Captures the commit ids in the set. The `operation_id` should be the
+    /// operation of the repo the revset was evaluated against.
+    pub fn to_snapshot(&self, operation_id: &OperationId) -> RevsetSnapshot {
+        RevsetSnapshot {
+            operation_id: operation_id.clone(),
+            commit_ids: self.iter().collect(),
+        }
+    }
+
+    /// Rebuilds the set from a snapshot taken by `to_snapshot()`.
+    ///
+    /// Fails if the snapshot wasn't taken at `operation_id`, which should be
+    /// the operation the `index` was loaded at, since the evaluated result
+    /// may be stale otherwise.
+    pub fn from_snapshot(
+        snapshot: &RevsetSnapshot,
+        operation_id: &OperationId,
+        index: CompositeIndex<'index>,
+    ) -> Result<Self, RevsetSnapshotError> {
+        if snapshot.operation_id != *operation_id {
+            return Err(RevsetSnapshotError::OperationMismatch {
+                snapshot: snapshot.operation_id.clone(),
+                current: operation_id.clone(),
+            });
+        }
+        let mut index_entries: Vec<_> = snapshot
+            .commit_ids
+            .iter()
+            .map(|id| {
+                index
+                    .entry_by_id(id)
+                    .ok_or_else(|| RevsetSnapshotError::CommitNotIndexed(id.clone()))
+            })
+            .try_collect()?;
+        // Snapshots are in iteration order, but don't rely on it since the
+        // internal revsets require entries in descending position order.
+        index_entries.sort_unstable_by_key(|entry| Reverse(entry.position()));
+        index_entries.dedup_by_key(|entry| entry.position());
+        Ok(RevsetImpl::new(
+            Box::new(EagerRevset { index_entries }),
+            index,
+        ))
+    }
 }
 
 impl fmt::Debug for RevsetImpl<'_> {
@@ -101,16 +781,27 @@ impl<'index> Revset<'index> for RevsetImpl<'index> {
         )
     }
 
+    fn for_each_commit_id(
+        &self,
+        f: &mut dyn FnMut(CommitId) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.inner
+            .iter()
+            .try_for_each(|index_entry| f(index_entry.commit_id()))
+    }
+
     fn iter_graph(&self) -> Box<dyn Iterator<Item = (CommitId, Vec<RevsetGraphEdge>)> + '_> {
         Box::new(RevsetGraphIterator::new(self.inner.iter()))
     }
 
     fn change_id_index(&self) -> Box<dyn ChangeIdIndex + 'index> {
         // TODO: Create a persistent lookup from change id to commit ids.
-        let mut pos_by_change = vec![];
-        for entry in self.inner.iter() {
-            pos_by_change.push((entry.change_id(), entry.position()));
-        }
+        let mut pos_by_change = Vec::with_capacity(self.inner.size_hint().0);
+        pos_by_change.extend(
+            self.inner
+                .iter()
+                .map(|entry| (entry.change_id(), entry.position())),
+        );
         let pos_by_change = IdIndex::from_vec(pos_by_change);
         Box::new(ChangeIdIndexImpl {
             index: self.index,
@@ -142,6 +833,19 @@ impl ChangeIdIndex for ChangeIdIndexImpl<'_> {
     fn shortest_unique_prefix_len(&self, change_id: &ChangeId) -> usize {
         self.pos_by_change.shortest_unique_prefix_len(change_id)
     }
+
+    fn shortest_unique_prefix_lens(&self) -> Vec<(CommitId, usize)> {
+        let mut lens = self
+            .pos_by_change
+            .shortest_unique_prefix_lens()
+            .into_iter()
+            .map(|(_, pos, len)| (*pos, len))
+            .collect_vec();
+        lens.sort_unstable_by_key(|&(pos, _)| Reverse(pos));
+        lens.into_iter()
+            .map(|(pos, len)| (self.index.entry_by_pos(pos).commit_id(), len))
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -162,6 +866,11 @@ impl<'index> InternalRevset<'index> for EagerRevset<'index> {
         Box::new(self.index_entries.iter().cloned())
     }
 
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.index_entries.len();
+        (len, Some(len))
+    }
+
     fn into_predicate<'a>(self: Box<Self>) -> Box<dyn ToPredicateFn + 'a>
     where
         Self: 'a,
@@ -337,6 +1046,13 @@ impl<'index, I1: Iterator<Item = IndexEntry<'index>>, I2: Iterator<Item = IndexE
             },
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower1, upper1) = self.iter1.size_hint();
+        let (lower2, upper2) = self.iter2.size_hint();
+        let upper = upper1.zip(upper2).and_then(|(u1, u2)| u1.checked_add(u2));
+        (lower1.max(lower2), upper)
+    }
 }
 
 #[derive(Debug)]
@@ -407,6 +1123,16 @@ impl<'index, I1: Iterator<Item = IndexEntry<'index>>, I2: Iterator<Item = IndexE
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, upper1) = self.iter1.size_hint();
+        let (_, upper2) = self.iter2.size_hint();
+        let upper = match (upper1, upper2) {
+            (Some(u1), Some(u2)) => Some(u1.min(u2)),
+            (u1, u2) => u1.or(u2),
+        };
+        (0, upper)
+    }
 }
 
 #[derive(Debug)]
@@ -479,35 +1205,385 @@ impl<'index, I1: Iterator<Item = IndexEntry<'index>>, I2: Iterator<Item = IndexE
             }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.iter1.size_hint().1)
+    }
 }
 
-pub fn evaluate<'index>(
-    expression: &ResolvedExpression,
-    store: &Arc<Store>,
-    index: CompositeIndex<'index>,
-) -> Result<RevsetImpl<'index>, RevsetEvaluationError> {
-    let context = EvaluationContext {
-        store: store.clone(),
-        index,
-    };
-    let internal_revset = context.evaluate(expression)?;
-    Ok(RevsetImpl::new(internal_revset, index))
+#[derive(Debug)]
+struct SymmetricDifferenceRevset<'index> {
+    set1: Box<dyn InternalRevset<'index> + 'index>,
+    set2: Box<dyn InternalRevset<'index> + 'index>,
 }
 
-struct EvaluationContext<'index> {
-    store: Arc<Store>,
-    index: CompositeIndex<'index>,
+impl<'index> InternalRevset<'index> for SymmetricDifferenceRevset<'index> {
+    fn iter(&self) -> Box<dyn Iterator<Item = IndexEntry<'index>> + '_> {
+        Box::new(SymmetricDifferenceRevsetIterator {
+            iter1: self.set1.iter().peekable(),
+            iter2: self.set2.iter().peekable(),
+        })
+    }
+
+    fn into_predicate<'a>(self: Box<Self>) -> Box<dyn ToPredicateFn + 'a>
+    where
+        Self: 'a,
+    {
+        self
+    }
 }
 
-fn to_u32_generation_range(range: &Range<u64>) -> Result<Range<u32>, RevsetEvaluationError> {
-    let start = range.start.try_into().map_err(|_| {
-        RevsetEvaluationError::Other(format!(
-            "Lower bound of generation ({}) is too large",
-            range.start
-        ))
-    })?;
-    let end = range.end.try_into().unwrap_or(u32::MAX);
-    Ok(start..end)
+impl ToPredicateFn for SymmetricDifferenceRevset<'_> {
+    fn to_predicate_fn(&self) -> Box<dyn FnMut(&IndexEntry<'_>) -> bool + '_> {
+        let mut p1 = self.set1.to_predicate_fn();
+        let mut p2 = self.set2.to_predicate_fn();
+        Box::new(move |entry| p1(entry) != p2(entry))
+    }
+}
+
+struct SymmetricDifferenceRevsetIterator<
+    'index,
+    I1: Iterator<Item = IndexEntry<'index>>,
+    I2: Iterator<Item = IndexEntry<'index>>,
+> {
+    iter1: Peekable<I1>,
+    iter2: Peekable<I2>,
+}
+
+impl<'index, I1: Iterator<Item = IndexEntry<'index>>, I2: Iterator<Item = IndexEntry<'index>>>
+    Iterator for SymmetricDifferenceRevsetIterator<'index, I1, I2>
+{
+    type Item = IndexEntry<'index>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match (self.iter1.peek(), self.iter2.peek()) {
+                (None, _) => {
+                    return self.iter2.next();
+                }
+                (_, None) => {
+                    return self.iter1.next();
+                }
+                (Some(entry1), Some(entry2)) => match entry1.position().cmp(&entry2.position()) {
+                    Ordering::Less => {
+                        return self.iter2.next();
+                    }
+                    Ordering::Equal => {
+                        self.iter1.next();
+                        self.iter2.next();
+                    }
+                    Ordering::Greater => {
+                        return self.iter1.next();
+                    }
+                },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, upper1) = self.iter1.size_hint();
+        let (_, upper2) = self.iter2.size_hint();
+        let upper = upper1.zip(upper2).and_then(|(u1, u2)| u1.checked_add(u2));
+        (0, upper)
+    }
+}
+
+/// Filter that reads commits from the store, loading the commits and testing
+/// the predicate on multiple threads. The matching entries are emitted in the
+/// original order.
+struct CommitPrefetchFilterRevset<'index> {
+    candidates: Box<dyn InternalRevset<'index> + 'index>,
+    store: Arc<Store>,
+    predicate: CommitPredicateFn,
+    threads: usize,
+}
+
+/// Number of entries to look ahead per thread.
+const COMMIT_PREFETCH_LOOKAHEAD_PER_THREAD: usize = 16;
+
+impl fmt::Debug for CommitPrefetchFilterRevset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitPrefetchFilterRevset")
+            .field("candidates", &self.candidates)
+            .field("threads", &self.threads)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'index> InternalRevset<'index> for CommitPrefetchFilterRevset<'index> {
+    fn iter(&self) -> Box<dyn Iterator<Item = IndexEntry<'index>> + '_> {
+        Box::new(CommitPrefetchFilterIterator::new(
+            self.candidates.iter(),
+            &self.store,
+            &self.predicate,
+            self.threads,
+        ))
+    }
+
+    fn into_predicate<'a>(self: Box<Self>) -> Box<dyn ToPredicateFn + 'a>
+    where
+        Self: 'a,
+    {
+        self
+    }
+}
+
+impl ToPredicateFn for CommitPrefetchFilterRevset<'_> {
+    fn to_predicate_fn(&self) -> Box<dyn FnMut(&IndexEntry<'_>) -> bool + '_> {
+        let mut p = self.candidates.to_predicate_fn();
+        Box::new(move |entry| {
+            p(entry) && {
+                let commit = self.store.get_commit(&entry.commit_id()).unwrap();
+                (self.predicate)(&commit)
+            }
+        })
+    }
+}
+
+/// Sends the upcoming candidates to a fixed set of worker threads through a
+/// bounded channel, and reorders their results to emit the matching entries
+/// in the order of the candidates.
+struct CommitPrefetchFilterIterator<'index, I> {
+    candidates: I,
+    lookahead: usize,
+    /// Entries sent to the workers, starting at sequence number `next_seq`.
+    pending: VecDeque<IndexEntry<'index>>,
+    next_seq: usize,
+    /// Results received ahead of `next_seq`.
+    reorder_buffer: HashMap<usize, BackendResult<bool>>,
+    job_sender: Option<mpsc::SyncSender<(usize, CommitId)>>,
+    result_receiver: mpsc::Receiver<(usize, BackendResult<bool>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl<'index, I> CommitPrefetchFilterIterator<'index, I> {
+    fn new(
+        candidates: I,
+        store: &Arc<Store>,
+        predicate: &CommitPredicateFn,
+        threads: usize,
+    ) -> Self {
+        let lookahead = threads * COMMIT_PREFETCH_LOOKAHEAD_PER_THREAD;
+        let (job_sender, job_receiver) = mpsc::sync_channel::<(usize, CommitId)>(lookahead);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, result_receiver) = mpsc::channel();
+        let workers = (0..threads)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                let store = store.clone();
+                let predicate = predicate.clone();
+                thread::spawn(move || loop {
+                    let job = job_receiver.lock().unwrap().recv();
+                    let (seq, commit_id) = match job {
+                        Ok(job) => job,
+                        Err(mpsc::RecvError) => break,
+                    };
+                    // Load errors are carried to the iterator, which reports
+                    // them in order.
+                    let result = store
+                        .get_commit(&commit_id)
+                        .map(|commit| predicate(&commit));
+                    if result_sender.send((seq, result)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        CommitPrefetchFilterIterator {
+            candidates,
+            lookahead,
+            pending: VecDeque::new(),
+            next_seq: 0,
+            reorder_buffer: HashMap::new(),
+            job_sender: Some(job_sender),
+            result_receiver,
+            workers,
+        }
+    }
+
+    fn receive_result(&mut self, seq: usize) -> BackendResult<bool> {
+        loop {
+            if let Some(result) = self.reorder_buffer.remove(&seq) {
+                return result;
+            }
+            let (received_seq, result) = self
+                .result_receiver
+                .recv()
+                .expect("commit prefetch workers should be alive");
+            self.reorder_buffer.insert(received_seq, result);
+        }
+    }
+}
+
+impl<'index, I: Iterator<Item = IndexEntry<'index>>> Iterator
+    for CommitPrefetchFilterIterator<'index, I>
+{
+    type Item = IndexEntry<'index>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.pending.len() < self.lookahead {
+                let entry = match self.candidates.next() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let seq = self.next_seq + self.pending.len();
+                // The channel can hold all pending entries, so this doesn't block.
+                self.job_sender
+                    .as_ref()
+                    .unwrap()
+                    .send((seq, entry.commit_id()))
+                    .expect("commit prefetch workers should be alive");
+                self.pending.push_back(entry);
+            }
+            let entry = self.pending.pop_front()?;
+            let result = self.receive_result(self.next_seq);
+            self.next_seq += 1;
+            // Like the serial predicate, fail if the commit can't be loaded.
+            if result.unwrap() {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+impl<I> Drop for CommitPrefetchFilterIterator<'_, I> {
+    fn drop(&mut self) {
+        // Disconnect the channel so the workers exit once idle.
+        self.job_sender.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+struct ProgressRevset<'index> {
+    inner: Box<dyn InternalRevset<'index> + 'index>,
+    interval: usize,
+    callback: Box<dyn Fn(usize) + 'index>,
+}
+
+impl fmt::Debug for ProgressRevset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressRevset")
+            .field("inner", &self.inner)
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'index> InternalRevset<'index> for ProgressRevset<'index> {
+    fn iter(&self) -> Box<dyn Iterator<Item = IndexEntry<'index>> + '_> {
+        Box::new(self.inner.iter().enumerate().map(|(i, entry)| {
+            let count = i + 1;
+            if count % self.interval == 0 {
+                (self.callback)(count);
+            }
+            entry
+        }))
+    }
+
+    fn into_predicate<'a>(self: Box<Self>) -> Box<dyn ToPredicateFn + 'a>
+    where
+        Self: 'a,
+    {
+        self.inner.into_predicate()
+    }
+}
+
+impl ToPredicateFn for ProgressRevset<'_> {
+    fn to_predicate_fn(&self) -> Box<dyn FnMut(&IndexEntry<'_>) -> bool + '_> {
+        self.inner.to_predicate_fn()
+    }
+}
+
+/// Options to tune how revsets are evaluated.
+#[derive(Clone, Debug, Default)]
+pub struct EvaluationOptions {
+    /// Number of threads to load commits and test filter predicates on if the
+    /// predicates only read the commit objects, such as `description()` and
+    /// `author()`. The filtered commits are still emitted in order.
+    /// Prefetching is disabled if this is 0 or 1, which is the default.
+    pub commit_prefetch_threads: usize,
+    /// Fail to evaluate `roots..heads` if none of the roots is an ancestor of
+    /// any of the heads. The range is then the same as `..heads` minus the
+    /// common ancestors, which usually means that it was mistyped. This also
+    /// applies to ranges optimized from `::heads ~ ::roots`.
+    pub strict_ranges: bool,
+}
+
+/// Evaluates the `expression` against the `index`.
+///
+/// The `store` and `index` don't have to belong to the head repo. Any pair
+/// loaded at a past operation can be passed in as long as the `expression`
+/// was resolved against the view of the same operation.
+pub fn evaluate<'index>(
+    expression: &ResolvedExpression,
+    store: &Arc<Store>,
+    index: CompositeIndex<'index>,
+) -> Result<RevsetImpl<'index>, RevsetEvaluationError> {
+    evaluate_with_options(expression, store, index, &EvaluationOptions::default())
+}
+
+/// Like `evaluate()`, but with non-default `options`.
+pub fn evaluate_with_options<'index>(
+    expression: &ResolvedExpression,
+    store: &Arc<Store>,
+    index: CompositeIndex<'index>,
+    options: &EvaluationOptions,
+) -> Result<RevsetImpl<'index>, RevsetEvaluationError> {
+    let shared_filters = find_shared_filters(expression)
+        .into_iter()
+        .map(|predicate| (predicate, None))
+        .collect();
+    let context = EvaluationContext {
+        store: store.clone(),
+        index,
+        options: options.clone(),
+        shared_filters: RefCell::new(shared_filters),
+    };
+    let internal_revset = context.evaluate(expression)?;
+    Ok(RevsetImpl::new(internal_revset, index))
+}
+
+/// Returns a set containing no commits, e.g. to start a fold over other sets.
+pub fn empty_revset(index: CompositeIndex<'_>) -> RevsetImpl<'_> {
+    RevsetImpl::new(Box::new(EagerRevset::empty()), index)
+}
+
+struct EvaluationContext<'index> {
+    store: Arc<Store>,
+    index: CompositeIndex<'index>,
+    options: EvaluationOptions,
+    /// Filters appearing more than once in the expression, memoized when
+    /// first evaluated.
+    shared_filters: RefCell<Vec<(RevsetFilterPredicate, Option<SharedPredicate<'index>>)>>,
+}
+
+/// Nearest ancestors of each candidate within the candidates, in descending
+/// position order. Every candidate has an entry. Candidates with no ancestor
+/// within the candidates map to an empty list, and are compared with the root
+/// tree.
+type CandidateBases = HashMap<IndexPosition, Vec<IndexPosition>>;
+
+type SharedPredicate<'index> = Rc<MemoizedPredicate<Box<dyn ToPredicateFn + 'index>>>;
+
+fn to_u32_generation_range(range: &Range<u64>) -> Result<Range<u32>, RevsetEvaluationError> {
+    if range.start > range.end {
+        return Err(RevsetEvaluationError::Other(format!(
+            "Lower bound of generation ({}) is greater than upper bound ({})",
+            range.start, range.end
+        )));
+    }
+    let start = range.start.try_into().map_err(|_| {
+        RevsetEvaluationError::Other(format!(
+            "Lower bound of generation ({}) is too large",
+            range.start
+        ))
+    })?;
+    let end = range.end.try_into().unwrap_or(u32::MAX);
+    Ok(start..end)
 }
 
 impl<'index> EvaluationContext<'index> {
@@ -538,6 +1614,13 @@ impl<'index> EvaluationContext<'index> {
                 let root_positions = root_set.iter().map(|entry| entry.position()).collect_vec();
                 let head_set = self.evaluate(heads)?;
                 let head_positions = head_set.iter().map(|entry| entry.position()).collect_vec();
+                if self.options.strict_ranges
+                    && !self.has_root_ancestor(&root_positions, &head_positions)
+                {
+                    return Err(RevsetEvaluationError::Other(
+                        "None of the range roots are ancestors of its heads".to_owned(),
+                    ));
+                }
                 let walk = self.index.walk_revs(&head_positions, &root_positions);
                 if generation == &GENERATION_RANGE_FULL {
                     Ok(Box::new(RevWalkRevset { walk }))
@@ -577,12 +1660,9 @@ impl<'index> EvaluationContext<'index> {
             }
             ResolvedExpression::Heads(candidates) => {
                 let candidate_set = self.evaluate(candidates)?;
-                let candidate_ids = candidate_set
-                    .iter()
-                    .map(|entry| entry.commit_id())
-                    .collect_vec();
-                Ok(Box::new(self.revset_for_commit_ids(
-                    &self.index.heads(&mut candidate_ids.iter()),
+                Ok(Box::new(take_heads_revset(
+                    self.index,
+                    candidate_set.as_ref(),
                 )))
             }
             ResolvedExpression::Roots(candidates) => {
@@ -602,13 +1682,77 @@ impl<'index> EvaluationContext<'index> {
                 }
                 Ok(Box::new(EagerRevset { index_entries }))
             }
-            ResolvedExpression::Latest { candidates, count } => {
+            ResolvedExpression::NthParent { children, index } => {
+                let mut index_entries = self
+                    .evaluate(children)?
+                    .iter()
+                    .filter_map(|child| child.parent_positions().get(*index).copied())
+                    .map(|pos| self.index.entry_by_pos(pos))
+                    .collect_vec();
+                index_entries.sort_unstable_by_key(|entry| Reverse(entry.position()));
+                index_entries.dedup();
+                Ok(Box::new(EagerRevset { index_entries }))
+            }
+            ResolvedExpression::Latest {
+                candidates,
+                count,
+                timestamp,
+            } => {
+                let candidate_set = self.evaluate(candidates)?;
+                Ok(Box::new(self.take_latest_revset(
+                    candidate_set.as_ref(),
+                    *count,
+                    *timestamp,
+                )))
+            }
+            ResolvedExpression::LatestPerChange(candidates) => {
+                let candidate_set = self.evaluate(candidates)?;
+                Ok(Box::new(
+                    self.take_latest_per_change_revset(candidate_set.as_ref()),
+                ))
+            }
+            ResolvedExpression::ChangeHeads(candidates) => {
                 let candidate_set = self.evaluate(candidates)?;
                 Ok(Box::new(
-                    self.take_latest_revset(candidate_set.as_ref(), *count),
+                    self.take_change_heads_revset(candidate_set.as_ref()),
                 ))
             }
+            ResolvedExpression::ChildrenWithin { roots, candidates } => {
+                let root_set = self.evaluate(roots)?;
+                let root_positions: HashSet<_> =
+                    root_set.iter().map(|entry| entry.position()).collect();
+                Ok(Box::new(FilterRevset {
+                    candidates: self.evaluate(candidates)?,
+                    predicate: PurePredicateFn(move |entry: &IndexEntry| {
+                        entry
+                            .parent_positions()
+                            .iter()
+                            .any(|parent_pos| root_positions.contains(parent_pos))
+                    }),
+                }))
+            }
+            ResolvedExpression::ChangeIdPrefix { candidates, prefix } => {
+                let candidate_set = self.evaluate(candidates)?;
+                Ok(Box::new(self.take_change_id_prefix_revset(
+                    candidate_set.as_ref(),
+                    prefix,
+                )))
+            }
+            ResolvedExpression::Coalesce(expressions) => {
+                for expression in expressions {
+                    let set = self.evaluate(expression)?;
+                    if set.iter().next().is_some() {
+                        return Ok(set);
+                    }
+                }
+                Ok(Box::new(EagerRevset::empty()))
+            }
             ResolvedExpression::Union(expression1, expression2) => {
+                if is_empty_expression(expression1) {
+                    return self.evaluate(expression2);
+                } else if is_empty_expression(expression2) {
+                    return self.evaluate(expression1);
+                }
                 let set1 = self.evaluate(expression1)?;
                 let set2 = self.evaluate(expression2)?;
                 Ok(Box::new(UnionRevset { set1, set2 }))
@@ -616,48 +1760,203 @@ impl<'index> EvaluationContext<'index> {
             ResolvedExpression::FilterWithin {
                 candidates,
                 predicate,
-            } => Ok(Box::new(FilterRevset {
-                candidates: self.evaluate(candidates)?,
-                predicate: self.evaluate_predicate(predicate)?,
-            })),
+            } => {
+                if is_empty_expression(candidates) {
+                    return Ok(Box::new(EagerRevset::empty()));
+                }
+                // Test index-only predicates first so commits rejected by them
+                // never have to be loaded from the store.
+                if let ResolvedExpression::FilterWithin {
+                    candidates: inner_candidates,
+                    predicate: inner_predicate,
+                } = candidates.as_ref()
+                {
+                    if is_index_only_predicate(predicate)
+                        && !is_index_only_predicate(inner_predicate)
+                        && !needs_candidate_positions(inner_predicate)
+                    {
+                        return self.evaluate(&ResolvedExpression::FilterWithin {
+                            candidates: Box::new(ResolvedExpression::FilterWithin {
+                                candidates: inner_candidates.clone(),
+                                predicate: predicate.clone(),
+                            }),
+                            predicate: inner_predicate.clone(),
+                        });
+                    }
+                }
+                let candidates = self.evaluate(candidates)?;
+                if self.options.commit_prefetch_threads > 1 {
+                    if let Some(commit_predicate) = build_commit_predicate_expression_fn(predicate)
+                    {
+                        return Ok(Box::new(CommitPrefetchFilterRevset {
+                            candidates,
+                            store: self.store.clone(),
+                            predicate: commit_predicate,
+                            threads: self.options.commit_prefetch_threads,
+                        }));
+                    }
+                }
+                let candidate_bases = needs_candidate_positions(predicate).then(|| {
+                    let candidate_positions = candidates
+                        .iter()
+                        .map(|entry| entry.position())
+                        .collect_vec();
+                    Rc::new(nearest_candidate_ancestors(
+                        self.index,
+                        &candidate_positions,
+                    ))
+                });
+                Ok(Box::new(FilterRevset {
+                    predicate: self.evaluate_predicate(
+                        predicate,
+                        &*candidates,
+                        candidate_bases.as_ref(),
+                    )?,
+                    candidates,
+                }))
+            }
             ResolvedExpression::Intersection(expression1, expression2) => {
+                if is_empty_expression(expression1) || is_empty_expression(expression2) {
+                    return Ok(Box::new(EagerRevset::empty()));
+                }
+                if let Some(head_id) = as_single_ancestors_head(expression2) {
+                    return self.evaluate_ancestors_of_within(expression1, head_id);
+                } else if let Some(head_id) = as_single_ancestors_head(expression1) {
+                    return self.evaluate_ancestors_of_within(expression2, head_id);
+                }
                 let set1 = self.evaluate(expression1)?;
                 let set2 = self.evaluate(expression2)?;
                 Ok(Box::new(IntersectionRevset { set1, set2 }))
             }
             ResolvedExpression::Difference(expression1, expression2) => {
+                if is_empty_expression(expression1) {
+                    return Ok(Box::new(EagerRevset::empty()));
+                } else if is_empty_expression(expression2) {
+                    return self.evaluate(expression1);
+                }
                 let set1 = self.evaluate(expression1)?;
                 let set2 = self.evaluate(expression2)?;
                 Ok(Box::new(DifferenceRevset { set1, set2 }))
             }
+            ResolvedExpression::SymmetricDifference(expression1, expression2) => {
+                if is_empty_expression(expression1) {
+                    return self.evaluate(expression2);
+                } else if is_empty_expression(expression2) {
+                    return self.evaluate(expression1);
+                }
+                let set1 = self.evaluate(expression1)?;
+                let set2 = self.evaluate(expression2)?;
+                Ok(Box::new(SymmetricDifferenceRevset { set1, set2 }))
+            }
         }
     }
 
     fn evaluate_predicate(
         &self,
         expression: &ResolvedPredicateExpression,
+        candidates: &dyn InternalRevset<'index>,
+        candidate_bases: Option<&Rc<CandidateBases>>,
     ) -> Result<Box<dyn ToPredicateFn + 'index>, RevsetEvaluationError> {
         match expression {
-            ResolvedPredicateExpression::Filter(predicate) => Ok(build_predicate_fn(
-                self.store.clone(),
-                self.index,
-                predicate,
-            )),
+            ResolvedPredicateExpression::Filter(predicate) => {
+                let mut shared_filters = self.shared_filters.borrow_mut();
+                if let Some((_, shared)) = shared_filters
+                    .iter_mut()
+                    .find(|(shared_predicate, _)| shared_predicate == predicate)
+                {
+                    let shared = shared.get_or_insert_with(|| {
+                        Rc::new(MemoizedPredicate::new(build_predicate_fn(
+                            self.store.clone(),
+                            self.index,
+                            predicate,
+                            candidate_bases,
+                        )))
+                    });
+                    return Ok(Box::new(shared.clone()));
+                }
+                Ok(build_predicate_fn(
+                    self.store.clone(),
+                    self.index,
+                    predicate,
+                    candidate_bases,
+                ))
+            }
             ResolvedPredicateExpression::Set(expression) => {
                 Ok(self.evaluate(expression)?.into_predicate())
             }
+            ResolvedPredicateExpression::SameTreeAs(expression) => {
+                // Look up the tree ids once, so the candidates only need to be
+                // compared by id. The candidates are compared up front since
+                // the predicate function can't report store errors.
+                let load_tree_id = |entry: IndexEntry<'index>| -> Result<TreeId, _> {
+                    let commit = self
+                        .store
+                        .get_commit(&entry.commit_id())
+                        .map_err(RevsetEvaluationError::StoreError)?;
+                    Ok(commit.tree_id().clone())
+                };
+                let tree_ids: HashSet<TreeId> = self
+                    .evaluate(expression)?
+                    .iter()
+                    .map(load_tree_id)
+                    .try_collect()?;
+                let mut positions = HashSet::new();
+                for entry in candidates.iter() {
+                    let position = entry.position();
+                    if tree_ids.contains(&load_tree_id(entry)?) {
+                        positions.insert(position);
+                    }
+                }
+                Ok(pure_predicate_fn(move |entry| {
+                    positions.contains(&entry.position())
+                }))
+            }
             ResolvedPredicateExpression::NotIn(complement) => {
-                let set = self.evaluate_predicate(complement)?;
+                let set = self.evaluate_predicate(complement, candidates, candidate_bases)?;
                 Ok(Box::new(NotInPredicate(set)))
             }
             ResolvedPredicateExpression::Union(expression1, expression2) => {
-                let set1 = self.evaluate_predicate(expression1)?;
-                let set2 = self.evaluate_predicate(expression2)?;
+                let set1 = self.evaluate_predicate(expression1, candidates, candidate_bases)?;
+                let set2 = self.evaluate_predicate(expression2, candidates, candidate_bases)?;
                 Ok(Box::new(UnionPredicate { set1, set2 }))
             }
         }
     }
 
+    /// Calculates `candidates & :head_id`. The ancestors are walked at most
+    /// once no matter how many times the candidates are iterated, and each
+    /// candidate is tested by a lookup in the positions walked so far.
+    fn evaluate_ancestors_of_within(
+        &self,
+        candidates: &ResolvedExpression,
+        head_id: &CommitId,
+    ) -> Result<Box<dyn InternalRevset<'index> + 'index>, RevsetEvaluationError> {
+        let candidates = self.evaluate(candidates)?;
+        let head_pos = self.index.commit_id_to_pos(head_id).unwrap();
+        Ok(Box::new(FilterRevset {
+            candidates,
+            predicate: AncestorsPositionSetPredicate::new(self.index.walk_revs(&[head_pos], &[])),
+        }))
+    }
+
+    /// Whether any of the roots is an ancestor of any of the heads. Empty
+    /// roots, such as in `..heads`, are accepted.
+    fn has_root_ancestor(
+        &self,
+        root_positions: &[IndexPosition],
+        head_positions: &[IndexPosition],
+    ) -> bool {
+        let min_root_pos = match root_positions.iter().min() {
+            Some(&pos) => pos,
+            None => return true,
+        };
+        let root_positions: HashSet<_> = root_positions.iter().copied().collect();
+        self.index
+            .walk_revs(head_positions, &[])
+            .take_while(|entry| entry.position() >= min_root_pos)
+            .any(|entry| root_positions.contains(&entry.position()))
+    }
+
     fn walk_ancestors<'a, S>(&self, head_set: &S) -> RevWalk<'index>
     where
         S: InternalRevset<'a> + ?Sized,
@@ -728,7 +2027,7 @@ impl<'index> EvaluationContext<'index> {
     }
 
     fn revset_for_commit_ids(&self, commit_ids: &[CommitId]) -> EagerRevset<'index> {
-        let mut index_entries = vec![];
+        let mut index_entries = Vec::with_capacity(commit_ids.len());
         for id in commit_ids {
             index_entries.push(self.index.entry_by_id(id).unwrap());
         }
@@ -737,51 +2036,271 @@ impl<'index> EvaluationContext<'index> {
         EagerRevset { index_entries }
     }
 
-    fn take_latest_revset(
+    /// Picks the commits whose change id starts with the `prefix`. Unlike
+    /// symbol resolution, an ambiguous prefix selects all matching changes,
+    /// including the divergent commits of each.
+    fn take_change_id_prefix_revset(
         &self,
         candidate_set: &dyn InternalRevset<'index>,
-        count: usize,
+        prefix: &HexPrefix,
     ) -> EagerRevset<'index> {
-        if count == 0 {
-            return EagerRevset::empty();
-        }
+        let mut pos_by_change = Vec::with_capacity(candidate_set.size_hint().0);
+        pos_by_change.extend(
+            candidate_set
+                .iter()
+                .map(|entry| (entry.change_id(), entry.position())),
+        );
+        let pos_by_change = IdIndex::from_vec(pos_by_change);
+        let mut index_entries = pos_by_change
+            .resolve_prefix_range(prefix)
+            .map(|(_, pos)| self.index.entry_by_pos(*pos))
+            .collect_vec();
+        index_entries.sort_unstable_by_key(|entry| Reverse(entry.position()));
+        EagerRevset { index_entries }
+    }
 
-        #[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
-        struct Item<'a> {
-            timestamp: MillisSinceEpoch,
-            entry: IndexEntryByPosition<'a>, // tie-breaker
+    /// Picks the commit with the latest committer timestamp for each change
+    /// id. Ties are broken by index position. Commits are only loaded for
+    /// changes with multiple commits in the set.
+    fn take_latest_per_change_revset(
+        &self,
+        candidate_set: &dyn InternalRevset<'index>,
+    ) -> EagerRevset<'index> {
+        let mut entries_by_change: HashMap<ChangeId, Vec<IndexEntry<'index>>> = HashMap::new();
+        for entry in candidate_set.iter() {
+            entries_by_change
+                .entry(entry.change_id())
+                .or_default()
+                .push(entry);
         }
-
-        let make_rev_item = |entry: IndexEntry<'index>| {
-            let commit = self.store.get_commit(&entry.commit_id()).unwrap();
-            Reverse(Item {
-                timestamp: commit.committer().timestamp.timestamp.clone(),
-                entry: IndexEntryByPosition(entry),
+        let mut index_entries = entries_by_change
+            .into_values()
+            .map(|entries| {
+                if entries.len() == 1 {
+                    return entries.into_iter().next().unwrap();
+                }
+                entries
+                    .into_iter()
+                    .max_by_key(|entry| {
+                        let commit = self.store.get_commit(&entry.commit_id()).unwrap();
+                        (
+                            commit.committer().timestamp.timestamp.clone(),
+                            entry.position(),
+                        )
+                    })
+                    .unwrap()
             })
-        };
+            .collect_vec();
+        index_entries.sort_unstable_by_key(|entry| Reverse(entry.position()));
+        EagerRevset { index_entries }
+    }
 
-        // Maintain min-heap containing the latest (greatest) count items. For small
-        // count and large candidate set, this is probably cheaper than building vec
-        // and applying selection algorithm.
-        let mut candidate_iter = candidate_set.iter().map(make_rev_item).fuse();
-        let mut latest_items = BinaryHeap::from_iter(candidate_iter.by_ref().take(count));
-        for item in candidate_iter {
-            let mut earliest = latest_items.peek_mut().unwrap();
-            if earliest.0 < item.0 {
-                *earliest = item;
+    fn take_change_heads_revset(
+        &self,
+        candidate_set: &dyn InternalRevset<'index>,
+    ) -> EagerRevset<'index> {
+        let mut entries_by_change: HashMap<ChangeId, Vec<IndexEntry<'index>>> = HashMap::new();
+        for entry in candidate_set.iter() {
+            entries_by_change
+                .entry(entry.change_id())
+                .or_default()
+                .push(entry);
+        }
+        let mut index_entries = vec![];
+        for entries in entries_by_change.into_values() {
+            if entries.len() == 1 {
+                index_entries.extend(entries);
+                continue;
             }
+            let commit_ids = entries.iter().map(|entry| entry.commit_id()).collect_vec();
+            let head_ids: HashSet<_> = self
+                .index
+                .heads(&mut commit_ids.iter())
+                .into_iter()
+                .collect();
+            index_entries.extend(
+                entries
+                    .into_iter()
+                    .filter(|entry| head_ids.contains(&entry.commit_id())),
+            );
         }
+        index_entries.sort_unstable_by_key(|entry| Reverse(entry.position()));
+        EagerRevset { index_entries }
+    }
+
+    /// Picks the `count` commits with the latest `timestamp`. The result is
+    /// ordered by index position as usual; `RevsetImpl::iter_by_date()` lists
+    /// it by committer timestamp instead.
+    fn take_latest_revset(
+        &self,
+        candidate_set: &dyn InternalRevset<'index>,
+        count: usize,
+        timestamp: LatestTimestamp,
+    ) -> EagerRevset<'index> {
+        if count == 0 {
+            return EagerRevset::empty();
+        }
+
+        #[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
+        struct Item<'a> {
+            timestamp: MillisSinceEpoch,
+            entry: IndexEntryByPosition<'a>, // tie-breaker
+        }
+
+        let make_item = |entry: IndexEntry<'index>| {
+            let commit = self.store.get_commit(&entry.commit_id()).unwrap();
+            let signature = match timestamp {
+                LatestTimestamp::Committer => commit.committer(),
+                LatestTimestamp::Author => commit.author(),
+            };
+            Item {
+                timestamp: signature.timestamp.timestamp.clone(),
+                entry: IndexEntryByPosition(entry),
+            }
+        };
+
+        // Collecting the entries doesn't load any commit, and is needed to
+        // choose the selection strategy.
+        let candidate_entries = candidate_set.iter().collect_vec();
+        let items = candidate_entries.into_iter().map(make_item);
+        let latest_items = if count.saturating_mul(LATEST_SELECTION_RATIO) >= items.len() {
+            take_greatest_by_selection(items.collect(), count)
+        } else {
+            take_greatest_by_heap(items, count)
+        };
 
         assert!(latest_items.len() <= count);
         let mut index_entries = latest_items
             .into_iter()
-            .map(|item| item.0.entry.0)
+            .map(|item| item.entry.0)
             .collect_vec();
         index_entries.sort_unstable_by_key(|b| Reverse(b.position()));
         EagerRevset { index_entries }
     }
 }
 
+/// Picks the candidates that aren't ancestors of other candidates.
+fn take_heads_revset<'index>(
+    index: CompositeIndex<'index>,
+    candidate_set: &dyn InternalRevset<'index>,
+) -> EagerRevset<'index> {
+    // A single commit such as in `heads(@)` is trivially its own head
+    let mut candidate_iter = candidate_set.iter();
+    let first_entries = candidate_iter.by_ref().take(2).collect_vec();
+    if first_entries.len() <= 1 {
+        return EagerRevset {
+            index_entries: first_entries,
+        };
+    }
+    let candidate_ids = first_entries
+        .into_iter()
+        .chain(candidate_iter)
+        .map(|entry| entry.commit_id())
+        .collect_vec();
+    let mut index_entries = index
+        .heads(&mut candidate_ids.iter())
+        .iter()
+        .map(|id| index.entry_by_id(id).unwrap())
+        .collect_vec();
+    index_entries.sort_unstable_by_key(|b| Reverse(b.position()));
+    EagerRevset { index_entries }
+}
+
+/// If `latest(x, count)` selects more than 1/N of the candidates, all of them
+/// are sorted out at once instead of maintaining a heap of the latest items.
+const LATEST_SELECTION_RATIO: usize = 4;
+
+/// Returns the `count` greatest `items` in arbitrary order.
+///
+/// This maintains a min-heap containing the greatest `count` items. For small
+/// `count` and many `items`, this is probably cheaper than building a vec and
+/// applying a selection algorithm.
+fn take_greatest_by_heap<T: Ord>(items: impl Iterator<Item = T>, count: usize) -> Vec<T> {
+    if count == 0 {
+        return vec![];
+    }
+    let mut items = items.map(Reverse).fuse();
+    let mut greatest_items = BinaryHeap::from_iter(items.by_ref().take(count));
+    for item in items {
+        let mut least = greatest_items.peek_mut().unwrap();
+        if least.0 < item.0 {
+            *least = item;
+        }
+    }
+    greatest_items.into_iter().map(|item| item.0).collect()
+}
+
+/// Returns the `count` greatest `items` in arbitrary order by partially
+/// sorting them.
+fn take_greatest_by_selection<T: Ord>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    if count < items.len() {
+        items.select_nth_unstable_by(count, |a, b| b.cmp(a));
+        items.truncate(count);
+    }
+    items
+}
+
+/// Caches the result of the wrapped predicate per entry, so a predicate shared
+/// by several parts of an expression is tested only once per entry.
+///
+/// The parts test the same entry close together since all of them follow the
+/// order of the revset iterator, so only the most recently tested entries are
+/// kept.
+#[derive(Debug)]
+struct MemoizedPredicate<P> {
+    predicate: P,
+    cache: RefCell<PredicateCache>,
+}
+
+/// Number of entries kept by `MemoizedPredicate`.
+const MEMOIZED_PREDICATE_CACHE_SIZE: usize = 64;
+
+#[derive(Debug, Default)]
+struct PredicateCache {
+    matches: HashMap<IndexPosition, bool>,
+    /// Cached positions, oldest first.
+    order: VecDeque<IndexPosition>,
+}
+
+impl PredicateCache {
+    fn get(&self, position: IndexPosition) -> Option<bool> {
+        self.matches.get(&position).copied()
+    }
+
+    fn insert(&mut self, position: IndexPosition, matched: bool) {
+        if self.order.len() >= MEMOIZED_PREDICATE_CACHE_SIZE {
+            let oldest = self.order.pop_front().unwrap();
+            self.matches.remove(&oldest);
+        }
+        self.matches.insert(position, matched);
+        self.order.push_back(position);
+    }
+}
+
+impl<P> MemoizedPredicate<P> {
+    fn new(predicate: P) -> Self {
+        MemoizedPredicate {
+            predicate,
+            cache: Default::default(),
+        }
+    }
+}
+
+impl<P: ToPredicateFn> ToPredicateFn for MemoizedPredicate<P> {
+    fn to_predicate_fn(&self) -> Box<dyn FnMut(&IndexEntry<'_>) -> bool + '_> {
+        let mut p = self.predicate.to_predicate_fn();
+        Box::new(move |entry| {
+            let position = entry.position();
+            if let Some(matched) = self.cache.borrow().get(position) {
+                return matched;
+            }
+            let matched = p(entry);
+            self.cache.borrow_mut().insert(position, matched);
+            matched
+        })
+    }
+}
+
 struct PurePredicateFn<F>(F);
 
 impl<F> fmt::Debug for PurePredicateFn<F> {
@@ -796,69 +2315,392 @@ impl<F: Fn(&IndexEntry<'_>) -> bool> ToPredicateFn for PurePredicateFn<F> {
     }
 }
 
+/// Membership in the ancestors of a commit. The ancestors are walked lazily
+/// down to the tested entry, and the walked positions are kept so that the
+/// walk is shared by all predicate functions.
+struct AncestorsPositionSetPredicate<'index> {
+    state: RefCell<(Peekable<RevWalk<'index>>, HashSet<IndexPosition>)>,
+}
+
+impl<'index> AncestorsPositionSetPredicate<'index> {
+    fn new(walk: RevWalk<'index>) -> Self {
+        AncestorsPositionSetPredicate {
+            state: RefCell::new((walk.peekable(), HashSet::new())),
+        }
+    }
+}
+
+impl fmt::Debug for AncestorsPositionSetPredicate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AncestorsPositionSetPredicate")
+            .field("len", &self.state.borrow().1.len())
+            .finish()
+    }
+}
+
+impl ToPredicateFn for AncestorsPositionSetPredicate<'_> {
+    fn to_predicate_fn(&self) -> Box<dyn FnMut(&IndexEntry<'_>) -> bool + '_> {
+        Box::new(|entry| {
+            let (walk, positions) = &mut *self.state.borrow_mut();
+            let pos = entry.position();
+            while let Some(ancestor) = walk.next_if(|ancestor| ancestor.position() >= pos) {
+                positions.insert(ancestor.position());
+            }
+            positions.contains(&pos)
+        })
+    }
+}
+
 fn pure_predicate_fn<'index>(
     f: impl Fn(&IndexEntry<'_>) -> bool + 'index,
 ) -> Box<dyn ToPredicateFn + 'index> {
     Box::new(PurePredicateFn(f))
 }
 
+/// The head if the expression is `:head` of a single commit.
+fn as_single_ancestors_head(expression: &ResolvedExpression) -> Option<&CommitId> {
+    match expression {
+        ResolvedExpression::Ancestors { heads, generation }
+            if generation == &GENERATION_RANGE_FULL =>
+        {
+            match heads.as_ref() {
+                ResolvedExpression::Commits(commit_ids) if commit_ids.len() == 1 => {
+                    commit_ids.first()
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether the expression is known to be empty without evaluating it, e.g.
+/// `none()`. Combinators with such operand can be short-circuited.
+fn is_empty_expression(expression: &ResolvedExpression) -> bool {
+    matches!(expression, ResolvedExpression::Commits(commit_ids) if commit_ids.is_empty())
+}
+
+/// Whether the predicate can be tested without loading commits from the store.
+fn is_index_only_predicate(expression: &ResolvedPredicateExpression) -> bool {
+    match expression {
+        ResolvedPredicateExpression::Filter(predicate) => {
+            matches!(
+                predicate,
+                RevsetFilterPredicate::ParentCount(_)
+                    | RevsetFilterPredicate::ChangeIdPrefix(_)
+                    | RevsetFilterPredicate::CommitIdPrefix(_)
+            )
+        }
+        ResolvedPredicateExpression::Set(_) | ResolvedPredicateExpression::SameTreeAs(_) => false,
+        ResolvedPredicateExpression::NotIn(complement) => is_index_only_predicate(complement),
+        ResolvedPredicateExpression::Union(expression1, expression2) => {
+            is_index_only_predicate(expression1) && is_index_only_predicate(expression2)
+        }
+    }
+}
+
+/// Finds the filters that load commits and appear more than once in the
+/// expression. They are worth memoizing since the same entry may be tested by
+/// each of them.
+fn find_shared_filters(expression: &ResolvedExpression) -> Vec<RevsetFilterPredicate> {
+    fn visit<'a>(expression: &'a ResolvedExpression, filters: &mut Vec<&'a RevsetFilterPredicate>) {
+        match expression {
+            ResolvedExpression::Commits(_) => {}
+            ResolvedExpression::Ancestors { heads, .. } => visit(heads, filters),
+            ResolvedExpression::Range { roots, heads, .. }
+            | ResolvedExpression::DagRange { roots, heads, .. }
+            | ResolvedExpression::ChildrenWithin {
+                roots,
+                candidates: heads,
+            } => {
+                visit(roots, filters);
+                visit(heads, filters);
+            }
+            ResolvedExpression::Heads(candidates)
+            | ResolvedExpression::Roots(candidates)
+            | ResolvedExpression::NthParent {
+                children: candidates,
+                ..
+            }
+            | ResolvedExpression::Latest { candidates, .. }
+            | ResolvedExpression::LatestPerChange(candidates)
+            | ResolvedExpression::ChangeHeads(candidates)
+            | ResolvedExpression::ChangeIdPrefix { candidates, .. } => visit(candidates, filters),
+            ResolvedExpression::Coalesce(expressions) => {
+                for expression in expressions {
+                    visit(expression, filters);
+                }
+            }
+            ResolvedExpression::FilterWithin {
+                candidates,
+                predicate,
+            } => {
+                visit(candidates, filters);
+                visit_predicate(predicate, filters);
+            }
+            ResolvedExpression::Union(expression1, expression2)
+            | ResolvedExpression::Intersection(expression1, expression2)
+            | ResolvedExpression::Difference(expression1, expression2)
+            | ResolvedExpression::SymmetricDifference(expression1, expression2) => {
+                visit(expression1, filters);
+                visit(expression2, filters);
+            }
+        }
+    }
+
+    fn visit_predicate<'a>(
+        expression: &'a ResolvedPredicateExpression,
+        filters: &mut Vec<&'a RevsetFilterPredicate>,
+    ) {
+        match expression {
+            ResolvedPredicateExpression::Filter(predicate) => filters.push(predicate),
+            ResolvedPredicateExpression::Set(expression)
+            | ResolvedPredicateExpression::SameTreeAs(expression) => visit(expression, filters),
+            ResolvedPredicateExpression::NotIn(complement) => visit_predicate(complement, filters),
+            ResolvedPredicateExpression::Union(expression1, expression2) => {
+                visit_predicate(expression1, filters);
+                visit_predicate(expression2, filters);
+            }
+        }
+    }
+
+    let mut filters = vec![];
+    visit(expression, &mut filters);
+    let mut shared_filters: Vec<RevsetFilterPredicate> = vec![];
+    for (i, &predicate) in filters.iter().enumerate() {
+        let expression = ResolvedPredicateExpression::Filter(predicate.clone());
+        if is_index_only_predicate(&expression)
+            || needs_candidate_positions(&expression)
+            || shared_filters.contains(predicate)
+        {
+            continue;
+        }
+        if filters[i + 1..].contains(&predicate) {
+            shared_filters.push(predicate.clone());
+        }
+    }
+    shared_filters
+}
+
+/// Whether the predicate depends on the set of candidates it is filtering.
+fn needs_candidate_positions(expression: &ResolvedPredicateExpression) -> bool {
+    match expression {
+        ResolvedPredicateExpression::Filter(predicate) => {
+            matches!(predicate, RevsetFilterPredicate::FileWithinCandidates(_))
+        }
+        ResolvedPredicateExpression::Set(_) | ResolvedPredicateExpression::SameTreeAs(_) => false,
+        ResolvedPredicateExpression::NotIn(complement) => needs_candidate_positions(complement),
+        ResolvedPredicateExpression::Union(expression1, expression2) => {
+            needs_candidate_positions(expression1) || needs_candidate_positions(expression2)
+        }
+    }
+}
+
 fn build_predicate_fn<'index>(
     store: Arc<Store>,
     index: CompositeIndex<'index>,
     predicate: &RevsetFilterPredicate,
+    candidate_bases: Option<&Rc<CandidateBases>>,
 ) -> Box<dyn ToPredicateFn + 'index> {
     match predicate {
         RevsetFilterPredicate::ParentCount(parent_count_range) => {
             let parent_count_range = parent_count_range.clone();
             pure_predicate_fn(move |entry| parent_count_range.contains(&entry.num_parents()))
         }
-        RevsetFilterPredicate::Description(needle) => {
-            let needle = needle.clone();
+        RevsetFilterPredicate::Trailer { .. }
+        | RevsetFilterPredicate::Description(_)
+        | RevsetFilterPredicate::HasDescription(_)
+        | RevsetFilterPredicate::Author(_)
+        | RevsetFilterPredicate::Committer(_)
+        | RevsetFilterPredicate::AuthorEmail(_)
+        | RevsetFilterPredicate::HasConflict => {
+            let commit_predicate = build_commit_predicate_fn(predicate).unwrap();
             pure_predicate_fn(move |entry| {
-                store
-                    .get_commit(&entry.commit_id())
-                    .unwrap()
-                    .description()
-                    .contains(needle.as_str())
+                let commit = store.get_commit(&entry.commit_id()).unwrap();
+                commit_predicate(&commit)
             })
         }
-        RevsetFilterPredicate::Author(needle) => {
-            let needle = needle.clone();
-            // TODO: Make these functions that take a needle to search for accept some
-            // syntax for specifying whether it's a regex and whether it's
-            // case-sensitive.
+        RevsetFilterPredicate::File(paths) => {
+            // TODO: Add support for globs and other formats
+            let matcher: Box<dyn Matcher> = if let Some(paths) = paths {
+                Box::new(PrefixMatcher::new(paths))
+            } else {
+                Box::new(EverythingMatcher)
+            };
             pure_predicate_fn(move |entry| {
-                let commit = store.get_commit(&entry.commit_id()).unwrap();
-                commit.author().name.contains(needle.as_str())
-                    || commit.author().email.contains(needle.as_str())
+                has_diff_from_parent(&store, index, entry, matcher.as_ref())
             })
         }
-        RevsetFilterPredicate::Committer(needle) => {
-            let needle = needle.clone();
+        RevsetFilterPredicate::FileCaseInsensitive(paths) => {
+            let matcher = CaseInsensitivePrefixMatcher::new(paths);
+            pure_predicate_fn(move |entry| has_diff_from_parent(&store, index, entry, &matcher))
+        }
+        RevsetFilterPredicate::FileAll(paths) => {
+            // Each path needs its own diff. Stop at the first path the commit
+            // doesn't modify.
+            let matchers = paths
+                .iter()
+                .map(|path| PrefixMatcher::new(slice::from_ref(path)))
+                .collect_vec();
             pure_predicate_fn(move |entry| {
-                let commit = store.get_commit(&entry.commit_id()).unwrap();
-                commit.committer().name.contains(needle.as_str())
-                    || commit.committer().email.contains(needle.as_str())
+                matchers
+                    .iter()
+                    .all(|matcher| has_diff_from_parent(&store, index, entry, matcher))
             })
         }
-        RevsetFilterPredicate::File(paths) => {
-            // TODO: Add support for globs and other formats
+        RevsetFilterPredicate::FileAdded(paths) => {
+            let paths = paths.clone();
+            pure_predicate_fn(move |entry| has_path_added(&store, index, entry, &paths))
+        }
+        RevsetFilterPredicate::FileCount(file_count_range) => {
+            let file_count_range = file_count_range.clone();
+            pure_predicate_fn(move |entry| {
+                // Once the upper bound is reached, the count is out of range
+                let limit = usize::try_from(file_count_range.end).unwrap_or(usize::MAX);
+                let count = count_diff_from_parent(&store, index, entry, limit);
+                file_count_range.contains(&u32::try_from(count).unwrap())
+            })
+        }
+        RevsetFilterPredicate::FileWithinCandidates(paths) => {
             let matcher: Box<dyn Matcher> = if let Some(paths) = paths {
                 Box::new(PrefixMatcher::new(paths))
             } else {
                 Box::new(EverythingMatcher)
             };
+            let candidate_bases = candidate_bases
+                .expect("candidate bases should be computed for file_within()")
+                .clone();
             pure_predicate_fn(move |entry| {
-                has_diff_from_parent(&store, index, entry, matcher.as_ref())
+                let base_positions = &candidate_bases[&entry.position()];
+                has_diff_from_bases(&store, index, entry, base_positions, matcher.as_ref())
             })
         }
-        RevsetFilterPredicate::HasConflict => pure_predicate_fn(move |entry| {
-            let commit = store.get_commit(&entry.commit_id()).unwrap();
-            commit.tree().has_conflict()
-        }),
+        RevsetFilterPredicate::ChangeIdPrefix(prefix) => {
+            let prefix = prefix.clone();
+            pure_predicate_fn(move |entry| prefix.matches(&entry.change_id()))
+        }
+        RevsetFilterPredicate::CommitIdPrefix(prefix) => {
+            let prefix = prefix.clone();
+            pure_predicate_fn(move |entry| prefix.matches(&entry.commit_id()))
+        }
     }
 }
 
+/// Predicate that only reads the commit object, so it can be tested on the
+/// threads loading the commits.
+type CommitPredicateFn = Arc<dyn Fn(&Commit) -> bool + Send + Sync>;
+
+/// Builds the predicate of a filter that only reads the commit object, or
+/// returns `None` if the filter needs the index or the parents' trees.
+fn build_commit_predicate_fn(predicate: &RevsetFilterPredicate) -> Option<CommitPredicateFn> {
+    match predicate {
+        RevsetFilterPredicate::Trailer { key, value } => {
+            let key = key.clone();
+            let value = value.clone();
+            Some(Arc::new(move |commit| {
+                parse_trailers(commit.description()).any(|(trailer_key, trailer_value)| {
+                    trailer_key.eq_ignore_ascii_case(&key)
+                        && value.as_ref().map_or(true, |value| trailer_value == value)
+                })
+            }))
+        }
+        RevsetFilterPredicate::Description(needle) => {
+            let needle = needle.clone();
+            Some(Arc::new(move |commit| {
+                commit.description().contains(needle.as_str())
+            }))
+        }
+        RevsetFilterPredicate::HasDescription(non_empty) => {
+            let non_empty = *non_empty;
+            Some(Arc::new(move |commit| {
+                commit.description().is_empty() != non_empty
+            }))
+        }
+        RevsetFilterPredicate::Author(needles) => {
+            let needles = needles.clone();
+            // TODO: Make these functions that take a needle to search for accept some
+            // syntax for specifying whether it's a regex and whether it's
+            // case-sensitive.
+            Some(Arc::new(move |commit| {
+                signature_contains_any(commit.author(), &needles)
+            }))
+        }
+        RevsetFilterPredicate::Committer(needles) => {
+            let needles = needles.clone();
+            Some(Arc::new(move |commit| {
+                signature_contains_any(commit.committer(), &needles)
+            }))
+        }
+        RevsetFilterPredicate::AuthorEmail(pattern) => {
+            let pattern = pattern.clone();
+            Some(Arc::new(move |commit| {
+                pattern.matches(&commit.author().email)
+            }))
+        }
+        RevsetFilterPredicate::HasConflict => Some(Arc::new(|commit| commit.tree().has_conflict())),
+        RevsetFilterPredicate::ParentCount(_)
+        | RevsetFilterPredicate::File(_)
+        | RevsetFilterPredicate::FileCaseInsensitive(_)
+        | RevsetFilterPredicate::FileAll(_)
+        | RevsetFilterPredicate::FileAdded(_)
+        | RevsetFilterPredicate::FileCount(_)
+        | RevsetFilterPredicate::FileWithinCandidates(_)
+        | RevsetFilterPredicate::ChangeIdPrefix(_)
+        | RevsetFilterPredicate::CommitIdPrefix(_) => None,
+    }
+}
+
+/// Like `build_commit_predicate_fn()`, but for a combination of filters.
+fn build_commit_predicate_expression_fn(
+    expression: &ResolvedPredicateExpression,
+) -> Option<CommitPredicateFn> {
+    match expression {
+        ResolvedPredicateExpression::Filter(predicate) => build_commit_predicate_fn(predicate),
+        ResolvedPredicateExpression::Set(_) | ResolvedPredicateExpression::SameTreeAs(_) => None,
+        ResolvedPredicateExpression::NotIn(complement) => {
+            let f = build_commit_predicate_expression_fn(complement)?;
+            Some(Arc::new(move |commit| !f(commit)))
+        }
+        ResolvedPredicateExpression::Union(expression1, expression2) => {
+            let f1 = build_commit_predicate_expression_fn(expression1)?;
+            let f2 = build_commit_predicate_expression_fn(expression2)?;
+            Some(Arc::new(move |commit| f1(commit) || f2(commit)))
+        }
+    }
+}
+
+/// Parses the `key: value` lines of the last paragraph of the description.
+///
+/// Like git, the paragraph is only a trailer block if every line in it is a
+/// trailer, and if it isn't the first paragraph (the subject).
+fn parse_trailers(description: &str) -> impl Iterator<Item = (&str, &str)> {
+    let paragraphs = description
+        .split("\n\n")
+        .map(|paragraph| paragraph.trim_matches('\n'))
+        .filter(|paragraph| !paragraph.trim().is_empty())
+        .collect_vec();
+    let trailers = match paragraphs.as_slice() {
+        [_, .., last] => last
+            .lines()
+            .map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let is_key =
+                    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+                is_key.then(|| (key, value.trim()))
+            })
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default(),
+        _ => vec![],
+    };
+    trailers.into_iter()
+}
+
+fn signature_contains_any(signature: &Signature, needles: &[String]) -> bool {
+    needles.iter().any(|needle| {
+        signature.name.contains(needle.as_str()) || signature.email.contains(needle.as_str())
+    })
+}
+
 fn has_diff_from_parent(
     store: &Arc<Store>,
     index: CompositeIndex<'_>,
@@ -867,6 +2709,11 @@ fn has_diff_from_parent(
 ) -> bool {
     let commit = store.get_commit(&entry.commit_id()).unwrap();
     let parents = commit.parents();
+    if parents.is_empty() {
+        // Fast path: every path in the tree is added, so there's no need to
+        // diff against an empty tree
+        return commit.tree().entries_matching(matcher).next().is_some();
+    }
     if let [parent] = parents.as_slice() {
         // Fast path: no need to load the root tree
         let unchanged = commit.tree_id() == parent.tree_id();
@@ -881,11 +2728,137 @@ fn has_diff_from_parent(
     from_tree.diff(&to_tree, matcher).next().is_some()
 }
 
+/// Whether any of the `paths` exists in the commit's tree but not in the merged
+/// tree of its parents. Since renames aren't tracked, a renamed path is added
+/// at its new location.
+fn has_path_added(
+    store: &Arc<Store>,
+    index: CompositeIndex<'_>,
+    entry: &IndexEntry<'_>,
+    paths: &[RepoPath],
+) -> bool {
+    let commit = store.get_commit(&entry.commit_id()).unwrap();
+    let to_tree = commit.tree();
+    let mut added_paths = paths
+        .iter()
+        .filter(|path| to_tree.path_value(path).is_some())
+        .peekable();
+    if added_paths.peek().is_none() {
+        return false;
+    }
+    let parents = commit.parents();
+    if let [parent] = parents.as_slice() {
+        // Fast path: no need to merge the parent trees
+        if commit.tree_id() == parent.tree_id() {
+            return false;
+        }
+    }
+    let from_tree = rewrite::merge_commit_trees_without_repo(store, &index, &parents);
+    added_paths.any(|path| from_tree.path_value(path).is_none())
+}
+
+/// Counts the files modified by the commit, up to `limit`.
+fn count_diff_from_parent(
+    store: &Arc<Store>,
+    index: CompositeIndex<'_>,
+    entry: &IndexEntry<'_>,
+    limit: usize,
+) -> usize {
+    let commit = store.get_commit(&entry.commit_id()).unwrap();
+    let parents = commit.parents();
+    if parents.is_empty() {
+        // Every path in the tree is added
+        return commit.tree().entries().take(limit).count();
+    }
+    if let [parent] = parents.as_slice() {
+        if commit.tree_id() == parent.tree_id() {
+            return 0;
+        }
+    }
+    let from_tree = rewrite::merge_commit_trees_without_repo(store, &index, &parents);
+    let to_tree = commit.tree();
+    from_tree
+        .diff(&to_tree, &EverythingMatcher)
+        .take(limit)
+        .count()
+}
+
+/// Finds the nearest ancestors of each candidate within the candidates.
+///
+/// The ancestors are walked once from all candidates down to the lowest
+/// one. Each entry carries the candidates whose search reached it without
+/// passing through another candidate, which become the bases of those
+/// candidates if the entry is a candidate itself.
+fn nearest_candidate_ancestors(
+    index: CompositeIndex<'_>,
+    candidate_positions: &[IndexPosition],
+) -> CandidateBases {
+    let min_candidate_pos = match candidate_positions.iter().min() {
+        Some(&pos) => pos,
+        None => return HashMap::new(),
+    };
+    let candidate_set: HashSet<_> = candidate_positions.iter().copied().collect();
+    let mut bases: CandidateBases = HashMap::new();
+    let mut pending: HashMap<IndexPosition, Vec<IndexPosition>> = HashMap::new();
+    for entry in index
+        .walk_revs(candidate_positions, &[])
+        .take_while(|entry| entry.position() >= min_candidate_pos)
+    {
+        let position = entry.position();
+        let mut requesters = pending.remove(&position).unwrap_or_default();
+        requesters.sort_unstable();
+        requesters.dedup();
+        if candidate_set.contains(&position) {
+            for requester in requesters {
+                bases.entry(requester).or_default().push(position);
+            }
+            bases.entry(position).or_default();
+            requesters = vec![position];
+        }
+        if requesters.is_empty() {
+            continue;
+        }
+        for parent_pos in entry.parent_positions() {
+            pending
+                .entry(parent_pos)
+                .or_default()
+                .extend_from_slice(&requesters);
+        }
+    }
+    bases
+}
+
+/// Compares the commit's tree with the merged tree of the `base_positions`.
+/// The root tree is used if there are no bases.
+fn has_diff_from_bases(
+    store: &Arc<Store>,
+    index: CompositeIndex<'_>,
+    entry: &IndexEntry<'_>,
+    base_positions: &[IndexPosition],
+    matcher: &dyn Matcher,
+) -> bool {
+    let base_commits = base_positions
+        .iter()
+        .map(|&pos| {
+            store
+                .get_commit(&index.entry_by_pos(pos).commit_id())
+                .unwrap()
+        })
+        .collect_vec();
+    let from_tree = rewrite::merge_commit_trees_without_repo(store, &index, &base_commits);
+    let to_tree = store.get_commit(&entry.commit_id()).unwrap().tree();
+    from_tree.diff(&to_tree, matcher).next().is_some()
+}
+
 #[cfg(test)]
 mod tests {
+    use assert_matches::assert_matches;
+    use maplit::btreemap;
+
     use super::*;
     use crate::backend::{ChangeId, CommitId, ObjectId};
     use crate::default_index_store::MutableIndexImpl;
+    use crate::default_revset_graph_iterator::RevsetGraphMergedRow;
 
     /// Generator of unique 16-byte ChangeId excluding root id
     fn change_id_generator() -> impl FnMut() -> ChangeId {
@@ -893,6 +2866,82 @@ mod tests {
         move || iter.next().unwrap()
     }
 
+    /// Builds an index of `len` commits in a line, and returns it with the
+    /// commit ids from the root.
+    fn linear_index(len: usize) -> (MutableIndexImpl, Vec<CommitId>) {
+        let mut new_change_id = change_id_generator();
+        let mut index = MutableIndexImpl::full(3, 16);
+        let ids = (0..len)
+            .map(|i| CommitId::from_hex(&format!("{i:06x}")))
+            .collect_vec();
+        let mut parents = vec![];
+        for id in &ids {
+            index.add_commit_data(id.clone(), new_change_id(), &parents);
+            parents = vec![id.clone()];
+        }
+        (index, ids)
+    }
+
+    /// Builds an index of the following history, and returns it with the
+    /// commit ids "000000" to "555555" as numbered.
+    ///
+    /// ```text
+    /// 5
+    /// |\
+    /// 3 4
+    /// | |
+    /// 1 2
+    /// |/
+    /// 0
+    /// ```
+    fn diamond_index() -> (MutableIndexImpl, [CommitId; 6]) {
+        let mut new_change_id = change_id_generator();
+        let mut index = MutableIndexImpl::full(3, 16);
+        let ids =
+            ["000000", "111111", "222222", "333333", "444444", "555555"].map(CommitId::from_hex);
+        let parents: [&[usize]; 6] = [&[], &[0], &[0], &[1], &[2], &[3, 4]];
+        for (id, parents) in ids.iter().zip(parents) {
+            let parent_ids = parents.iter().map(|&i| ids[i].clone()).collect_vec();
+            index.add_commit_data(id.clone(), new_change_id(), &parent_ids);
+        }
+        (index, ids)
+    }
+
+    /// Builds an index of the following history, where the merge's parents
+    /// have different generation numbers, and returns it with the commit ids
+    /// "000000" to "444444" as numbered.
+    ///
+    /// ```text
+    /// 4
+    /// |
+    /// 3
+    /// |\
+    /// 1 2
+    /// |
+    /// 0
+    /// ```
+    fn uneven_merge_index() -> (MutableIndexImpl, [CommitId; 5]) {
+        let mut new_change_id = change_id_generator();
+        let mut index = MutableIndexImpl::full(3, 16);
+        let ids = ["000000", "111111", "222222", "333333", "444444"].map(CommitId::from_hex);
+        let parents: [&[usize]; 5] = [&[], &[0], &[], &[1, 2], &[3]];
+        for (id, parents) in ids.iter().zip(parents) {
+            let parent_ids = parents.iter().map(|&i| ids[i].clone()).collect_vec();
+            index.add_commit_data(id.clone(), new_change_id(), &parent_ids);
+        }
+        (index, ids)
+    }
+
+    /// Builds a revset of the `ids`, which must be indexed.
+    fn revset_of<'index>(index: CompositeIndex<'index>, ids: &[&CommitId]) -> RevsetImpl<'index> {
+        let index_entries = ids
+            .iter()
+            .map(|id| index.entry_by_id(id).unwrap())
+            .sorted_by_key(|entry| Reverse(entry.position()))
+            .collect();
+        RevsetImpl::new(Box::new(EagerRevset { index_entries }), index)
+    }
+
     #[test]
     fn test_revset_combinator() {
         let mut new_change_id = change_id_generator();
@@ -991,5 +3040,907 @@ mod tests {
         assert!(!p(&get_entry(&id_2)));
         assert!(!p(&get_entry(&id_1)));
         assert!(p(&get_entry(&id_0)));
+
+        let set = SymmetricDifferenceRevset {
+            set1: make_set(&[&id_4, &id_2, &id_0]),
+            set2: make_set(&[&id_3, &id_2, &id_1]),
+        };
+        assert_eq!(
+            set.iter().collect_vec(),
+            make_entries(&[&id_4, &id_3, &id_1, &id_0])
+        );
+        let mut p = set.to_predicate_fn();
+        assert!(p(&get_entry(&id_4)));
+        assert!(p(&get_entry(&id_3)));
+        assert!(!p(&get_entry(&id_2)));
+        assert!(p(&get_entry(&id_1)));
+        assert!(p(&get_entry(&id_0)));
+        // Trailing entries of either side are emitted
+        let set = SymmetricDifferenceRevset {
+            set1: make_set(&[&id_4, &id_3]),
+            set2: make_set(&[&id_3, &id_1, &id_0]),
+        };
+        assert_eq!(
+            set.iter().collect_vec(),
+            make_entries(&[&id_4, &id_1, &id_0])
+        );
+    }
+
+    #[test]
+    fn test_take_heads_revset() {
+        let (index, ids) = diamond_index();
+        let index = index.as_composite();
+        let [id_0, id_1, id_2, id_3, id_4, id_5] = &ids;
+        let heads_of = |candidate_index: CompositeIndex, candidate_ids: &[&CommitId]| {
+            let candidate_set = revset_of(candidate_index, candidate_ids);
+            take_heads_revset(index, candidate_set.inner.as_ref())
+                .iter()
+                .map(|entry| entry.commit_id())
+                .collect_vec()
+        };
+
+        assert_eq!(heads_of(index, &[]), vec![]);
+        assert_eq!(heads_of(index, &[id_3]), vec![id_3.clone()]);
+        assert_eq!(
+            heads_of(index, &[id_0, id_1, id_3, id_4]),
+            vec![id_4.clone(), id_3.clone()]
+        );
+        assert_eq!(
+            heads_of(index, &ids.iter().collect_vec()),
+            vec![id_5.clone()]
+        );
+        assert_eq!(heads_of(index, &[id_0, id_2]), vec![id_2.clone()]);
+
+        // A single candidate is returned without being looked up in the index,
+        // so it doesn't have to be there
+        let mut other_index = MutableIndexImpl::full(3, 16);
+        let other_id = CommitId::from_hex("ffffff");
+        other_index.add_commit_data(other_id.clone(), ChangeId::new(vec![0xff; 16]), &[]);
+        assert_eq!(
+            heads_of(other_index.as_composite(), &[&other_id]),
+            vec![other_id.clone()]
+        );
+    }
+
+    #[test]
+    fn test_revset_combinator_size_hint() {
+        let (index, ids) = linear_index(3);
+        let [id_0, id_1, id_2]: [CommitId; 3] = ids.try_into().unwrap();
+
+        let get_entry = |id: &CommitId| index.as_composite().entry_by_id(id).unwrap();
+        let make_set = |ids: &[&CommitId]| -> Box<dyn InternalRevset> {
+            let index_entries = ids.iter().map(|id| get_entry(id)).collect_vec();
+            Box::new(EagerRevset { index_entries })
+        };
+
+        // Eager sets know their exact size without iterating
+        let set = make_set(&[&id_2, &id_1]);
+        assert_eq!(set.size_hint(), (2, Some(2)));
+        assert_eq!(make_set(&[]).size_hint(), (0, Some(0)));
+
+        let set = UnionRevset {
+            set1: make_set(&[&id_2, &id_1]),
+            set2: make_set(&[&id_1, &id_0]),
+        };
+        assert_eq!(set.iter().size_hint(), (2, Some(4)));
+        assert_eq!(set.size_hint(), (0, None));
+        let set = IntersectionRevset {
+            set1: make_set(&[&id_2, &id_1]),
+            set2: make_set(&[&id_1]),
+        };
+        assert_eq!(set.iter().size_hint(), (0, Some(1)));
+        let set = DifferenceRevset {
+            set1: make_set(&[&id_2, &id_1]),
+            set2: make_set(&[&id_1]),
+        };
+        assert_eq!(set.iter().size_hint(), (0, Some(2)));
+        let set = SymmetricDifferenceRevset {
+            set1: make_set(&[&id_2, &id_1]),
+            set2: make_set(&[&id_1]),
+        };
+        assert_eq!(set.iter().size_hint(), (0, Some(3)));
+    }
+
+    #[test]
+    fn test_memoized_predicate() {
+        let (index, ids) = linear_index(4);
+        let index = index.as_composite();
+        let index_entries = ids
+            .iter()
+            .rev()
+            .map(|id| index.entry_by_id(id).unwrap())
+            .collect_vec();
+        let calls = std::cell::Cell::new(0);
+        let even_position = |entry: &IndexEntry| {
+            calls.set(calls.get() + 1);
+            entry.position().to_usize() % 2 == 0
+        };
+
+        // Like "x & (x | none())" with "x" shared by both operands
+        fn make_filter<'a>(
+            index_entries: &[IndexEntry<'a>],
+            x: Box<dyn ToPredicateFn + 'a>,
+            y: Box<dyn ToPredicateFn + 'a>,
+        ) -> impl InternalRevset<'a> + 'a {
+            FilterRevset {
+                candidates: Box::new(FilterRevset {
+                    candidates: Box::new(EagerRevset {
+                        index_entries: index_entries.to_vec(),
+                    }),
+                    predicate: x,
+                }),
+                predicate: UnionPredicate {
+                    set1: y,
+                    set2: Box::new(EagerRevset::empty()),
+                },
+            }
+        }
+        let expected = vec![ids[2].clone(), ids[0].clone()];
+        let revset = make_filter(
+            &index_entries,
+            pure_predicate_fn(even_position),
+            pure_predicate_fn(even_position),
+        );
+        assert_eq!(
+            revset.iter().map(|entry| entry.commit_id()).collect_vec(),
+            expected
+        );
+        assert_eq!(calls.get(), 6);
+
+        calls.set(0);
+        let memoized = Rc::new(MemoizedPredicate::new(pure_predicate_fn(even_position)));
+        let revset = make_filter(
+            &index_entries,
+            Box::new(memoized.clone()),
+            Box::new(memoized),
+        );
+        assert_eq!(
+            revset.iter().map(|entry| entry.commit_id()).collect_vec(),
+            expected
+        );
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn test_memoized_predicate_cache_size() {
+        let (index, ids) = linear_index(MEMOIZED_PREDICATE_CACHE_SIZE * 2);
+        let index = index.as_composite();
+        let head_pos = index.commit_id_to_pos(ids.last().unwrap()).unwrap();
+        let index_entries = index.walk_revs(&[head_pos], &[]).collect_vec();
+
+        let memoized = MemoizedPredicate::new(pure_predicate_fn(|_| true));
+        let mut p = memoized.to_predicate_fn();
+        for entry in &index_entries {
+            assert!(p(entry));
+        }
+        drop(p);
+        // Only the most recently tested entries are kept
+        let cache = memoized.cache.borrow();
+        assert_eq!(cache.matches.len(), MEMOIZED_PREDICATE_CACHE_SIZE);
+        assert_eq!(cache.get(index_entries[0].position()), None);
+        assert_eq!(
+            cache.get(index_entries.last().unwrap().position()),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_find_shared_filters() {
+        let description = RevsetFilterPredicate::Description("foo".to_owned());
+        let filter = |predicate: &RevsetFilterPredicate| ResolvedExpression::FilterWithin {
+            candidates: Box::new(ResolvedExpression::Commits(vec![])),
+            predicate: ResolvedPredicateExpression::Filter(predicate.clone()),
+        };
+        let expression = ResolvedExpression::Intersection(
+            Box::new(filter(&description)),
+            Box::new(ResolvedExpression::Union(
+                Box::new(filter(&description)),
+                Box::new(filter(&RevsetFilterPredicate::HasConflict)),
+            )),
+        );
+        assert_eq!(find_shared_filters(&expression), vec![description.clone()]);
+        assert_eq!(find_shared_filters(&filter(&description)), vec![]);
+        // Index-only filters are cheap enough without memoization
+        let parent_count = RevsetFilterPredicate::ParentCount(2..u32::MAX);
+        let expression = ResolvedExpression::Union(
+            Box::new(filter(&parent_count)),
+            Box::new(filter(&parent_count)),
+        );
+        assert_eq!(find_shared_filters(&expression), vec![]);
+    }
+
+    #[test]
+    fn test_revset_change_id_index() {
+        let (index, ids) = linear_index(6);
+        let index = index.as_composite();
+
+        let revset = RevsetImpl::new(
+            Box::new(UnionRevset {
+                set1: Box::new(EagerRevset {
+                    index_entries: [&ids[5], &ids[3], &ids[1]]
+                        .iter()
+                        .map(|id| index.entry_by_id(id).unwrap())
+                        .collect(),
+                }),
+                set2: Box::new(EagerRevset {
+                    index_entries: [&ids[4], &ids[3], &ids[0]]
+                        .iter()
+                        .map(|id| index.entry_by_id(id).unwrap())
+                        .collect(),
+                }),
+            }),
+            index,
+        );
+        let change_id_index = revset.change_id_index();
+        let mut expected = vec![];
+        for entry in revset.inner.iter() {
+            expected.push((entry.change_id(), entry.commit_id()));
+        }
+        let expected = IdIndex::from_vec(expected);
+        assert_eq!(
+            expected
+                .prefix_keys(&HexPrefix::new("").unwrap(), 100)
+                .len(),
+            5
+        );
+
+        // Every full and shortest prefix resolves to the same commits as with
+        // an index built from all entries of the revset
+        for id in &ids {
+            let change_id = index.entry_by_id(id).unwrap().change_id();
+            let hex = change_id.hex();
+            let len = change_id_index.shortest_unique_prefix_len(&change_id);
+            for prefix in [&hex[..len], &hex[..]] {
+                let prefix = HexPrefix::new(prefix).unwrap();
+                assert_eq!(
+                    change_id_index.resolve_prefix(&prefix),
+                    expected
+                        .resolve_prefix_with(&prefix, |id: &CommitId| id.clone())
+                        .map(|(_, commit_ids)| commit_ids),
+                );
+            }
+        }
+        assert_eq!(
+            change_id_index.resolve_prefix(&HexPrefix::new("").unwrap()),
+            PrefixResolution::AmbiguousMatch
+        );
+    }
+
+    #[test]
+    fn test_revset_walk_diamond_no_duplicates() {
+        let (index, ids) = diamond_index();
+        let index = index.as_composite();
+        let get_pos = |id: &CommitId| index.commit_id_to_pos(id).unwrap();
+
+        let set = RevWalkRevset {
+            walk: index.walk_revs(&[get_pos(&ids[5])], &[]),
+        };
+        let expected = ids.iter().rev().map(get_pos).collect_vec();
+        // Each iteration restarts the walk from a clone
+        for _ in 0..2 {
+            assert_eq!(
+                set.iter().map(|entry| entry.position()).collect_vec(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_revset_to_sets() {
+        let (index, ids) = linear_index(3);
+        let index = index.as_composite();
+        let get_entry = |id: &CommitId| index.entry_by_id(id).unwrap();
+
+        let revset = revset_of(index, &[&ids[2], &ids[0]]);
+        assert_eq!(
+            revset.to_commit_id_set(),
+            HashSet::from([ids[2].clone(), ids[0].clone()])
+        );
+        assert_eq!(
+            revset.to_position_set(),
+            HashSet::from([get_entry(&ids[2]).position(), get_entry(&ids[0]).position()])
+        );
+
+        let revset = revset_of(index, &[]);
+        assert!(revset.to_commit_id_set().is_empty());
+        assert!(revset.to_position_set().is_empty());
+    }
+
+    #[test]
+    fn test_empty_revset() {
+        let (index, ids) = linear_index(1);
+        let index = index.as_composite();
+
+        let revset = empty_revset(index);
+        assert_eq!(revset.iter().next(), None);
+        assert!(revset.is_empty());
+        assert_eq!(revset.count(), 0);
+        let other = revset_of(index, &[&ids[0]]);
+        assert!(revset.set_eq(&empty_revset(index)));
+        assert!(!revset.set_eq(&other));
+        assert_eq!(
+            other.iter_graph_merged(&revset).collect_vec(),
+            vec![(ids[0].clone(), RevsetGraphMergedRow::Left(vec![]))]
+        );
+    }
+
+    #[test]
+    fn test_revset_intersect_commit_ids() {
+        let (index, ids) = linear_index(5);
+        let index = index.as_composite();
+        let revset = revset_of(index, &[&ids[4], &ids[3], &ids[1], &ids[0]]);
+
+        // Ids outside the set, including unknown ones, are dropped
+        let external = HashSet::from([
+            ids[0].clone(),
+            ids[2].clone(),
+            ids[4].clone(),
+            CommitId::from_hex("555555"),
+        ]);
+        assert_eq!(
+            revset.intersect_commit_ids(&external),
+            vec![ids[4].clone(), ids[0].clone()]
+        );
+        assert_eq!(revset.intersect_commit_ids(&HashSet::new()), vec![]);
+    }
+
+    #[test]
+    fn test_revset_content_fingerprint() {
+        let id_0 = CommitId::from_hex("000000");
+        let id_1 = CommitId::from_hex("111111");
+        let id_2 = CommitId::from_hex("222222");
+        let build_index = |unrelated_commit: bool| {
+            let mut new_change_id = change_id_generator();
+            let mut index = MutableIndexImpl::full(3, 16);
+            if unrelated_commit {
+                index.add_commit_data(CommitId::from_hex("999999"), new_change_id(), &[]);
+            }
+            index.add_commit_data(id_0.clone(), new_change_id(), &[]);
+            index.add_commit_data(id_1.clone(), new_change_id(), &[id_0.clone()]);
+            index.add_commit_data(id_2.clone(), new_change_id(), &[id_1.clone()]);
+            index
+        };
+        let fingerprint = |index: &MutableIndexImpl, ids: &[&CommitId]| {
+            revset_of(index.as_composite(), ids).content_fingerprint()
+        };
+
+        // Index positions differ between the indexes
+        let index1 = build_index(false);
+        let index2 = build_index(true);
+        let fingerprint1 = fingerprint(&index1, &[&id_2, &id_0]);
+        assert_eq!(fingerprint1, fingerprint(&index2, &[&id_2, &id_0]));
+        assert_ne!(fingerprint1, fingerprint(&index1, &[&id_2, &id_1]));
+        assert_ne!(fingerprint1, fingerprint(&index1, &[&id_2]));
+        assert_ne!(fingerprint1, fingerprint(&index1, &[]));
+    }
+
+    #[test]
+    fn test_revset_set_eq() {
+        let (index, ids) = linear_index(5);
+        let index = index.as_composite();
+        let make_revset =
+            |indices: &[usize]| revset_of(index, &indices.iter().map(|&i| &ids[i]).collect_vec());
+
+        assert!(make_revset(&[4, 2, 0]).set_eq(&make_revset(&[4, 2, 0])));
+        assert!(make_revset(&[]).set_eq(&make_revset(&[])));
+        // Subset
+        assert!(!make_revset(&[4, 0]).set_eq(&make_revset(&[4, 2, 0])));
+        assert!(!make_revset(&[4, 2]).set_eq(&make_revset(&[4, 2, 0])));
+        // Superset
+        assert!(!make_revset(&[4, 2, 0]).set_eq(&make_revset(&[2, 0])));
+        assert!(!make_revset(&[1]).set_eq(&make_revset(&[])));
+
+        // Stops at the first mismatch
+        let pulled = std::cell::Cell::new(0);
+        let revset = make_revset(&[4, 3, 2, 1, 0]).with_progress(1, |count| pulled.set(count));
+        assert!(!revset.set_eq(&make_revset(&[4, 2, 1, 0])));
+        assert_eq!(pulled.get(), 2);
+    }
+
+    #[test]
+    fn test_revset_to_membership() {
+        let (index, ids) = linear_index(3);
+        let index = index.as_composite();
+        let revset = revset_of(index, &[&ids[2], &ids[0]]);
+        let membership = revset.to_membership();
+        assert_eq!(membership.len(), 2);
+        assert!(membership.contains(&ids[2]));
+        assert!(!membership.contains(&ids[1]));
+        assert!(membership.contains(&ids[0]));
+        // Can be probed in any order, repeatedly
+        assert!(membership.contains(&ids[2]));
+        // Not indexed
+        assert!(!membership.contains(&CommitId::from_hex("333333")));
+
+        let revset = revset_of(index, &[]);
+        assert!(revset.to_membership().is_empty());
+    }
+
+    #[test]
+    fn test_revset_iter_chunked() {
+        let (index, ids) = linear_index(5);
+        let index = index.as_composite();
+        let revset = revset_of(index, &ids.iter().collect_vec());
+
+        let chunks = revset.iter_chunked(2).collect_vec();
+        assert_eq!(
+            chunks.iter().map(|ids| ids.len()).collect_vec(),
+            vec![2, 2, 1]
+        );
+        assert_eq!(
+            chunks.iter().map(|ids| ids.len()).sum::<usize>(),
+            revset.iter().count()
+        );
+        assert_eq!(chunks.concat(), revset.iter().collect_vec());
+
+        let chunks = revset.iter_chunked(5).collect_vec();
+        assert_eq!(chunks, vec![revset.iter().collect_vec()]);
+
+        let revset = revset_of(index, &[]);
+        assert_eq!(revset.iter_chunked(2).count(), 0);
+    }
+
+    #[test]
+    fn test_take_greatest_strategies() {
+        let items = vec![3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5];
+        for count in 0..=items.len() + 1 {
+            let mut expected = items.clone();
+            expected.sort_unstable_by(|a, b| b.cmp(a));
+            expected.truncate(count);
+            let mut by_heap = take_greatest_by_heap(items.iter().copied(), count);
+            by_heap.sort_unstable_by(|a, b| b.cmp(a));
+            let mut by_selection = take_greatest_by_selection(items.clone(), count);
+            by_selection.sort_unstable_by(|a, b| b.cmp(a));
+            assert_eq!(by_heap, expected, "count={count}");
+            assert_eq!(by_selection, expected, "count={count}");
+        }
+        assert!(take_greatest_by_heap(iter::empty::<i32>(), 2).is_empty());
+        assert!(take_greatest_by_selection(Vec::<i32>::new(), 2).is_empty());
+    }
+
+    #[test]
+    fn test_revset_partition_by_bases() {
+        let (index, [id_0, id_1, id_2, id_3, id_4, id_5]) = diamond_index();
+        let id_6 = CommitId::from_hex("666666");
+        let index = index.as_composite();
+        let revset = revset_of(index, &[&id_5, &id_4, &id_3, &id_2, &id_1, &id_0]);
+
+        let partition = revset.partition_by_bases(&[id_1.clone(), id_2.clone()]);
+        assert_eq!(
+            partition,
+            HashMap::from([
+                (id_0.clone(), None),
+                (id_1.clone(), Some(id_1.clone())),
+                (id_2.clone(), Some(id_2.clone())),
+                (id_3.clone(), Some(id_1.clone())),
+                (id_4.clone(), Some(id_2.clone())),
+                (id_5.clone(), Some(id_1.clone())),
+            ])
+        );
+
+        // The merge descends from both bases, so the order of bases matters
+        let partition = revset.partition_by_bases(&[id_2.clone(), id_1.clone()]);
+        assert_eq!(partition[&id_5], Some(id_2.clone()));
+        assert_eq!(partition[&id_3], Some(id_1.clone()));
+
+        // A base descending from another base listed before it is classified
+        // by the earlier one
+        let partition = revset.partition_by_bases(&[id_0.clone(), id_1.clone()]);
+        assert!(partition.values().all(|base| base == &Some(id_0.clone())));
+        let partition = revset.partition_by_bases(&[id_1.clone(), id_0.clone()]);
+        assert_eq!(partition[&id_5], Some(id_1.clone()));
+        assert_eq!(partition[&id_3], Some(id_1.clone()));
+        assert_eq!(partition[&id_1], Some(id_1.clone()));
+        assert_eq!(partition[&id_2], Some(id_0.clone()));
+        assert_eq!(partition[&id_0], Some(id_0.clone()));
+
+        // Unknown bases are ignored
+        let partition = revset.partition_by_bases(&[id_6, id_4.clone()]);
+        assert_eq!(partition[&id_5], Some(id_4.clone()));
+        assert_eq!(partition[&id_4], Some(id_4));
+        assert_eq!(partition[&id_3], None);
+        assert_eq!(partition[&id_0], None);
+
+        let partition = revset.partition_by_bases(&[]);
+        assert!(partition.values().all(|base| base.is_none()));
+        assert_eq!(partition.len(), 6);
+    }
+
+    #[test]
+    fn test_revset_closest_common_ancestor_distance() {
+        let (mut index, [id_0, id_1, id_2, id_3, id_4, id_5]) = diamond_index();
+        // Unrelated root
+        let id_6 = CommitId::from_hex("666666");
+        let id_7 = CommitId::from_hex("777777");
+        index.add_commit_data(id_6.clone(), ChangeId::new(vec![0xff; 16]), &[]);
+        let index = index.as_composite();
+        let revset = revset_of(index, &[]);
+        let distance = |a, b| revset.closest_common_ancestor_distance(a, b);
+
+        assert_eq!(distance(&id_3, &id_3), Some(0));
+        assert_eq!(distance(&id_3, &id_1), Some(1));
+        assert_eq!(distance(&id_1, &id_3), Some(1));
+        assert_eq!(distance(&id_3, &id_0), Some(2));
+        // Fork
+        assert_eq!(distance(&id_1, &id_2), Some(2));
+        assert_eq!(distance(&id_3, &id_4), Some(4));
+        assert_eq!(distance(&id_3, &id_2), Some(3));
+        // The shorter path through the merge wins
+        assert_eq!(distance(&id_5, &id_4), Some(1));
+        assert_eq!(distance(&id_5, &id_2), Some(2));
+        assert_eq!(distance(&id_5, &id_1), Some(2));
+        // Unrelated or unknown commits
+        assert_eq!(distance(&id_5, &id_6), None);
+        assert_eq!(distance(&id_5, &id_7), None);
+    }
+
+    #[test]
+    fn test_revset_nearest_labeled_ancestor() {
+        // 5
+        // 4
+        // 3
+        // 2 <- labeled
+        // 1
+        // 0 <- labeled
+        let (index, ids) = linear_index(6);
+        let index = index.as_composite();
+        let revset = revset_of(index, &[]);
+        let labeled = HashSet::from([ids[0].clone(), ids[2].clone()]);
+        let nearest = |id| revset.nearest_labeled_ancestor(id, &labeled);
+
+        assert_eq!(nearest(&ids[5]), Some(ids[2].clone()));
+        assert_eq!(nearest(&ids[3]), Some(ids[2].clone()));
+        // The commit itself is the nearest if it's labeled
+        assert_eq!(nearest(&ids[2]), Some(ids[2].clone()));
+        assert_eq!(nearest(&ids[1]), Some(ids[0].clone()));
+        // Labeled descendants aren't ancestors
+        assert_eq!(
+            revset.nearest_labeled_ancestor(&ids[5], &HashSet::from([ids[4].clone()])),
+            Some(ids[4].clone())
+        );
+        assert_eq!(
+            revset.nearest_labeled_ancestor(&ids[3], &HashSet::from([ids[4].clone()])),
+            None
+        );
+        // Unknown commit
+        assert_eq!(nearest(&CommitId::from_hex("777777")), None);
+    }
+
+    #[test]
+    fn test_revset_generation_of() {
+        let (index, [id_0, id_1, id_2, id_3, id_4]) = uneven_merge_index();
+        let index = index.as_composite();
+        let revset = revset_of(index, &[&id_3]);
+
+        assert_eq!(revset.generation_of(&id_0), Some(0));
+        assert_eq!(revset.generation_of(&id_1), Some(1));
+        assert_eq!(revset.generation_of(&id_2), Some(0));
+        assert_eq!(revset.generation_of(&id_3), Some(2));
+        // Not in the set
+        assert_eq!(revset.generation_of(&id_4), Some(3));
+        // Not indexed
+        assert_eq!(revset.generation_of(&CommitId::from_hex("555555")), None);
+    }
+
+    #[test]
+    fn test_revset_frontier() {
+        let (index, [id_0, id_1, id_2, id_3, id_4, id_5]) = diamond_index();
+        let index = index.as_composite();
+        let make_revset = |ids: &[&CommitId]| revset_of(index, ids);
+
+        // The merge is in the frontier because its parent 4 is excluded, even
+        // though it has an ancestor in the set
+        assert_eq!(
+            make_revset(&[&id_5, &id_3, &id_1]).frontier(),
+            vec![id_5.clone(), id_1.clone()]
+        );
+        assert_eq!(
+            make_revset(&[&id_5, &id_4, &id_3, &id_2, &id_1]).frontier(),
+            vec![id_2.clone(), id_1.clone()]
+        );
+        // The root commit has no parent outside the set
+        assert_eq!(
+            make_revset(&[&id_5, &id_4, &id_3, &id_2, &id_1, &id_0]).frontier(),
+            vec![]
+        );
+        assert_eq!(make_revset(&[&id_5, &id_0]).frontier(), vec![id_5.clone()]);
+        assert_eq!(make_revset(&[]).frontier(), vec![]);
+    }
+
+    #[test]
+    fn test_revset_leaves() {
+        let (index, [id_0, id_1, id_2, id_3, id_4, id_5]) = diamond_index();
+        let index = index.as_composite();
+        let make_revset = |ids: &[&CommitId]| revset_of(index, ids);
+
+        // Both sides of the fork are leaves
+        assert_eq!(
+            make_revset(&[&id_4, &id_3, &id_2, &id_1, &id_0]).leaves(),
+            vec![id_4.clone(), id_3.clone()]
+        );
+        assert_eq!(
+            make_revset(&[&id_4, &id_2, &id_0]).leaves(),
+            vec![id_4.clone()]
+        );
+        // 1 is a leaf since its child 3 is excluded, though 5 descends from it
+        assert_eq!(
+            make_revset(&[&id_5, &id_1, &id_0]).leaves(),
+            vec![id_5.clone(), id_1.clone()]
+        );
+        assert_eq!(make_revset(&[]).leaves(), vec![]);
+    }
+
+    #[test]
+    fn test_revset_by_generation() {
+        let (index, [id_0, id_1, id_2, id_3, id_4]) = uneven_merge_index();
+        let index = index.as_composite();
+        let make_revset = |ids: &[&CommitId]| revset_of(index, ids);
+
+        assert_eq!(
+            make_revset(&[&id_3, &id_2, &id_1, &id_0]).by_generation(),
+            btreemap! {
+                0 => vec![id_2.clone(), id_0.clone()],
+                1 => vec![id_1.clone()],
+                2 => vec![id_3.clone()],
+            }
+        );
+        // Generations of commits outside the set are skipped
+        assert_eq!(
+            make_revset(&[&id_4, &id_0]).by_generation(),
+            btreemap! {
+                0 => vec![id_0.clone()],
+                3 => vec![id_4.clone()],
+            }
+        );
+        assert_eq!(make_revset(&[]).by_generation(), btreemap! {});
+    }
+
+    #[test]
+    fn test_revset_with_progress() {
+        let (index, ids) = linear_index(5);
+        let index = index.as_composite();
+        let counts = std::cell::RefCell::new(vec![]);
+        let revset = revset_of(index, &ids.iter().collect_vec())
+            .with_progress(2, |count| counts.borrow_mut().push(count));
+
+        // Called as the entries are consumed
+        let mut iter = revset.iter();
+        assert_eq!(iter.next(), Some(ids[4].clone()));
+        assert!(counts.borrow().is_empty());
+        assert_eq!(iter.next(), Some(ids[3].clone()));
+        assert_eq!(*counts.borrow(), vec![2]);
+        assert_eq!(iter.count(), 3);
+        assert_eq!(*counts.borrow(), vec![2, 4]);
+
+        // Restarts counting for each iteration
+        counts.borrow_mut().clear();
+        assert_eq!(revset.iter().count(), 5);
+        assert_eq!(*counts.borrow(), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_revset_for_each_commit_id() {
+        let (index, ids) = linear_index(5);
+        let index = index.as_composite();
+        let counts = std::cell::RefCell::new(vec![]);
+        let revset = revset_of(index, &ids.iter().collect_vec())
+            .with_progress(1, |count| counts.borrow_mut().push(count));
+
+        let mut visited = vec![];
+        let result = revset.for_each_commit_id(&mut |id| {
+            visited.push(id);
+            if visited.len() == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(result, ControlFlow::Break(()));
+        assert_eq!(visited, vec![ids[4].clone(), ids[3].clone()]);
+        // No entry is pulled from the inner revset after breaking
+        assert_eq!(*counts.borrow(), vec![1, 2]);
+
+        counts.borrow_mut().clear();
+        let mut visited = vec![];
+        let result = revset.for_each_commit_id(&mut |id| {
+            visited.push(id);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(result, ControlFlow::Continue(()));
+        assert_eq!(visited, ids.iter().rev().cloned().collect_vec());
+        assert_eq!(*counts.borrow(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_revset_bitmap() {
+        // More commits than fit in a single word
+        let (index, ids) = linear_index(150);
+        let index = index.as_composite();
+        let make_set = |ids: &[&CommitId]| -> Box<dyn InternalRevset> {
+            let mut index_entries = ids
+                .iter()
+                .map(|id| index.entry_by_id(id).unwrap())
+                .collect_vec();
+            index_entries.sort_unstable_by_key(|entry| Reverse(entry.position()));
+            Box::new(EagerRevset { index_entries })
+        };
+        let set1_ids = ids.iter().step_by(2).collect_vec();
+        let set2_ids = ids.iter().step_by(3).take(20).collect_vec();
+        let bitmap1 = RevsetImpl::new(make_set(&set1_ids), index).to_bitmap();
+        let bitmap2 = RevsetImpl::new(make_set(&set2_ids), index).to_bitmap();
+        assert_eq!(bitmap1.len(), 75);
+        assert_eq!(bitmap2.len(), 20);
+
+        // Bitmap operations match the revset operators
+        let from_bitmap =
+            |bitmap: &RevsetBitmap| RevsetImpl::from_bitmap(bitmap, index).iter().collect_vec();
+        let union = UnionRevset {
+            set1: make_set(&set1_ids),
+            set2: make_set(&set2_ids),
+        };
+        assert_eq!(
+            from_bitmap(&bitmap1.union(&bitmap2)),
+            RevsetImpl::new(Box::new(union), index).iter().collect_vec()
+        );
+        assert_eq!(bitmap1.union(&bitmap2), bitmap2.union(&bitmap1));
+        let intersection = IntersectionRevset {
+            set1: make_set(&set1_ids),
+            set2: make_set(&set2_ids),
+        };
+        assert_eq!(
+            from_bitmap(&bitmap1.intersection(&bitmap2)),
+            RevsetImpl::new(Box::new(intersection), index)
+                .iter()
+                .collect_vec()
+        );
+        let difference = DifferenceRevset {
+            set1: make_set(&set1_ids),
+            set2: make_set(&set2_ids),
+        };
+        assert_eq!(
+            from_bitmap(&bitmap1.difference(&bitmap2)),
+            RevsetImpl::new(Box::new(difference), index)
+                .iter()
+                .collect_vec()
+        );
+        assert_eq!(
+            from_bitmap(&bitmap2.difference(&bitmap1)),
+            from_bitmap(&bitmap2.difference(&bitmap2.intersection(&bitmap1)))
+        );
+
+        // Sets compare equal regardless of how they were built
+        assert!(bitmap2.difference(&bitmap2).is_empty());
+        assert_eq!(bitmap2.difference(&bitmap2), RevsetBitmap::default());
+        assert_eq!(bitmap1.intersection(&bitmap1), bitmap1);
+        assert!(bitmap1.contains(index.commit_id_to_pos(&ids[148]).unwrap()));
+        assert!(!bitmap1.contains(index.commit_id_to_pos(&ids[149]).unwrap()));
+        assert!(!bitmap1.contains(IndexPosition::from_usize(1000)));
+    }
+
+    #[test]
+    fn test_revset_snapshot_round_trip() {
+        let (index, ids) = linear_index(3);
+        let [id_0, _, id_2]: [CommitId; 3] = ids.try_into().unwrap();
+        let id_3 = CommitId::from_hex("333333");
+        let index = index.as_composite();
+        let op_id = OperationId::from_hex("abcdef");
+        let other_op_id = OperationId::from_hex("123456");
+        let revset = revset_of(index, &[&id_2, &id_0]);
+
+        let snapshot = revset.to_snapshot(&op_id);
+        assert_eq!(snapshot.operation_id, op_id);
+        assert_eq!(snapshot.commit_ids, vec![id_2.clone(), id_0.clone()]);
+        let json = snapshot.to_json();
+        let parsed = RevsetSnapshot::from_json(&json).unwrap();
+        assert_eq!(parsed, snapshot);
+        let restored = RevsetImpl::from_snapshot(&parsed, &op_id, index).unwrap();
+        assert_eq!(restored.iter().collect_vec(), vec![id_2, id_0]);
+
+        // Snapshot of another operation is rejected
+        assert_matches!(
+            RevsetImpl::from_snapshot(&parsed, &other_op_id, index),
+            Err(RevsetSnapshotError::OperationMismatch { .. })
+        );
+        // Commits missing from the index are rejected
+        let unknown = RevsetSnapshot {
+            operation_id: op_id.clone(),
+            commit_ids: vec![id_3.clone()],
+        };
+        assert_matches!(
+            RevsetImpl::from_snapshot(&unknown, &op_id, index),
+            Err(RevsetSnapshotError::CommitNotIndexed(id)) if id == id_3
+        );
+        assert_matches!(
+            RevsetSnapshot::from_json(&serde_json::json!({ "operation_id": "abcdef" })),
+            Err(RevsetSnapshotError::InvalidFormat(_))
+        );
+    }
+
+    #[test]
+    fn test_revset_walk_to() {
+        let (index, [id_0, id_1, id_2, id_3, id_4, id_5]) = diamond_index();
+        let id_6 = CommitId::from_hex("666666");
+        let index = index.as_composite();
+        let get_pos = |id: &CommitId| index.commit_id_to_pos(id).unwrap();
+        let make_revset = |ids: &[&CommitId]| revset_of(index, ids);
+        let path = |ids: &[&CommitId]| ids.iter().map(|id| get_pos(id)).collect_vec();
+
+        // Linear
+        let revset = make_revset(&[&id_0, &id_1, &id_3]);
+        assert_eq!(
+            revset.walk_to(&id_3, &id_0),
+            Some(path(&[&id_3, &id_1, &id_0]))
+        );
+        assert_eq!(revset.walk_to(&id_3, &id_3), Some(path(&[&id_3])));
+        // Not an ancestor
+        assert_eq!(revset.walk_to(&id_0, &id_3), None);
+        // Not in the set
+        assert_eq!(revset.walk_to(&id_3, &id_2), None);
+        assert_eq!(revset.walk_to(&id_3, &id_6), None);
+
+        // Branching: either side of the merge can be taken
+        let revset = make_revset(&[&id_0, &id_1, &id_2, &id_3, &id_4, &id_5]);
+        let found = revset.walk_to(&id_5, &id_0).unwrap();
+        assert!(
+            found == path(&[&id_5, &id_3, &id_1, &id_0])
+                || found == path(&[&id_5, &id_4, &id_2, &id_0])
+        );
+        assert_eq!(
+            revset.walk_to(&id_5, &id_2),
+            Some(path(&[&id_5, &id_4, &id_2]))
+        );
+
+        // The path must stay within the set
+        let revset = make_revset(&[&id_0, &id_2, &id_3, &id_4, &id_5]);
+        assert_eq!(
+            revset.walk_to(&id_5, &id_0),
+            Some(path(&[&id_5, &id_4, &id_2, &id_0]))
+        );
+        let revset = make_revset(&[&id_0, &id_3, &id_5]);
+        assert_eq!(revset.walk_to(&id_5, &id_3), Some(path(&[&id_5, &id_3])));
+        assert_eq!(revset.walk_to(&id_5, &id_0), None);
+    }
+
+    #[test]
+    fn test_nearest_candidate_ancestors() {
+        let (index, [id_0, id_1, id_2, id_3, id_4, id_5]) = diamond_index();
+        let index = index.as_composite();
+        let get_pos = |id: &CommitId| index.commit_id_to_pos(id).unwrap();
+        let bases = |ids: &[&CommitId]| {
+            let candidates = ids.iter().map(|id| get_pos(id)).collect_vec();
+            nearest_candidate_ancestors(index, &candidates)
+                .into_iter()
+                .sorted()
+                .collect_vec()
+        };
+
+        // The search stops at the first candidates on each path, and below the
+        // lowest candidate
+        assert_eq!(
+            bases(&[&id_5, &id_3, &id_1]),
+            vec![
+                (get_pos(&id_1), vec![]),
+                (get_pos(&id_3), vec![get_pos(&id_1)]),
+                (get_pos(&id_5), vec![get_pos(&id_3)]),
+            ]
+        );
+        // Merged paths list the candidate once
+        assert_eq!(
+            bases(&[&id_5, &id_0]),
+            vec![
+                (get_pos(&id_0), vec![]),
+                (get_pos(&id_5), vec![get_pos(&id_0)]),
+            ]
+        );
+        assert_eq!(
+            bases(&[&id_5, &id_3, &id_4]),
+            vec![
+                (get_pos(&id_3), vec![]),
+                (get_pos(&id_4), vec![]),
+                (get_pos(&id_5), vec![get_pos(&id_4), get_pos(&id_3)]),
+            ]
+        );
+        assert_eq!(bases(&[&id_2]), vec![(get_pos(&id_2), vec![])]);
+        assert_eq!(bases(&[]), vec![]);
     }
 }
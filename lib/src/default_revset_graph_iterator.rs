@@ -15,7 +15,7 @@
 use std::cmp::min;
 use std::collections::{BTreeMap, HashSet};
 
-use crate::backend::CommitId;
+use crate::backend::{ChangeId, CommitId};
 use crate::default_index_store::{IndexEntry, IndexPosition};
 use crate::nightly_shims::BTreeMapExt;
 use crate::revset::{RevsetGraphEdge, RevsetGraphEdgeType};
@@ -119,6 +119,24 @@ impl<'revset, 'index> RevsetGraphIterator<'revset, 'index> {
         self
     }
 
+    /// Also yields the change id of each commit, which is read from the same
+    /// index entry as the commit id.
+    pub fn with_change_ids(self) -> RevsetGraphWithChangeIdsIterator<'revset, 'index> {
+        RevsetGraphWithChangeIdsIterator(self)
+    }
+
+    fn next_entry(&mut self) -> Option<(IndexEntry<'index>, Vec<RevsetGraphEdge>)> {
+        let index_entry = self.next_index_entry()?;
+        let mut edges = self.edges_from_internal_commit(&index_entry);
+        if self.skip_transitive_edges {
+            edges = self.remove_transitive_edges(edges);
+        }
+        let mut edges: Vec<_> = edges.into_iter().collect();
+        edges.sort_by(|(target_pos1, _), (target_pos2, _)| target_pos2.cmp(target_pos1));
+        let edges = edges.into_iter().map(|(_, edge)| edge).collect();
+        Some((index_entry, edges))
+    }
+
     fn next_index_entry(&mut self) -> Option<IndexEntry<'index>> {
         if let Some(index_entry) = self.look_ahead.pop_last_value() {
             return Some(index_entry);
@@ -274,14 +292,111 @@ impl<'revset, 'index> Iterator for RevsetGraphIterator<'revset, 'index> {
     type Item = (CommitId, Vec<RevsetGraphEdge>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let index_entry = self.next_index_entry()?;
-        let mut edges = self.edges_from_internal_commit(&index_entry);
-        if self.skip_transitive_edges {
-            edges = self.remove_transitive_edges(edges);
-        }
-        let mut edges: Vec<_> = edges.into_iter().collect();
-        edges.sort_by(|(target_pos1, _), (target_pos2, _)| target_pos2.cmp(target_pos1));
-        let edges = edges.into_iter().map(|(_, edge)| edge).collect();
+        let (index_entry, edges) = self.next_entry()?;
         Some((index_entry.commit_id(), edges))
     }
 }
+
+/// Like `RevsetGraphIterator`, but also yields the change id of each commit.
+pub struct RevsetGraphWithChangeIdsIterator<'revset, 'index>(RevsetGraphIterator<'revset, 'index>);
+
+impl<'revset, 'index> Iterator for RevsetGraphWithChangeIdsIterator<'revset, 'index> {
+    type Item = (CommitId, ChangeId, Vec<RevsetGraphEdge>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (index_entry, edges) = self.0.next_entry()?;
+        Some((index_entry.commit_id(), index_entry.change_id(), edges))
+    }
+}
+
+/// Graph row of `RevsetGraphMergedIterator`, holding the edges of each side
+/// that contains the commit.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RevsetGraphMergedRow {
+    Left(Vec<RevsetGraphEdge>),
+    Right(Vec<RevsetGraphEdge>),
+    Both(Vec<RevsetGraphEdge>, Vec<RevsetGraphEdge>),
+}
+
+impl RevsetGraphMergedRow {
+    pub fn in_left(&self) -> bool {
+        matches!(self, Self::Left(_) | Self::Both(..))
+    }
+
+    pub fn in_right(&self) -> bool {
+        matches!(self, Self::Right(_) | Self::Both(..))
+    }
+
+    pub fn left_edges(&self) -> Option<&[RevsetGraphEdge]> {
+        match self {
+            Self::Left(edges) | Self::Both(edges, _) => Some(edges),
+            Self::Right(_) => None,
+        }
+    }
+
+    pub fn right_edges(&self) -> Option<&[RevsetGraphEdge]> {
+        match self {
+            Self::Right(edges) | Self::Both(_, edges) => Some(edges),
+            Self::Left(_) => None,
+        }
+    }
+}
+
+/// Merges the graphs of two revsets over the same index, aligning the rows by
+/// commit. Each side's edges are computed within that side's revset.
+pub struct RevsetGraphMergedIterator<'revset, 'index> {
+    left: RevsetGraphIterator<'revset, 'index>,
+    right: RevsetGraphIterator<'revset, 'index>,
+    left_peeked: Option<(IndexEntry<'index>, Vec<RevsetGraphEdge>)>,
+    right_peeked: Option<(IndexEntry<'index>, Vec<RevsetGraphEdge>)>,
+}
+
+impl<'revset, 'index> RevsetGraphMergedIterator<'revset, 'index> {
+    pub fn new(
+        left: RevsetGraphIterator<'revset, 'index>,
+        right: RevsetGraphIterator<'revset, 'index>,
+    ) -> Self {
+        RevsetGraphMergedIterator {
+            left,
+            right,
+            left_peeked: None,
+            right_peeked: None,
+        }
+    }
+}
+
+impl<'revset, 'index> Iterator for RevsetGraphMergedIterator<'revset, 'index> {
+    type Item = (CommitId, RevsetGraphMergedRow);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left_peeked.is_none() {
+            self.left_peeked = self.left.next_entry();
+        }
+        if self.right_peeked.is_none() {
+            self.right_peeked = self.right.next_entry();
+        }
+        // Both sides are sorted by descending position
+        let left_position = self.left_peeked.as_ref().map(|(entry, _)| entry.position());
+        let right_position = self
+            .right_peeked
+            .as_ref()
+            .map(|(entry, _)| entry.position());
+        match (left_position, right_position) {
+            (None, None) => None,
+            (Some(left), Some(right)) if left == right => {
+                let (entry, left_edges) = self.left_peeked.take().unwrap();
+                let (_, right_edges) = self.right_peeked.take().unwrap();
+                let row = RevsetGraphMergedRow::Both(left_edges, right_edges);
+                Some((entry.commit_id(), row))
+            }
+            (Some(left), right) if right.map_or(true, |right| left > right) => {
+                let (entry, edges) = self.left_peeked.take().unwrap();
+                Some((entry.commit_id(), RevsetGraphMergedRow::Left(edges)))
+            }
+            _ => {
+                let (entry, edges) = self.right_peeked.take().unwrap();
+                Some((entry.commit_id(), RevsetGraphMergedRow::Right(edges)))
+            }
+        }
+    }
+}
@@ -14,6 +14,7 @@
 
 use std::rc::Rc;
 
+use itertools::Itertools as _;
 use once_cell::unsync::OnceCell;
 
 use crate::backend::{self, ChangeId, CommitId, ObjectId};
@@ -214,6 +215,17 @@ where
             .map(|(k, v)| (k, v))
     }
 
+    /// Returns up to `limit` distinct keys with the given prefix, in sorted
+    /// order. Unlike `resolve_prefix_range()`, keys with multiple values are
+    /// listed once.
+    pub fn prefix_keys(&self, prefix: &HexPrefix, limit: usize) -> Vec<&K> {
+        self.resolve_prefix_range(prefix)
+            .map(|(k, _)| k)
+            .dedup()
+            .take(limit)
+            .collect()
+    }
+
     pub fn has_key(&self, key: &K) -> bool {
         self.0.binary_search_by(|(k, _)| k.cmp(key)).is_ok()
     }
@@ -244,6 +256,30 @@ where
             // Even if the key is the only one in the index, we require at least one digit.
             .unwrap_or(1)
     }
+
+    /// Returns `shortest_unique_prefix_len()` of every entry, computed in a
+    /// single pass over the sorted entries instead of one lookup per key.
+    ///
+    /// The entries are returned in key order.
+    pub fn shortest_unique_prefix_lens(&self) -> Vec<(&K, &V, usize)> {
+        let mut lens = Vec::with_capacity(self.0.len());
+        let mut prev_key: Option<&K> = None;
+        let mut start = 0;
+        while start < self.0.len() {
+            // Entries with the same key share the neighbors on both sides.
+            let key = &self.0[start].0;
+            let end = start + self.0[start..].iter().take_while(|(k, _)| k == key).count();
+            let next_key = self.0.get(end).map(|(k, _)| k);
+            let len = itertools::chain(prev_key, next_key)
+                .map(|neighbor| backend::common_hex_len(key.as_bytes(), neighbor.as_bytes()) + 1)
+                .max()
+                .unwrap_or(1);
+            lens.extend(self.0[start..end].iter().map(|(k, v)| (k, v, len)));
+            prev_key = Some(key);
+            start = end;
+        }
+        lens
+    }
 }
 
 #[cfg(test)]
@@ -300,6 +336,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_id_index_prefix_keys() {
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("0000"), 0),
+            (ChangeId::from_hex("0099"), 1),
+            (ChangeId::from_hex("0099"), 2),
+            (ChangeId::from_hex("0aaa"), 3),
+            (ChangeId::from_hex("0aab"), 4),
+            (ChangeId::from_hex("1000"), 5),
+        ]);
+        let prefix_keys = |prefix: &str, limit: usize| {
+            id_index
+                .prefix_keys(&HexPrefix::new(prefix).unwrap(), limit)
+                .into_iter()
+                .map(|k| k.hex())
+                .collect_vec()
+        };
+        // Duplicate keys are listed once
+        assert_eq!(prefix_keys("0", 3), vec!["0000", "0099", "0aaa"]);
+        assert_eq!(prefix_keys("0", 10), vec!["0000", "0099", "0aaa", "0aab"]);
+        assert_eq!(prefix_keys("009", 10), vec!["0099"]);
+        assert_eq!(prefix_keys("0", 0), Vec::<String>::new());
+        assert_eq!(prefix_keys("f", 10), Vec::<String>::new());
+    }
+
     #[test]
     fn test_has_key() {
         // No crash if empty
@@ -365,4 +426,32 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn test_id_index_shortest_unique_prefix_lens() {
+        // No crash if empty
+        let id_index = IdIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert!(id_index.shortest_unique_prefix_lens().is_empty());
+
+        let id_index = IdIndex::from_vec(vec![(ChangeId::from_hex("ab"), 0)]);
+        assert_eq!(
+            id_index.shortest_unique_prefix_lens(),
+            vec![(&ChangeId::from_hex("ab"), &0, 1)]
+        );
+
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("ab"), 0),
+            (ChangeId::from_hex("acd0"), 1),
+            (ChangeId::from_hex("acd0"), 2), // duplicated key is allowed
+            (ChangeId::from_hex("acf0"), 3),
+            (ChangeId::from_hex("a0"), 4),
+            (ChangeId::from_hex("ba"), 5),
+            (ChangeId::from_hex("ba12"), 6), // key prefixed by another key
+        ]);
+        let lens = id_index.shortest_unique_prefix_lens();
+        assert_eq!(lens.len(), 7);
+        for (key, _, len) in lens {
+            assert_eq!(len, id_index.shortest_unique_prefix_len(key), "{key:?}");
+        }
+    }
 }
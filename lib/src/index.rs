@@ -170,6 +170,16 @@ impl<T> PrefixResolution<T> {
             PrefixResolution::AmbiguousMatch => PrefixResolution::AmbiguousMatch,
         }
     }
+
+    /// Chains another resolution step on a single match. `NoMatch` and
+    /// `AmbiguousMatch` are passed through.
+    pub fn and_then<U>(self, f: impl FnOnce(T) -> PrefixResolution<U>) -> PrefixResolution<U> {
+        match self {
+            PrefixResolution::NoMatch => PrefixResolution::NoMatch,
+            PrefixResolution::SingleMatch(x) => f(x),
+            PrefixResolution::AmbiguousMatch => PrefixResolution::AmbiguousMatch,
+        }
+    }
 }
 
 impl<T: Clone> PrefixResolution<T> {
@@ -221,4 +231,50 @@ mod tests {
         assert!(!HexPrefix::new("12a").unwrap().matches(&id));
         assert!(!HexPrefix::new("123a").unwrap().matches(&id));
     }
+
+    #[test]
+    fn test_prefix_resolution_map() {
+        let double = |x: i32| x * 2;
+        assert_eq!(
+            PrefixResolution::NoMatch.map(double),
+            PrefixResolution::NoMatch
+        );
+        assert_eq!(
+            PrefixResolution::SingleMatch(3).map(double),
+            PrefixResolution::SingleMatch(6)
+        );
+        assert_eq!(
+            PrefixResolution::AmbiguousMatch.map(double),
+            PrefixResolution::AmbiguousMatch
+        );
+    }
+
+    #[test]
+    fn test_prefix_resolution_and_then() {
+        let positive = |x: i32| match x {
+            0 => PrefixResolution::NoMatch,
+            x if x > 0 => PrefixResolution::SingleMatch(x as u32),
+            _ => PrefixResolution::AmbiguousMatch,
+        };
+        assert_eq!(
+            PrefixResolution::NoMatch.and_then(positive),
+            PrefixResolution::NoMatch
+        );
+        assert_eq!(
+            PrefixResolution::AmbiguousMatch.and_then(positive),
+            PrefixResolution::AmbiguousMatch
+        );
+        assert_eq!(
+            PrefixResolution::SingleMatch(1).and_then(positive),
+            PrefixResolution::SingleMatch(1)
+        );
+        assert_eq!(
+            PrefixResolution::SingleMatch(0).and_then(positive),
+            PrefixResolution::NoMatch
+        );
+        assert_eq!(
+            PrefixResolution::SingleMatch(-1).and_then(positive),
+            PrefixResolution::AmbiguousMatch
+        );
+    }
 }
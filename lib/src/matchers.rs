@@ -157,6 +157,48 @@ impl Matcher for PrefixMatcher {
     }
 }
 
+/// Like `PrefixMatcher`, but compares the path components ignoring case.
+pub struct CaseInsensitivePrefixMatcher {
+    prefixes: Vec<Vec<String>>,
+}
+
+impl CaseInsensitivePrefixMatcher {
+    pub fn new(prefixes: &[RepoPath]) -> Self {
+        let prefixes = prefixes.iter().map(lowercase_components).collect();
+        CaseInsensitivePrefixMatcher { prefixes }
+    }
+}
+
+fn lowercase_components(path: &RepoPath) -> Vec<String> {
+    path.components()
+        .iter()
+        .map(|component| component.as_str().to_lowercase())
+        .collect()
+}
+
+impl Matcher for CaseInsensitivePrefixMatcher {
+    fn matches(&self, file: &RepoPath) -> bool {
+        let file = lowercase_components(file);
+        self.prefixes.iter().any(|prefix| file.starts_with(prefix))
+    }
+
+    fn visit(&self, dir: &RepoPath) -> Visit {
+        let dir = lowercase_components(dir);
+        if self.prefixes.iter().any(|prefix| dir.starts_with(prefix)) {
+            Visit::AllRecursively
+        } else if self.prefixes.iter().any(|prefix| prefix.starts_with(&dir)) {
+            // The entries in the directory can't be listed since their names
+            // might be cased differently.
+            Visit::Specific {
+                dirs: VisitDirs::All,
+                files: VisitFiles::All,
+            }
+        } else {
+            Visit::Nothing
+        }
+    }
+}
+
 /// Matches paths that are matched by the first input matcher but not by the
 /// second.
 pub struct DifferenceMatcher<'input> {
@@ -514,6 +556,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_caseinsensitiveprefixmatcher() {
+        let m = CaseInsensitivePrefixMatcher::new(&[RepoPath::from_internal_string("Foo/bar")]);
+        assert!(!m.matches(&RepoPath::from_internal_string("foo")));
+        assert!(m.matches(&RepoPath::from_internal_string("foo/bar")));
+        assert!(m.matches(&RepoPath::from_internal_string("FOO/Bar/baz")));
+        assert!(!m.matches(&RepoPath::from_internal_string("foo/barbaz")));
+        assert!(!m.matches(&RepoPath::from_internal_string("bar/foo/bar")));
+
+        // Ancestor directories of the prefix are visited regardless of case
+        assert_eq!(
+            m.visit(&RepoPath::root()),
+            Visit::Specific {
+                dirs: VisitDirs::All,
+                files: VisitFiles::All
+            }
+        );
+        assert_eq!(
+            m.visit(&RepoPath::from_internal_string("FOO")),
+            Visit::Specific {
+                dirs: VisitDirs::All,
+                files: VisitFiles::All
+            }
+        );
+        assert_eq!(
+            m.visit(&RepoPath::from_internal_string("foo/BAR")),
+            Visit::AllRecursively
+        );
+        assert_eq!(
+            m.visit(&RepoPath::from_internal_string("bar")),
+            Visit::Nothing
+        );
+    }
+
     #[test]
     fn test_prefixmatcher_nested_prefixes() {
         let m = PrefixMatcher::new(&[
@@ -18,8 +18,10 @@ use std::fmt::{Debug, Error, Formatter};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
+use itertools::Itertools as _;
+
 use crate::backend::CommitId;
-use crate::op_store::{OpStore, OperationId, ViewId};
+use crate::op_store::{OpStore, OpStoreResult, OperationId, ViewId};
 use crate::{dag_walk, op_store};
 
 #[derive(Clone)]
@@ -79,16 +81,27 @@ impl Operation {
     }
 
     pub fn parents(&self) -> Vec<Operation> {
-        let mut parents = Vec::new();
-        for parent_id in &self.data.parents {
-            let data = self.op_store.read_operation(parent_id).unwrap();
-            parents.push(Operation::new(
-                self.op_store.clone(),
-                parent_id.clone(),
-                data,
-            ));
-        }
-        parents
+        self.try_parents()
+            .into_iter()
+            .map(|parent| parent.unwrap())
+            .collect()
+    }
+
+    /// Loads the parents in the order of `parent_ids()`, without panicking if
+    /// some of them can't be read from the store.
+    pub fn try_parents(&self) -> Vec<OpStoreResult<Operation>> {
+        self.data
+            .parents
+            .iter()
+            .map(|parent_id| {
+                let data = self.op_store.read_operation(parent_id)?;
+                Ok(Operation::new(
+                    self.op_store.clone(),
+                    parent_id.clone(),
+                    data,
+                ))
+            })
+            .collect()
     }
 
     pub fn view(&self) -> View {
@@ -196,3 +209,90 @@ pub fn walk_ancestors(head_op: &Operation) -> impl Iterator<Item = Operation> {
     )
     .map(|OperationByEndTime(op)| op)
 }
+
+/// Operation that couldn't be loaded from the store.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to load operation {}: {source}", id.hex())]
+pub struct OperationLoadError {
+    pub id: OperationId,
+    #[source]
+    pub source: op_store::OpStoreError,
+}
+
+/// Walks `head_ops` and their ancestors in reverse topological order.
+///
+/// A parent that can't be loaded from the store is yielded as an error after
+/// all of its children, and its ancestors aren't walked.
+pub fn walk_ancestors_of_heads(
+    head_ops: &[Operation],
+) -> impl Iterator<Item = Result<Operation, OperationLoadError>> {
+    dag_walk::topo_order_reverse_lazy(
+        head_ops
+            .iter()
+            .cloned()
+            .map(|op| WalkNode::Loaded(OperationByEndTime(op)))
+            .collect_vec(),
+        |node| node.id().clone(),
+        |node| match node {
+            WalkNode::Loaded(OperationByEndTime(op)) => op
+                .parent_ids()
+                .iter()
+                .zip(op.try_parents())
+                .map(|(parent_id, parent)| match parent {
+                    Ok(parent) => WalkNode::Loaded(OperationByEndTime(parent)),
+                    Err(err) => WalkNode::Missing(OperationLoadError {
+                        id: parent_id.clone(),
+                        source: err,
+                    }),
+                })
+                .collect_vec(),
+            WalkNode::Missing(_) => vec![],
+        },
+    )
+    .map(|node| match node {
+        WalkNode::Loaded(OperationByEndTime(op)) => Ok(op),
+        WalkNode::Missing(err) => Err(err),
+    })
+}
+
+/// Node of `walk_ancestors_of_heads()`. Operations that can't be loaded have
+/// no end time, and are ordered before the others so they are visited last.
+#[derive(Debug)]
+enum WalkNode {
+    Missing(OperationLoadError),
+    Loaded(OperationByEndTime),
+}
+
+impl WalkNode {
+    fn id(&self) -> &OperationId {
+        match self {
+            WalkNode::Missing(err) => &err.id,
+            WalkNode::Loaded(OperationByEndTime(op)) => op.id(),
+        }
+    }
+}
+
+impl PartialEq for WalkNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for WalkNode {}
+
+impl Ord for WalkNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (WalkNode::Missing(err1), WalkNode::Missing(err2)) => err1.id.cmp(&err2.id),
+            (WalkNode::Missing(_), WalkNode::Loaded(_)) => Ordering::Less,
+            (WalkNode::Loaded(_), WalkNode::Missing(_)) => Ordering::Greater,
+            (WalkNode::Loaded(op1), WalkNode::Loaded(op2)) => op1.cmp(op2),
+        }
+    }
+}
+
+impl PartialOrd for WalkNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
@@ -14,7 +14,8 @@
 
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::ops::Range;
+use std::num::NonZeroUsize;
+use std::ops::{ControlFlow, Range};
 use std::path::Path;
 use std::rc::Rc;
 use std::str::FromStr;
@@ -66,6 +67,15 @@ pub enum RevsetEvaluationError {
     Other(String),
 }
 
+/// Error occurred while evaluating a `PreparedRevsetExpression`.
+#[derive(Debug, Error)]
+pub enum PreparedRevsetError {
+    #[error(transparent)]
+    Resolution(#[from] RevsetResolutionError),
+    #[error(transparent)]
+    Evaluation(#[from] RevsetEvaluationError),
+}
+
 #[derive(Parser)]
 #[grammar = "revset.pest"]
 pub struct RevsetParser;
@@ -110,6 +120,8 @@ pub enum RevsetParseErrorKind {
     BadAliasExpansion(String),
     #[error(r#"Alias "{0}" expanded recursively"#)]
     RecursiveAlias(String),
+    #[error("String pattern isn't expected here")]
+    UnexpectedStringPattern,
 }
 
 impl RevsetParseError {
@@ -218,20 +230,94 @@ pub enum RevsetCommitRef {
     GitHead,
 }
 
+/// Pattern to be tested against string property like author email.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StringPattern {
+    /// Matches strings containing the needle.
+    Substring(String),
+    /// Matches strings containing the needle, ignoring case.
+    SubstringI(String),
+    /// Matches strings equal to the needle.
+    Exact(String),
+    /// Matches strings equal to the needle, ignoring case.
+    ExactI(String),
+}
+
+impl StringPattern {
+    /// Returns true if this pattern matches the `haystack`.
+    pub fn matches(&self, haystack: &str) -> bool {
+        match self {
+            StringPattern::Substring(needle) => haystack.contains(needle.as_str()),
+            StringPattern::SubstringI(needle) => haystack
+                .to_lowercase()
+                .contains(needle.to_lowercase().as_str()),
+            StringPattern::Exact(needle) => haystack == needle,
+            StringPattern::ExactI(needle) => haystack.to_lowercase() == needle.to_lowercase(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum RevsetFilterPredicate {
     /// Commits with number of parents in the range.
     ParentCount(Range<u32>),
     /// Commits with description containing the needle.
     Description(String),
-    /// Commits with author's name or email containing the needle.
-    Author(String),
-    /// Commits with committer's name or email containing the needle.
-    Committer(String),
+    /// Commits with non-empty description if true, or with empty description
+    /// if false.
+    HasDescription(bool),
+    /// Commits with a trailer of the key, and of the value if specified, in
+    /// the last paragraph of the description.
+    Trailer { key: String, value: Option<String> },
+    /// Commits with author's name or email containing any of the needles.
+    Author(Vec<String>),
+    /// Commits with committer's name or email containing any of the needles.
+    Committer(Vec<String>),
+    /// Commits with author's email matching the pattern.
+    AuthorEmail(StringPattern),
     /// Commits modifying the paths specified by the pattern.
     File(Option<Vec<RepoPath>>), // TODO: embed matcher expression?
+    /// Commits modifying the paths specified by the pattern, ignoring case.
+    FileCaseInsensitive(Vec<RepoPath>),
+    /// Commits modifying every one of the paths.
+    FileAll(Vec<RepoPath>),
+    /// Commits adding any of the paths, which are absent in the merged tree
+    /// of the parents.
+    FileAdded(Vec<RepoPath>),
+    /// Commits with number of modified files in the range.
+    FileCount(Range<u32>),
+    /// Commits modifying the paths specified by the pattern, compared to their
+    /// nearest ancestors within the candidate set instead of their parents.
+    FileWithinCandidates(Option<Vec<RepoPath>>),
     /// Commits with conflicts
     HasConflict,
+    /// Commits whose change id starts with the prefix. Divergent commits of
+    /// the same change all match.
+    ChangeIdPrefix(HexPrefix),
+    /// Commits whose commit id starts with the prefix
+    CommitIdPrefix(HexPrefix),
+}
+
+/// Timestamp to order commits by in `latest()`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LatestTimestamp {
+    /// Committer timestamp, which is updated when a commit is rewritten.
+    #[default]
+    Committer,
+    /// Author timestamp, which is preserved when a commit is rebased.
+    Author,
+}
+
+impl FromStr for LatestTimestamp {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "committer" => Ok(LatestTimestamp::Committer),
+            "author" => Ok(LatestTimestamp::Author),
+            _ => Err(()),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -262,18 +348,36 @@ pub enum RevsetExpression {
     },
     Heads(Rc<RevsetExpression>),
     Roots(Rc<RevsetExpression>),
+    /// Parent at `index` (0-based) of each commit in `children`.
+    NthParent {
+        children: Rc<RevsetExpression>,
+        index: usize,
+    },
     Latest {
         candidates: Rc<RevsetExpression>,
         count: usize,
+        timestamp: LatestTimestamp,
     },
+    /// Latest commit of each change in `candidates`.
+    LatestPerChange(Rc<RevsetExpression>),
+    /// Commits in `candidates` without a descendant of the same change in
+    /// `candidates`.
+    ChangeHeads(Rc<RevsetExpression>),
     Filter(RevsetFilterPredicate),
+    /// Filter for commits whose tree is identical to the tree of any commit in
+    /// the given set.
+    SameTreeAs(Rc<RevsetExpression>),
     /// Marker for subtree that should be intersected as filter.
     AsFilter(Rc<RevsetExpression>),
     Present(Rc<RevsetExpression>),
+    /// First non-empty set among the expressions. Unknown symbols in the
+    /// expressions resolve to empty sets.
+    Coalesce(Vec<Rc<RevsetExpression>>),
     NotIn(Rc<RevsetExpression>),
     Union(Rc<RevsetExpression>, Rc<RevsetExpression>),
     Intersection(Rc<RevsetExpression>, Rc<RevsetExpression>),
     Difference(Rc<RevsetExpression>, Rc<RevsetExpression>),
+    SymmetricDifference(Rc<RevsetExpression>, Rc<RevsetExpression>),
 }
 
 impl RevsetExpression {
@@ -329,21 +433,56 @@ impl RevsetExpression {
     }
 
     pub fn latest(self: &Rc<RevsetExpression>, count: usize) -> Rc<RevsetExpression> {
+        self.latest_by(count, LatestTimestamp::default())
+    }
+
+    pub fn latest_by(
+        self: &Rc<RevsetExpression>,
+        count: usize,
+        timestamp: LatestTimestamp,
+    ) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::Latest {
             candidates: self.clone(),
             count,
+            timestamp,
         })
     }
 
+    /// Latest commit of each change in `self`, to pick one of divergent
+    /// commits.
+    pub fn latest_per_change(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
+        Rc::new(RevsetExpression::LatestPerChange(self.clone()))
+    }
+
+    /// Commits in `self` that aren't superseded by a descendant of the same
+    /// change in `self`. Divergent commits of a change are all kept.
+    pub fn change_heads(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
+        Rc::new(RevsetExpression::ChangeHeads(self.clone()))
+    }
+
     pub fn filter(predicate: RevsetFilterPredicate) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::Filter(predicate))
     }
 
+    /// Commits whose tree is identical to the tree of any commit in `self`.
+    pub fn same_tree_as(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
+        Rc::new(RevsetExpression::SameTreeAs(self.clone()))
+    }
+
     /// Commits in `self` that don't have descendants in `self`.
     pub fn heads(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::Heads(self.clone()))
     }
 
+    /// Heads of the common ancestors of `self` and `other`. There can be
+    /// several of them if the history has criss-cross merges.
+    pub fn merge_base(
+        self: &Rc<RevsetExpression>,
+        other: &Rc<RevsetExpression>,
+    ) -> Rc<RevsetExpression> {
+        self.ancestors().intersection(&other.ancestors()).heads()
+    }
+
     /// Commits in `self` that don't have ancestors in `self`.
     pub fn roots(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::Roots(self.clone()))
@@ -357,6 +496,15 @@ impl RevsetExpression {
         })
     }
 
+    /// Parent at `index` (0-based) of each commit in `self`. Commits with
+    /// fewer parents contribute nothing.
+    pub fn nth_parent(self: &Rc<RevsetExpression>, index: usize) -> Rc<RevsetExpression> {
+        Rc::new(RevsetExpression::NthParent {
+            children: self.clone(),
+            index,
+        })
+    }
+
     /// Ancestors of `self`, including `self`.
     pub fn ancestors(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::Ancestors {
@@ -373,6 +521,16 @@ impl RevsetExpression {
         })
     }
 
+    /// Children of `self` that are in `candidates`. If `candidates` is a list
+    /// of commits, only their parents are tested instead of walking the
+    /// descendants of `self`.
+    pub fn children_within(
+        self: &Rc<RevsetExpression>,
+        candidates: &Rc<RevsetExpression>,
+    ) -> Rc<RevsetExpression> {
+        self.children().intersection(candidates)
+    }
+
     /// Descendants of `self`, including `self`.
     pub fn descendants(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::Descendants {
@@ -411,6 +569,21 @@ impl RevsetExpression {
         })
     }
 
+    /// Ancestors of `self` that aren't ancestors of `roots`. This is the same
+    /// `Range` as `roots.range(self)`, or `roots..self` in the revset
+    /// language, with the heads given first.
+    pub fn range_excluding(
+        self: &Rc<RevsetExpression>,
+        roots: &Rc<RevsetExpression>,
+    ) -> Rc<RevsetExpression> {
+        roots.range(self)
+    }
+
+    /// Commits in the first of `expressions` that isn't empty.
+    pub fn coalesce(expressions: &[Rc<RevsetExpression>]) -> Rc<RevsetExpression> {
+        Rc::new(RevsetExpression::Coalesce(expressions.to_vec()))
+    }
+
     /// Commits that are not in `self`, i.e. the complement of `self`.
     pub fn negated(self: &Rc<RevsetExpression>) -> Rc<RevsetExpression> {
         Rc::new(RevsetExpression::NotIn(self.clone()))
@@ -440,6 +613,17 @@ impl RevsetExpression {
         Rc::new(RevsetExpression::Difference(self.clone(), other.clone()))
     }
 
+    /// Commits that are in exactly one of `self` and `other`.
+    pub fn symmetric_difference(
+        self: &Rc<RevsetExpression>,
+        other: &Rc<RevsetExpression>,
+    ) -> Rc<RevsetExpression> {
+        Rc::new(RevsetExpression::SymmetricDifference(
+            self.clone(),
+            other.clone(),
+        ))
+    }
+
     pub fn resolve(
         self: Rc<Self>,
         repo: &dyn Repo,
@@ -457,6 +641,21 @@ impl RevsetExpression {
         resolve_symbols(repo, self, symbol_resolver)
             .map(|expression| resolve_visibility(repo, &expression))
     }
+
+    /// Like `resolve_user_expression()`, but `all()` and descendants such as
+    /// `x:` are bounded by the given `visible_heads` instead of the heads of
+    /// the repo's view. For example, pass all heads of the index to make
+    /// `all()` include hidden commits.
+    pub fn resolve_with_visible_heads(
+        self: Rc<Self>,
+        repo: &dyn Repo,
+        symbol_resolver: &dyn SymbolResolver,
+        visible_heads: &[CommitId],
+    ) -> Result<ResolvedExpression, RevsetResolutionError> {
+        let expression = resolve_symbols(repo, self, symbol_resolver)?;
+        let context = VisibilityResolutionContext { visible_heads };
+        Ok(context.resolve(&expression))
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -466,6 +665,8 @@ pub enum ResolvedPredicateExpression {
     /// Set expression to be evaluated as filter. This is typically a subtree
     /// node of `Union` with a pure filter predicate.
     Set(Box<ResolvedExpression>),
+    /// Commits whose tree is identical to the tree of any commit in the set.
+    SameTreeAs(Box<ResolvedExpression>),
     NotIn(Box<ResolvedPredicateExpression>),
     Union(
         Box<ResolvedPredicateExpression>,
@@ -500,10 +701,29 @@ pub enum ResolvedExpression {
     },
     Heads(Box<ResolvedExpression>),
     Roots(Box<ResolvedExpression>),
+    NthParent {
+        children: Box<ResolvedExpression>,
+        index: usize,
+    },
     Latest {
         candidates: Box<ResolvedExpression>,
         count: usize,
+        timestamp: LatestTimestamp,
+    },
+    LatestPerChange(Box<ResolvedExpression>),
+    ChangeHeads(Box<ResolvedExpression>),
+    /// Commits in `candidates` that have a parent in `roots`.
+    ChildrenWithin {
+        roots: Box<ResolvedExpression>,
+        candidates: Box<ResolvedExpression>,
+    },
+    /// Commits in `candidates` whose change id starts with `prefix`, looked up
+    /// in an index of their change ids.
+    ChangeIdPrefix {
+        candidates: Box<ResolvedExpression>,
+        prefix: HexPrefix,
     },
+    Coalesce(Vec<ResolvedExpression>),
     Union(Box<ResolvedExpression>, Box<ResolvedExpression>),
     /// Intersects `candidates` with `predicate` by filtering.
     FilterWithin {
@@ -513,6 +733,7 @@ pub enum ResolvedExpression {
     /// Intersects expressions by merging.
     Intersection(Box<ResolvedExpression>, Box<ResolvedExpression>),
     Difference(Box<ResolvedExpression>, Box<ResolvedExpression>),
+    SymmetricDifference(Box<ResolvedExpression>, Box<ResolvedExpression>),
 }
 
 impl ResolvedExpression {
@@ -524,6 +745,42 @@ impl ResolvedExpression {
     }
 }
 
+/// Revset expression optimized once to be evaluated repeatedly.
+///
+/// A `Revset` borrows the index it was evaluated against, so it can't follow
+/// index updates. Keep this instead, and evaluate it against each new repo
+/// snapshot. Symbols and visible heads are resolved per evaluation since they
+/// depend on the snapshot's view.
+#[derive(Clone, Debug)]
+pub struct PreparedRevsetExpression {
+    expression: Rc<RevsetExpression>,
+}
+
+impl PreparedRevsetExpression {
+    pub fn new(expression: Rc<RevsetExpression>) -> Self {
+        PreparedRevsetExpression {
+            expression: optimize(expression),
+        }
+    }
+
+    /// The optimized expression.
+    pub fn expression(&self) -> &Rc<RevsetExpression> {
+        &self.expression
+    }
+
+    pub fn evaluate<'index>(
+        &self,
+        repo: &'index dyn Repo,
+        symbol_resolver: &dyn SymbolResolver,
+    ) -> Result<Box<dyn Revset<'index> + 'index>, PreparedRevsetError> {
+        let resolved = self
+            .expression
+            .clone()
+            .resolve_user_expression(repo, symbol_resolver)?;
+        Ok(resolved.evaluate(repo)?)
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct RevsetAliasesMap {
     symbol_aliases: HashMap<String, String>,
@@ -715,6 +972,7 @@ fn parse_expression_rule(
     static PRATT: Lazy<PrattParser<Rule>> = Lazy::new(|| {
         PrattParser::new()
             .op(Op::infix(Rule::union_op, Assoc::Left)
+                | Op::infix(Rule::symmetric_difference_op, Assoc::Left)
                 | Op::infix(Rule::compat_add_op, Assoc::Left))
             .op(Op::infix(Rule::intersection_op, Assoc::Left)
                 | Op::infix(Rule::difference_op, Assoc::Left)
@@ -749,6 +1007,7 @@ fn parse_expression_rule(
             Rule::compat_add_op => Err(not_infix_op(&op, "|", "union")),
             Rule::intersection_op => Ok(lhs?.intersection(&rhs?)),
             Rule::difference_op => Ok(lhs?.minus(&rhs?)),
+            Rule::symmetric_difference_op => Ok(lhs?.symmetric_difference(&rhs?)),
             Rule::compat_sub_op => Err(not_infix_op(&op, "~", "difference")),
             Rule::dag_range_op => Ok(lhs?.dag_range_to(&rhs?)),
             Rule::range_op => Ok(lhs?.range(&rhs?)),
@@ -770,7 +1029,7 @@ fn parse_primary_rule(
             let arguments_pair = pairs.next().unwrap();
             parse_function_expression(first, arguments_pair, state, span)
         }
-        Rule::symbol => parse_symbol_rule(first.into_inner(), state),
+        Rule::symbol => parse_symbol_rule(first.into_inner().next().unwrap(), state),
         _ => {
             panic!("unexpected revset parse rule: {:?}", first.as_str());
         }
@@ -778,10 +1037,9 @@ fn parse_primary_rule(
 }
 
 fn parse_symbol_rule(
-    mut pairs: Pairs<Rule>,
+    first: Pair<Rule>,
     state: ParseState,
 ) -> Result<Rc<RevsetExpression>, RevsetParseError> {
-    let first = pairs.next().unwrap();
     match first.as_rule() {
         Rule::identifier => {
             let name = first.as_str();
@@ -828,7 +1086,7 @@ fn parse_function_expression(
         assert!(optional.is_empty());
         let args: Vec<_> = required
             .into_iter()
-            .map(|arg| parse_expression_rule(arg.into_inner(), state))
+            .map(|arg| parse_function_argument_to_expression(arg, state))
             .try_collect()?;
         let locals = params.iter().map(|s| s.as_str()).zip(args).collect();
         state.with_alias_expanding(id, &locals, primary_span, |state| {
@@ -878,27 +1136,40 @@ static BUILTIN_FUNCTION_MAP: Lazy<HashMap<&'static str, RevsetFunction>> = Lazy:
     let mut map: HashMap<&'static str, RevsetFunction> = HashMap::new();
     map.insert("parents", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let expression = parse_expression_rule(arg.into_inner(), state)?;
+        let expression = parse_function_argument_to_expression(arg, state)?;
         Ok(expression.parents())
     });
+    map.insert("parent", |name, arguments_pair, state| {
+        let ([children_arg, nth_arg], []) = expect_arguments(name, arguments_pair)?;
+        let children = parse_function_argument_to_expression(children_arg, state)?;
+        let nth: NonZeroUsize =
+            parse_function_argument_as_literal("positive integer", name, nth_arg, state)?;
+        Ok(children.nth_parent(nth.get() - 1))
+    });
     map.insert("children", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let expression = parse_expression_rule(arg.into_inner(), state)?;
+        let expression = parse_function_argument_to_expression(arg, state)?;
         Ok(expression.children())
     });
+    map.insert("children_in", |name, arguments_pair, state| {
+        let ([parents_arg, candidates_arg], []) = expect_arguments(name, arguments_pair)?;
+        let parents = parse_function_argument_to_expression(parents_arg, state)?;
+        let candidates = parse_function_argument_to_expression(candidates_arg, state)?;
+        Ok(parents.children_within(&candidates))
+    });
     map.insert("ancestors", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let expression = parse_expression_rule(arg.into_inner(), state)?;
+        let expression = parse_function_argument_to_expression(arg, state)?;
         Ok(expression.ancestors())
     });
     map.insert("descendants", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let expression = parse_expression_rule(arg.into_inner(), state)?;
+        let expression = parse_function_argument_to_expression(arg, state)?;
         Ok(expression.descendants())
     });
     map.insert("connected", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let candidates = parse_expression_rule(arg.into_inner(), state)?;
+        let candidates = parse_function_argument_to_expression(arg, state)?;
         Ok(candidates.connected())
     });
     map.insert("none", |name, arguments_pair, _state| {
@@ -911,12 +1182,18 @@ static BUILTIN_FUNCTION_MAP: Lazy<HashMap<&'static str, RevsetFunction>> = Lazy:
     });
     map.insert("heads", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let candidates = parse_expression_rule(arg.into_inner(), state)?;
+        let candidates = parse_function_argument_to_expression(arg, state)?;
         Ok(candidates.heads())
     });
+    map.insert("merge_base", |name, arguments_pair, state| {
+        let ([left_arg, right_arg], []) = expect_arguments(name, arguments_pair)?;
+        let left = parse_function_argument_to_expression(left_arg, state)?;
+        let right = parse_function_argument_to_expression(right_arg, state)?;
+        Ok(left.merge_base(&right))
+    });
     map.insert("roots", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let candidates = parse_expression_rule(arg.into_inner(), state)?;
+        let candidates = parse_function_argument_to_expression(arg, state)?;
         Ok(candidates.roots())
     });
     map.insert("visible_heads", |name, arguments_pair, _state| {
@@ -963,14 +1240,40 @@ static BUILTIN_FUNCTION_MAP: Lazy<HashMap<&'static str, RevsetFunction>> = Lazy:
         Ok(RevsetExpression::git_head())
     });
     map.insert("latest", |name, arguments_pair, state| {
-        let ([candidates_arg], [count_opt_arg]) = expect_arguments(name, arguments_pair)?;
-        let candidates = parse_expression_rule(candidates_arg.into_inner(), state)?;
+        let ([candidates_arg], [count_opt_arg, timestamp_opt_arg]) =
+            expect_named_arguments(name, &["", "count", "by"], arguments_pair)?;
+        let candidates = parse_function_argument_to_expression(candidates_arg, state)?;
         let count = if let Some(count_arg) = count_opt_arg {
             parse_function_argument_as_literal("integer", name, count_arg, state)?
         } else {
             1
         };
-        Ok(candidates.latest(count))
+        let timestamp = if let Some(timestamp_arg) = timestamp_opt_arg {
+            parse_function_argument_as_literal(
+                r#""committer" or "author""#,
+                name,
+                timestamp_arg,
+                state,
+            )?
+        } else {
+            LatestTimestamp::default()
+        };
+        Ok(candidates.latest_by(count, timestamp))
+    });
+    map.insert("latest_per_change", |name, arguments_pair, state| {
+        let arg = expect_one_argument(name, arguments_pair)?;
+        let candidates = parse_function_argument_to_expression(arg, state)?;
+        Ok(candidates.latest_per_change())
+    });
+    map.insert("change_heads", |name, arguments_pair, state| {
+        let arg = expect_one_argument(name, arguments_pair)?;
+        let candidates = parse_function_argument_to_expression(arg, state)?;
+        Ok(candidates.change_heads())
+    });
+    map.insert("same_tree_as", |name, arguments_pair, state| {
+        let arg = expect_one_argument(name, arguments_pair)?;
+        let commits = parse_function_argument_to_expression(arg, state)?;
+        Ok(commits.same_tree_as())
     });
     map.insert("merges", |name, arguments_pair, _state| {
         expect_no_arguments(name, arguments_pair)?;
@@ -980,23 +1283,46 @@ static BUILTIN_FUNCTION_MAP: Lazy<HashMap<&'static str, RevsetFunction>> = Lazy:
     });
     map.insert("description", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let needle = parse_function_argument_to_string(name, arg, state)?;
-        Ok(RevsetExpression::filter(
-            RevsetFilterPredicate::Description(needle),
-        ))
+        // Bare "empty" and "nonempty" select by presence of description.
+        // Quote them to search for the words.
+        let predicate = match arg.as_str().trim() {
+            "empty" => RevsetFilterPredicate::HasDescription(false),
+            "nonempty" => RevsetFilterPredicate::HasDescription(true),
+            _ => {
+                let needle = parse_function_argument_to_string(name, arg, state)?;
+                RevsetFilterPredicate::Description(needle)
+            }
+        };
+        Ok(RevsetExpression::filter(predicate))
+    });
+    map.insert("trailer", |name, arguments_pair, state| {
+        let ([key_arg], [value_opt_arg]) = expect_arguments(name, arguments_pair)?;
+        let key = parse_function_argument_to_string(name, key_arg, state)?;
+        let value = value_opt_arg
+            .map(|arg| parse_function_argument_to_string(name, arg, state))
+            .transpose()?;
+        Ok(RevsetExpression::filter(RevsetFilterPredicate::Trailer {
+            key,
+            value,
+        }))
     });
     map.insert("author", |name, arguments_pair, state| {
-        let arg = expect_one_argument(name, arguments_pair)?;
-        let needle = parse_function_argument_to_string(name, arg, state)?;
+        let needles = parse_function_arguments_to_strings(name, arguments_pair, state)?;
         Ok(RevsetExpression::filter(RevsetFilterPredicate::Author(
-            needle,
+            needles,
         )))
     });
-    map.insert("committer", |name, arguments_pair, state| {
+    map.insert("author_email", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let needle = parse_function_argument_to_string(name, arg, state)?;
+        let pattern = parse_function_argument_to_string_pattern(name, arg, state)?;
+        Ok(RevsetExpression::filter(
+            RevsetFilterPredicate::AuthorEmail(pattern),
+        ))
+    });
+    map.insert("committer", |name, arguments_pair, state| {
+        let needles = parse_function_arguments_to_strings(name, arguments_pair, state)?;
         Ok(RevsetExpression::filter(RevsetFilterPredicate::Committer(
-            needle,
+            needles,
         )))
     });
     map.insert("empty", |name, arguments_pair, _state| {
@@ -1004,51 +1330,113 @@ static BUILTIN_FUNCTION_MAP: Lazy<HashMap<&'static str, RevsetFunction>> = Lazy:
         Ok(RevsetExpression::filter(RevsetFilterPredicate::File(None)).negated())
     });
     map.insert("file", |name, arguments_pair, state| {
-        if let Some(ctx) = state.workspace_ctx {
-            let arguments_span = arguments_pair.as_span();
-            let paths: Vec<_> = arguments_pair
-                .into_inner()
-                .map(|arg| -> Result<_, RevsetParseError> {
-                    let span = arg.as_span();
-                    let needle = parse_function_argument_to_string(name, arg, state)?;
-                    let path = RepoPath::parse_fs_path(ctx.cwd, ctx.workspace_root, &needle)
-                        .map_err(|e| {
-                            RevsetParseError::with_span(
-                                RevsetParseErrorKind::FsPathParseError(e),
-                                span,
-                            )
-                        })?;
-                    Ok(path)
-                })
-                .try_collect()?;
-            if paths.is_empty() {
-                Err(RevsetParseError::with_span(
-                    RevsetParseErrorKind::InvalidFunctionArguments {
-                        name: name.to_owned(),
-                        message: "Expected at least 1 argument".to_string(),
-                    },
-                    arguments_span,
-                ))
-            } else {
-                Ok(RevsetExpression::filter(RevsetFilterPredicate::File(Some(
-                    paths,
-                ))))
-            }
-        } else {
-            Err(RevsetParseError::new(
-                RevsetParseErrorKind::FsPathWithoutWorkspace,
-            ))
-        }
+        let (paths, case_insensitive_paths) =
+            parse_function_arguments_to_path_patterns(name, arguments_pair, state)?;
+        let expression = match (paths.is_empty(), case_insensitive_paths.is_empty()) {
+            (false, true) => RevsetExpression::filter(RevsetFilterPredicate::File(Some(paths))),
+            (true, _) => RevsetExpression::filter(RevsetFilterPredicate::FileCaseInsensitive(
+                case_insensitive_paths,
+            )),
+            (false, false) => RevsetExpression::filter(RevsetFilterPredicate::File(Some(paths)))
+                .union(&RevsetExpression::filter(
+                    RevsetFilterPredicate::FileCaseInsensitive(case_insensitive_paths),
+                )),
+        };
+        Ok(expression)
+    });
+    map.insert("file_all", |name, arguments_pair, state| {
+        let paths = parse_function_arguments_to_paths(name, arguments_pair, state)?;
+        Ok(RevsetExpression::filter(RevsetFilterPredicate::FileAll(
+            paths,
+        )))
+    });
+    map.insert("file_added", |name, arguments_pair, state| {
+        let paths = parse_function_arguments_to_paths(name, arguments_pair, state)?;
+        Ok(RevsetExpression::filter(RevsetFilterPredicate::FileAdded(
+            paths,
+        )))
+    });
+    map.insert("file_count", |name, arguments_pair, _state| {
+        let arg = expect_one_argument(name, arguments_pair)?;
+        let range = parse_function_argument_as_count_range("file count range", name, arg)?;
+        Ok(RevsetExpression::filter(RevsetFilterPredicate::FileCount(
+            range,
+        )))
+    });
+    map.insert("file_within", |name, arguments_pair, state| {
+        let paths = parse_function_arguments_to_paths(name, arguments_pair, state)?;
+        Ok(RevsetExpression::filter(
+            RevsetFilterPredicate::FileWithinCandidates(Some(paths)),
+        ))
     });
     map.insert("conflict", |name, arguments_pair, _state| {
         expect_no_arguments(name, arguments_pair)?;
         Ok(RevsetExpression::filter(RevsetFilterPredicate::HasConflict))
     });
+    map.insert("change_id", |name, arguments_pair, state| {
+        let arg = expect_one_argument(name, arguments_pair)?;
+        let span = arg.as_span();
+        let prefix = parse_function_argument_to_string(name, arg, state)?;
+        let prefix = to_forward_hex(&prefix)
+            .filter(|hex| !hex.is_empty())
+            .as_deref()
+            .and_then(HexPrefix::new)
+            .ok_or_else(|| {
+                RevsetParseError::with_span(
+                    RevsetParseErrorKind::InvalidFunctionArguments {
+                        name: name.to_owned(),
+                        message: "Expected function argument of type change id prefix".to_owned(),
+                    },
+                    span,
+                )
+            })?;
+        Ok(RevsetExpression::filter(
+            RevsetFilterPredicate::ChangeIdPrefix(prefix),
+        ))
+    });
+    map.insert("commit_id", |name, arguments_pair, state| {
+        let arg = expect_one_argument(name, arguments_pair)?;
+        let span = arg.as_span();
+        let prefix = parse_function_argument_to_string(name, arg, state)?;
+        let prefix = Some(prefix.as_str())
+            .filter(|hex| !hex.is_empty())
+            .and_then(HexPrefix::new)
+            .ok_or_else(|| {
+                RevsetParseError::with_span(
+                    RevsetParseErrorKind::InvalidFunctionArguments {
+                        name: name.to_owned(),
+                        message: "Expected function argument of type commit id prefix".to_owned(),
+                    },
+                    span,
+                )
+            })?;
+        Ok(RevsetExpression::filter(
+            RevsetFilterPredicate::CommitIdPrefix(prefix),
+        ))
+    });
     map.insert("present", |name, arguments_pair, state| {
         let arg = expect_one_argument(name, arguments_pair)?;
-        let expression = parse_expression_rule(arg.into_inner(), state)?;
+        let expression = parse_function_argument_to_expression(arg, state)?;
         Ok(Rc::new(RevsetExpression::Present(expression)))
     });
+    map.insert("coalesce", |name, arguments_pair, state| {
+        let arguments_span = arguments_pair.as_span();
+        let expressions: Vec<_> = arguments_pair
+            .into_inner()
+            .map(|arg| parse_function_argument_to_expression(arg, state))
+            .try_collect()?;
+        if expressions.is_empty() {
+            Err(RevsetParseError::with_span(
+                RevsetParseErrorKind::InvalidFunctionArguments {
+                    name: name.to_owned(),
+                    message: "Expected at least 1 argument".to_string(),
+                },
+                arguments_span,
+            ))
+        } else {
+            Ok(RevsetExpression::coalesce(&expressions))
+        }
+    });
     map
 });
 
@@ -1123,7 +1511,7 @@ fn expect_named_arguments_vec<'i>(
     for pair in arguments_pair.into_inner() {
         let span = pair.as_span();
         match pair.as_rule() {
-            Rule::expression => {
+            Rule::expression | Rule::string_pattern => {
                 let pos = pos_iter
                     .as_mut()
                     .ok_or_else(|| {
@@ -1182,6 +1570,196 @@ fn parse_function_argument_to_string(
     parse_function_argument_as_literal("string", name, pair, state)
 }
 
+fn parse_function_arguments_to_strings(
+    name: &str,
+    arguments_pair: Pair<Rule>,
+    state: ParseState,
+) -> Result<Vec<String>, RevsetParseError> {
+    let arguments_span = arguments_pair.as_span();
+    let needles: Vec<_> = arguments_pair
+        .into_inner()
+        .map(|arg| parse_function_argument_to_string(name, arg, state))
+        .try_collect()?;
+    if needles.is_empty() {
+        Err(RevsetParseError::with_span(
+            RevsetParseErrorKind::InvalidFunctionArguments {
+                name: name.to_owned(),
+                message: "Expected at least 1 argument".to_string(),
+            },
+            arguments_span,
+        ))
+    } else {
+        Ok(needles)
+    }
+}
+
+fn parse_function_arguments_to_paths(
+    name: &str,
+    arguments_pair: Pair<Rule>,
+    state: ParseState,
+) -> Result<Vec<RepoPath>, RevsetParseError> {
+    let ctx = state
+        .workspace_ctx
+        .ok_or_else(|| RevsetParseError::new(RevsetParseErrorKind::FsPathWithoutWorkspace))?;
+    let arguments_span = arguments_pair.as_span();
+    let paths: Vec<_> = arguments_pair
+        .into_inner()
+        .map(|arg| -> Result<_, RevsetParseError> {
+            let span = arg.as_span();
+            let needle = parse_function_argument_to_string(name, arg, state)?;
+            let path =
+                RepoPath::parse_fs_path(ctx.cwd, ctx.workspace_root, &needle).map_err(|e| {
+                    RevsetParseError::with_span(RevsetParseErrorKind::FsPathParseError(e), span)
+                })?;
+            Ok(path)
+        })
+        .try_collect()?;
+    if paths.is_empty() {
+        Err(RevsetParseError::with_span(
+            RevsetParseErrorKind::InvalidFunctionArguments {
+                name: name.to_owned(),
+                message: "Expected at least 1 argument".to_string(),
+            },
+            arguments_span,
+        ))
+    } else {
+        Ok(paths)
+    }
+}
+
+/// Parses paths like `parse_function_arguments_to_paths()`, but each of them
+/// may be a `ci:"path"` pattern to be matched ignoring case. Returns the
+/// case-sensitive and case-insensitive paths, at least one of which isn't
+/// empty.
+fn parse_function_arguments_to_path_patterns(
+    name: &str,
+    arguments_pair: Pair<Rule>,
+    state: ParseState,
+) -> Result<(Vec<RepoPath>, Vec<RepoPath>), RevsetParseError> {
+    let ctx = state
+        .workspace_ctx
+        .ok_or_else(|| RevsetParseError::new(RevsetParseErrorKind::FsPathWithoutWorkspace))?;
+    let arguments_span = arguments_pair.as_span();
+    let mut paths = vec![];
+    let mut case_insensitive_paths = vec![];
+    for arg in arguments_pair.into_inner() {
+        let span = arg.as_span();
+        let (needle, case_insensitive) =
+            match parse_function_argument_to_string_pattern(name, arg, state)? {
+                StringPattern::Substring(needle) => (needle, false),
+                StringPattern::SubstringI(needle) => (needle, true),
+                StringPattern::Exact(_) | StringPattern::ExactI(_) => {
+                    return Err(RevsetParseError::with_span(
+                        RevsetParseErrorKind::InvalidFunctionArguments {
+                            name: name.to_owned(),
+                            message: "Expected function argument of type path".to_string(),
+                        },
+                        span,
+                    ));
+                }
+            };
+        let path = RepoPath::parse_fs_path(ctx.cwd, ctx.workspace_root, &needle).map_err(|e| {
+            RevsetParseError::with_span(RevsetParseErrorKind::FsPathParseError(e), span)
+        })?;
+        if case_insensitive {
+            case_insensitive_paths.push(path);
+        } else {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() && case_insensitive_paths.is_empty() {
+        Err(RevsetParseError::with_span(
+            RevsetParseErrorKind::InvalidFunctionArguments {
+                name: name.to_owned(),
+                message: "Expected at least 1 argument".to_string(),
+            },
+            arguments_span,
+        ))
+    } else {
+        Ok((paths, case_insensitive_paths))
+    }
+}
+
+fn parse_function_argument_to_expression(
+    pair: Pair<Rule>,
+    state: ParseState,
+) -> Result<Rc<RevsetExpression>, RevsetParseError> {
+    if pair.as_rule() != Rule::string_pattern {
+        return parse_expression_rule(pair.into_inner(), state);
+    }
+    // Elsewhere, `kind:"needle"` is the range it was before string patterns
+    // were introduced.
+    let span = pair.as_span();
+    let mut inner = pair.into_inner();
+    match (inner.next(), inner.next(), inner.next(), inner.next()) {
+        (Some(kind), Some(_op), Some(needle), None) => {
+            let kind = parse_symbol_rule(kind, state)?;
+            let needle = parse_symbol_rule(needle, state)?;
+            Ok(kind.dag_range_to(&needle))
+        }
+        _ => Err(RevsetParseError::with_span(
+            RevsetParseErrorKind::UnexpectedStringPattern,
+            span,
+        )),
+    }
+}
+
+/// Parses `kind:"needle"` pattern, or a plain string to be matched as
+/// substring. The `exact` kind can be combined with the case-insensitive `i`
+/// (or `ci`) kind in either order, e.g. `i:exact:"needle"`.
+fn parse_function_argument_to_string_pattern(
+    name: &str,
+    pair: Pair<Rule>,
+    state: ParseState,
+) -> Result<StringPattern, RevsetParseError> {
+    if pair.as_rule() != Rule::string_pattern {
+        return parse_function_argument_to_string(name, pair, state).map(StringPattern::Substring);
+    }
+    let make_error = |message: String, span| {
+        RevsetParseError::with_span(
+            RevsetParseErrorKind::InvalidFunctionArguments {
+                name: name.to_owned(),
+                message,
+            },
+            span,
+        )
+    };
+    let mut exact = false;
+    let mut case_insensitive = false;
+    let mut needle = String::new();
+    for inner in pair.into_inner() {
+        match inner.as_rule() {
+            Rule::identifier => match inner.as_str() {
+                "exact" if !exact => exact = true,
+                "i" | "ci" if !case_insensitive => case_insensitive = true,
+                kind => {
+                    return Err(make_error(
+                        format!(r#"Invalid string pattern kind "{kind}""#),
+                        inner.as_span(),
+                    ));
+                }
+            },
+            Rule::pattern_kind_op => {}
+            Rule::literal_string => {
+                needle = inner
+                    .as_str()
+                    .strip_prefix('"')
+                    .unwrap()
+                    .strip_suffix('"')
+                    .unwrap()
+                    .to_owned();
+            }
+            r => panic!("unexpected string pattern rule {r:?}"),
+        }
+    }
+    Ok(match (exact, case_insensitive) {
+        (false, false) => StringPattern::Substring(needle),
+        (false, true) => StringPattern::SubstringI(needle),
+        (true, false) => StringPattern::Exact(needle),
+        (true, true) => StringPattern::ExactI(needle),
+    })
+}
+
 fn parse_function_argument_as_literal<T: FromStr>(
     type_name: &str,
     name: &str,
@@ -1198,7 +1776,7 @@ fn parse_function_argument_as_literal<T: FromStr>(
             span,
         )
     };
-    let expression = parse_expression_rule(pair.into_inner(), state)?;
+    let expression = parse_function_argument_to_expression(pair, state)?;
     match expression.as_ref() {
         RevsetExpression::CommitRef(RevsetCommitRef::Symbol(symbol)) => {
             symbol.parse().map_err(|_| make_error())
@@ -1207,6 +1785,41 @@ fn parse_function_argument_as_literal<T: FromStr>(
     }
 }
 
+/// Parses `n`, `n..`, `..m`, or `n..m` into a range. The argument is taken
+/// verbatim since `n..` would otherwise be parsed as a revset range.
+fn parse_function_argument_as_count_range(
+    type_name: &str,
+    name: &str,
+    pair: Pair<Rule>,
+) -> Result<Range<u32>, RevsetParseError> {
+    let span = pair.as_span();
+    let make_error = || {
+        RevsetParseError::with_span(
+            RevsetParseErrorKind::InvalidFunctionArguments {
+                name: name.to_string(),
+                message: format!("Expected function argument of type {type_name}"),
+            },
+            span,
+        )
+    };
+    let parse_bound = |s: &str, default: u32| -> Result<u32, RevsetParseError> {
+        let s = s.trim();
+        if s.is_empty() {
+            Ok(default)
+        } else {
+            s.parse().map_err(|_| make_error())
+        }
+    };
+    let text = pair.as_str();
+    let range = if let Some((start, end)) = text.split_once("..") {
+        parse_bound(start, 0)?..parse_bound(end, u32::MAX)?
+    } else {
+        let count: u32 = text.trim().parse().map_err(|_| make_error())?;
+        count..count.saturating_add(1)
+    };
+    Ok(range)
+}
+
 pub fn parse(
     revset_str: &str,
     aliases_map: &RevsetAliasesMap,
@@ -1291,18 +1904,52 @@ fn try_transform_expression<E>(
             RevsetExpression::Roots(candidates) => {
                 transform_rec(candidates, pre, post)?.map(RevsetExpression::Roots)
             }
-            RevsetExpression::Latest { candidates, count } => transform_rec(candidates, pre, post)?
-                .map(|candidates| RevsetExpression::Latest {
-                    candidates,
-                    count: *count,
+            RevsetExpression::NthParent { children, index } => transform_rec(children, pre, post)?
+                .map(|children| RevsetExpression::NthParent {
+                    children,
+                    index: *index,
                 }),
+            RevsetExpression::Latest {
+                candidates,
+                count,
+                timestamp,
+            } => transform_rec(candidates, pre, post)?.map(|candidates| RevsetExpression::Latest {
+                candidates,
+                count: *count,
+                timestamp: *timestamp,
+            }),
+            RevsetExpression::LatestPerChange(candidates) => {
+                transform_rec(candidates, pre, post)?.map(RevsetExpression::LatestPerChange)
+            }
+            RevsetExpression::ChangeHeads(candidates) => {
+                transform_rec(candidates, pre, post)?.map(RevsetExpression::ChangeHeads)
+            }
             RevsetExpression::Filter(_) => None,
+            RevsetExpression::SameTreeAs(commits) => {
+                transform_rec(commits, pre, post)?.map(RevsetExpression::SameTreeAs)
+            }
             RevsetExpression::AsFilter(candidates) => {
                 transform_rec(candidates, pre, post)?.map(RevsetExpression::AsFilter)
             }
             RevsetExpression::Present(candidates) => {
                 transform_rec(candidates, pre, post)?.map(RevsetExpression::Present)
             }
+            RevsetExpression::Coalesce(expressions) => {
+                let new_expressions: Vec<_> = expressions
+                    .iter()
+                    .map(|expression| transform_rec(expression, pre, post))
+                    .try_collect()?;
+                new_expressions.iter().any(Option::is_some).then(|| {
+                    let expressions = new_expressions
+                        .into_iter()
+                        .zip(expressions)
+                        .map(|(new_expression, expression)| {
+                            new_expression.unwrap_or_else(|| expression.clone())
+                        })
+                        .collect();
+                    RevsetExpression::Coalesce(expressions)
+                })
+            }
             RevsetExpression::NotIn(complement) => {
                 transform_rec(complement, pre, post)?.map(RevsetExpression::NotIn)
             }
@@ -1325,6 +1972,13 @@ fn try_transform_expression<E>(
                     },
                 )
             }
+            RevsetExpression::SymmetricDifference(expression1, expression2) => {
+                transform_rec_pair((expression1, expression2), pre, post)?.map(
+                    |(expression1, expression2)| {
+                        RevsetExpression::SymmetricDifference(expression1, expression2)
+                    },
+                )
+            }
         }
         .map(Rc::new))
     }
@@ -1379,7 +2033,9 @@ fn internalize_filter(expression: &Rc<RevsetExpression>) -> TransformedExpressio
     fn is_filter(expression: &RevsetExpression) -> bool {
         matches!(
             expression,
-            RevsetExpression::Filter(_) | RevsetExpression::AsFilter(_)
+            RevsetExpression::Filter(_)
+                | RevsetExpression::SameTreeAs(_)
+                | RevsetExpression::AsFilter(_)
         )
     }
 
@@ -1495,7 +2151,12 @@ fn fold_difference(expression: &Rc<RevsetExpression>) -> TransformedExpression {
         RevsetExpression::Intersection(expression1, expression2) => {
             match (expression1.as_ref(), expression2.as_ref()) {
                 // For '~x & f', don't move filter node 'f' left
-                (_, RevsetExpression::Filter(_) | RevsetExpression::AsFilter(_)) => None,
+                (
+                    _,
+                    RevsetExpression::Filter(_)
+                    | RevsetExpression::SameTreeAs(_)
+                    | RevsetExpression::AsFilter(_),
+                ) => None,
                 (_, RevsetExpression::NotIn(complement)) => {
                     Some(to_difference(expression1, complement))
                 }
@@ -1893,18 +2554,21 @@ fn resolve_symbols(
         &expression,
         |expression| match expression.as_ref() {
             // 'present(x)' opens new symbol resolution scope to map error to 'none()'.
-            RevsetExpression::Present(candidates) => {
-                resolve_symbols(repo, candidates.clone(), symbol_resolver)
-                    .or_else(|err| match err {
-                        RevsetResolutionError::NoSuchRevision { .. } => {
-                            Ok(RevsetExpression::none())
-                        }
-                        RevsetResolutionError::EmptyString
-                        | RevsetResolutionError::AmbiguousCommitIdPrefix(_)
-                        | RevsetResolutionError::AmbiguousChangeIdPrefix(_)
-                        | RevsetResolutionError::StoreError(_) => Err(err),
+            RevsetExpression::Present(candidates) => match candidates.as_ref() {
+                // Nothing to resolve, so no error to suppress.
+                RevsetExpression::None | RevsetExpression::All => Ok(Some(candidates.clone())),
+                // Always rewrite subtree
+                _ => resolve_symbols_or_none(repo, candidates.clone(), symbol_resolver).map(Some),
+            },
+            // So does each argument of 'coalesce(x, ..)'.
+            RevsetExpression::Coalesce(expressions) => {
+                let expressions = expressions
+                    .iter()
+                    .map(|expression| {
+                        resolve_symbols_or_none(repo, expression.clone(), symbol_resolver)
                     })
-                    .map(Some) // Always rewrite subtree
+                    .try_collect()?;
+                Ok(Some(Rc::new(RevsetExpression::Coalesce(expressions))))
             }
             // Otherwise resolve symbols recursively.
             _ => Ok(None),
@@ -1920,6 +2584,22 @@ fn resolve_symbols(
     .unwrap_or(expression))
 }
 
+/// Resolves symbols like `resolve_symbols()`, but maps unknown symbols in the
+/// `expression` to `none()`.
+fn resolve_symbols_or_none(
+    repo: &dyn Repo,
+    expression: Rc<RevsetExpression>,
+    symbol_resolver: &dyn SymbolResolver,
+) -> Result<Rc<RevsetExpression>, RevsetResolutionError> {
+    resolve_symbols(repo, expression, symbol_resolver).or_else(|err| match err {
+        RevsetResolutionError::NoSuchRevision { .. } => Ok(RevsetExpression::none()),
+        RevsetResolutionError::EmptyString
+        | RevsetResolutionError::AmbiguousCommitIdPrefix(_)
+        | RevsetResolutionError::AmbiguousChangeIdPrefix(_)
+        | RevsetResolutionError::StoreError(_) => Err(err),
+    })
+}
+
 /// Inserts implicit `all()` and `visible_heads()` nodes to the `expression`.
 ///
 /// Symbols and commit refs in the `expression` should have been resolved.
@@ -1985,11 +2665,36 @@ impl VisibilityResolutionContext<'_> {
             RevsetExpression::Roots(candidates) => {
                 ResolvedExpression::Roots(self.resolve(candidates).into())
             }
-            RevsetExpression::Latest { candidates, count } => ResolvedExpression::Latest {
+            RevsetExpression::NthParent { children, index } => ResolvedExpression::NthParent {
+                children: self.resolve(children).into(),
+                index: *index,
+            },
+            RevsetExpression::Latest {
+                candidates,
+                count,
+                timestamp,
+            } => ResolvedExpression::Latest {
                 candidates: self.resolve(candidates).into(),
                 count: *count,
+                timestamp: *timestamp,
             },
-            RevsetExpression::Filter(_) | RevsetExpression::AsFilter(_) => {
+            RevsetExpression::LatestPerChange(candidates) => {
+                ResolvedExpression::LatestPerChange(self.resolve(candidates).into())
+            }
+            RevsetExpression::ChangeHeads(candidates) => {
+                ResolvedExpression::ChangeHeads(self.resolve(candidates).into())
+            }
+            RevsetExpression::Filter(RevsetFilterPredicate::ChangeIdPrefix(prefix)) => {
+                // Resolve the prefix through an index instead of testing every
+                // visible commit.
+                ResolvedExpression::ChangeIdPrefix {
+                    candidates: self.resolve_all().into(),
+                    prefix: prefix.clone(),
+                }
+            }
+            RevsetExpression::Filter(_)
+            | RevsetExpression::SameTreeAs(_)
+            | RevsetExpression::AsFilter(_) => {
                 // Top-level filter without intersection: e.g. "~author(_)" is represented as
                 // `AsFilter(NotIn(Filter(Author(_))))`.
                 ResolvedExpression::FilterWithin {
@@ -2000,6 +2705,12 @@ impl VisibilityResolutionContext<'_> {
             RevsetExpression::Present(_) => {
                 panic!("Expression '{expression:?}' should have been resolved by caller");
             }
+            RevsetExpression::Coalesce(expressions) => ResolvedExpression::Coalesce(
+                expressions
+                    .iter()
+                    .map(|expression| self.resolve(expression))
+                    .collect(),
+            ),
             RevsetExpression::NotIn(complement) => ResolvedExpression::Difference(
                 self.resolve_all().into(),
                 self.resolve(complement).into(),
@@ -2010,16 +2721,28 @@ impl VisibilityResolutionContext<'_> {
             ),
             RevsetExpression::Intersection(expression1, expression2) => {
                 match expression2.as_ref() {
-                    RevsetExpression::Filter(_) | RevsetExpression::AsFilter(_) => {
-                        ResolvedExpression::FilterWithin {
-                            candidates: self.resolve(expression1).into(),
-                            predicate: self.resolve_predicate(expression2),
+                    RevsetExpression::Filter(_)
+                    | RevsetExpression::SameTreeAs(_)
+                    | RevsetExpression::AsFilter(_) => ResolvedExpression::FilterWithin {
+                        candidates: self.resolve(expression1).into(),
+                        predicate: self.resolve_predicate(expression2),
+                    },
+                    _ => {
+                        if let Some(resolved) =
+                            self.resolve_children_within(expression1, expression2)
+                        {
+                            return resolved;
                         }
+                        if let Some(resolved) =
+                            self.resolve_children_within(expression2, expression1)
+                        {
+                            return resolved;
+                        }
+                        ResolvedExpression::Intersection(
+                            self.resolve(expression1).into(),
+                            self.resolve(expression2).into(),
+                        )
                     }
-                    _ => ResolvedExpression::Intersection(
-                        self.resolve(expression1).into(),
-                        self.resolve(expression2).into(),
-                    ),
                 }
             }
             RevsetExpression::Difference(expression1, expression2) => {
@@ -2028,6 +2751,40 @@ impl VisibilityResolutionContext<'_> {
                     self.resolve(expression2).into(),
                 )
             }
+            RevsetExpression::SymmetricDifference(expression1, expression2) => {
+                ResolvedExpression::SymmetricDifference(
+                    self.resolve(expression1).into(),
+                    self.resolve(expression2).into(),
+                )
+            }
+        }
+    }
+
+    /// Resolves `children(roots) & candidates` by testing the parents of each
+    /// candidate instead of walking the descendants of `roots`. This is only
+    /// done if `candidates` is a list of commits, which is usually small.
+    /// Otherwise, the lazy intersection is cheaper than collecting them.
+    ///
+    /// Like `children(roots)`, the candidates are bounded by the visible heads
+    /// so hidden commits are excluded.
+    fn resolve_children_within(
+        &self,
+        children: &RevsetExpression,
+        candidates: &RevsetExpression,
+    ) -> Option<ResolvedExpression> {
+        match (children, candidates) {
+            (
+                RevsetExpression::Descendants { roots, generation },
+                RevsetExpression::Commits(commit_ids),
+            ) if *generation == (1..2) => Some(ResolvedExpression::ChildrenWithin {
+                roots: self.resolve(roots).into(),
+                candidates: ResolvedExpression::Intersection(
+                    ResolvedExpression::Commits(commit_ids.clone()).into(),
+                    self.resolve_all().into(),
+                )
+                .into(),
+            }),
+            _ => None,
         }
     }
 
@@ -2065,12 +2822,19 @@ impl VisibilityResolutionContext<'_> {
             | RevsetExpression::DagRange { .. }
             | RevsetExpression::Heads(_)
             | RevsetExpression::Roots(_)
-            | RevsetExpression::Latest { .. } => {
+            | RevsetExpression::NthParent { .. }
+            | RevsetExpression::Latest { .. }
+            | RevsetExpression::LatestPerChange(_)
+            | RevsetExpression::ChangeHeads(_)
+            | RevsetExpression::Coalesce(_) => {
                 ResolvedPredicateExpression::Set(self.resolve(expression).into())
             }
             RevsetExpression::Filter(predicate) => {
                 ResolvedPredicateExpression::Filter(predicate.clone())
             }
+            RevsetExpression::SameTreeAs(commits) => {
+                ResolvedPredicateExpression::SameTreeAs(self.resolve(commits).into())
+            }
             RevsetExpression::AsFilter(candidates) => self.resolve_predicate(candidates),
             RevsetExpression::Present(_) => {
                 panic!("Expression '{expression:?}' should have been resolved by caller")
@@ -2085,7 +2849,9 @@ impl VisibilityResolutionContext<'_> {
             }
             // Intersection of filters should have been substituted by optimize().
             // If it weren't, just fall back to the set evaluation path.
-            RevsetExpression::Intersection(..) | RevsetExpression::Difference(..) => {
+            RevsetExpression::Intersection(..)
+            | RevsetExpression::Difference(..)
+            | RevsetExpression::SymmetricDifference(..) => {
                 ResolvedPredicateExpression::Set(self.resolve(expression).into())
             }
         }
@@ -2099,6 +2865,16 @@ pub trait Revset<'index>: fmt::Debug {
     /// Iterates commit/change id pairs in topological order.
     fn commit_change_ids(&self) -> Box<dyn Iterator<Item = (CommitId, ChangeId)> + '_>;
 
+    /// Calls `f` with each commit id in the order of `iter()`, without
+    /// collecting them. The iteration stops as soon as `f` returns
+    /// `ControlFlow::Break`, which is then returned.
+    fn for_each_commit_id(
+        &self,
+        f: &mut dyn FnMut(CommitId) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.iter().try_for_each(f)
+    }
+
     fn iter_graph(&self) -> Box<dyn Iterator<Item = (CommitId, Vec<RevsetGraphEdge>)> + '_>;
 
     fn change_id_index(&self) -> Box<dyn ChangeIdIndex + 'index>;
@@ -2127,6 +2903,11 @@ pub trait ChangeIdIndex: Send + Sync {
     ///   additional fact that it's the entire key). This case is extremely
     ///   unlikely for hashes with 12+ hexadecimal characters.
     fn shortest_unique_prefix_len(&self, change_id: &ChangeId) -> usize;
+
+    /// Returns the commits in the revset along with the
+    /// `shortest_unique_prefix_len()` of their change ids, in the revset's
+    /// order. This is cheaper than looking up each change id separately.
+    fn shortest_unique_prefix_lens(&self) -> Vec<(CommitId, usize)>;
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -2352,6 +3133,10 @@ mod tests {
                 generation: GENERATION_RANGE_FULL,
             })
         );
+        assert_eq!(
+            wc_symbol.range_excluding(&foo_symbol),
+            foo_symbol.range(&wc_symbol)
+        );
         assert_eq!(
             foo_symbol.negated(),
             Rc::new(RevsetExpression::NotIn(foo_symbol.clone()))
@@ -2372,7 +3157,17 @@ mod tests {
         );
         assert_eq!(
             foo_symbol.minus(&wc_symbol),
-            Rc::new(RevsetExpression::Difference(foo_symbol, wc_symbol.clone()))
+            Rc::new(RevsetExpression::Difference(
+                foo_symbol.clone(),
+                wc_symbol.clone()
+            ))
+        );
+        assert_eq!(
+            foo_symbol.symmetric_difference(&wc_symbol),
+            Rc::new(RevsetExpression::SymmetricDifference(
+                foo_symbol,
+                wc_symbol.clone()
+            ))
         );
     }
 
@@ -2429,6 +3224,10 @@ mod tests {
             Ok(wc_symbol.range(&RevsetExpression::visible_heads()))
         );
         assert_eq!(parse("foo..bar"), Ok(foo_symbol.range(&bar_symbol)));
+        assert_eq!(
+            parse("foo..bar"),
+            Ok(bar_symbol.range_excluding(&foo_symbol))
+        );
         // Parse the "negate" operator
         assert_eq!(parse("~ foo"), Ok(foo_symbol.negated()));
         assert_eq!(
@@ -2441,6 +3240,11 @@ mod tests {
         assert_eq!(parse("foo | bar"), Ok(foo_symbol.union(&bar_symbol)));
         // Parse the "difference" operator
         assert_eq!(parse("foo ~ bar"), Ok(foo_symbol.minus(&bar_symbol)));
+        // Parse the "symmetric difference" operator
+        assert_eq!(
+            parse("foo % bar"),
+            Ok(foo_symbol.symmetric_difference(&bar_symbol))
+        );
         // Parentheses are allowed before suffix operators
         assert_eq!(parse("(@)-"), Ok(wc_symbol.parents()));
         // Space is allowed around expressions
@@ -2561,6 +3365,8 @@ mod tests {
         assert_eq!(parse("x&y|z").unwrap(), parse("(x&y)|z").unwrap());
         assert_eq!(parse("x|y&z").unwrap(), parse("x|(y&z)").unwrap());
         assert_eq!(parse("x|y~z").unwrap(), parse("x|(y~z)").unwrap());
+        assert_eq!(parse("x%y|z").unwrap(), parse("(x%y)|z").unwrap());
+        assert_eq!(parse("x%y&z").unwrap(), parse("x%(y&z)").unwrap());
         // Parse repeated "ancestors"/"descendants"/"dag range"/"range" operators
         assert_eq!(parse(":foo:"), Err(RevsetParseErrorKind::SyntaxError));
         assert_eq!(parse("::foo"), Err(RevsetParseErrorKind::SyntaxError));
@@ -2598,6 +3404,113 @@ mod tests {
                 message: "Expected 1 arguments".to_string()
             })
         );
+        assert_eq!(parse("parent(@, 2)"), Ok(wc_symbol.nth_parent(1)));
+        assert_eq!(
+            parse("coalesce(@, foo)"),
+            Ok(RevsetExpression::coalesce(&[
+                wc_symbol,
+                RevsetExpression::symbol("foo".to_string())
+            ]))
+        );
+        assert_eq!(
+            parse("coalesce()"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "coalesce".to_string(),
+                message: "Expected at least 1 argument".to_string()
+            })
+        );
+        assert_eq!(
+            parse("parent(@, 0)"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "parent".to_string(),
+                message: "Expected function argument of type positive integer".to_string()
+            })
+        );
+        assert_eq!(
+            parse("change_id(zzk)"),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::ChangeIdPrefix(HexPrefix::new("00f").unwrap())
+            ))
+        );
+        assert_eq!(
+            parse("change_id(abc)"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "change_id".to_string(),
+                message: "Expected function argument of type change id prefix".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"change_id("")"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "change_id".to_string(),
+                message: "Expected function argument of type change id prefix".to_string()
+            })
+        );
+        assert_eq!(
+            parse("file_count(2..)"),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::FileCount(
+                2..u32::MAX
+            )))
+        );
+        assert_eq!(
+            parse("file_count(..3)"),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::FileCount(
+                0..3
+            )))
+        );
+        assert_eq!(
+            parse("file_count(1..3)"),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::FileCount(
+                1..3
+            )))
+        );
+        assert_eq!(
+            parse("file_count(2)"),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::FileCount(
+                2..3
+            )))
+        );
+        assert_eq!(
+            parse("file_count(a..)"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "file_count".to_string(),
+                message: "Expected function argument of type file count range".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"trailer("Signed-off-by")"#),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::Trailer {
+                key: "Signed-off-by".to_string(),
+                value: None,
+            }))
+        );
+        assert_eq!(
+            parse(r##"trailer(Fixes, "#123")"##),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::Trailer {
+                key: "Fixes".to_string(),
+                value: Some("#123".to_string()),
+            }))
+        );
+        assert_eq!(
+            parse("commit_id(abc)"),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::CommitIdPrefix(HexPrefix::new("abc").unwrap())
+            ))
+        );
+        assert_eq!(
+            parse("commit_id(xyz)"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "commit_id".to_string(),
+                message: "Expected function argument of type commit id prefix".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"commit_id("")"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "commit_id".to_string(),
+                message: "Expected function argument of type commit id prefix".to_string()
+            })
+        );
         assert_eq!(
             parse(r#"description("")"#),
             Ok(RevsetExpression::filter(
@@ -2623,12 +3536,167 @@ mod tests {
                 RevsetFilterPredicate::Description("foo".to_string())
             ))
         );
+        assert_eq!(
+            parse("description(empty)"),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::HasDescription(false)
+            ))
+        );
+        assert_eq!(
+            parse("description( nonempty )"),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::HasDescription(true)
+            ))
+        );
+        assert_eq!(
+            parse("description(\"empty\")"),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::Description("empty".to_string())
+            ))
+        );
         assert_eq!(
             parse("description(\"(foo)\")"),
             Ok(RevsetExpression::filter(
                 RevsetFilterPredicate::Description("(foo)".to_string())
             ))
         );
+        assert_eq!(
+            parse("latest(foo, 2, author)"),
+            Ok(RevsetExpression::symbol("foo".to_string()).latest_by(2, LatestTimestamp::Author))
+        );
+        assert_eq!(
+            parse("latest(foo, by=committer)"),
+            Ok(RevsetExpression::symbol("foo".to_string()).latest(1))
+        );
+        assert_eq!(
+            parse("latest(foo, 2, bar)"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "latest".to_string(),
+                message: r#"Expected function argument of type "committer" or "author""#
+                    .to_string()
+            })
+        );
+        assert_eq!(
+            parse("author(foo, bar)"),
+            Ok(RevsetExpression::filter(RevsetFilterPredicate::Author(
+                vec!["foo".to_string(), "bar".to_string()]
+            )))
+        );
+        assert_eq!(
+            parse("committer()"),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "committer".to_string(),
+                message: "Expected at least 1 argument".to_string()
+            })
+        );
+        assert_eq!(
+            parse("author_email(foo)"),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::AuthorEmail(StringPattern::Substring("foo".to_string()))
+            ))
+        );
+        assert_eq!(
+            parse(r#"author_email(exact:"foo")"#),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::AuthorEmail(StringPattern::Exact("foo".to_string()))
+            ))
+        );
+        assert_eq!(
+            parse(r#"author_email(ci:"foo")"#),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::AuthorEmail(StringPattern::SubstringI("foo".to_string()))
+            ))
+        );
+        assert_eq!(
+            parse(r#"author_email(i:"foo")"#),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::AuthorEmail(StringPattern::SubstringI("foo".to_string()))
+            ))
+        );
+        assert_eq!(
+            parse(r#"author_email(i:exact:"foo")"#),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::AuthorEmail(StringPattern::ExactI("foo".to_string()))
+            ))
+        );
+        assert_eq!(
+            parse(r#"author_email(exact:ci:"foo")"#),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::AuthorEmail(StringPattern::ExactI("foo".to_string()))
+            ))
+        );
+        assert_eq!(
+            parse(r#"author_email(exact:foo)"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "author_email".to_string(),
+                message: "Expected function argument of type string".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"author_email(ci:i:"foo")"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "author_email".to_string(),
+                message: r#"Invalid string pattern kind "i""#.to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"author_email(exact-ci:"foo")"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "author_email".to_string(),
+                message: r#"Invalid string pattern kind "exact-ci""#.to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"author_email(i:exact:i:"foo")"#),
+            Err(RevsetParseErrorKind::SyntaxError)
+        );
+        // String patterns are ranges outside of function arguments, and in
+        // arguments to functions not taking string patterns
+        assert_eq!(
+            parse(r#"parents(exact:"main")"#),
+            Ok(RevsetExpression::symbol("exact".to_string())
+                .dag_range_to(&RevsetExpression::symbol("main".to_string()))
+                .parents())
+        );
+        assert_eq!(
+            parse(r#"parents(foo:"main", )"#),
+            Ok(RevsetExpression::symbol("foo".to_string())
+                .dag_range_to(&RevsetExpression::symbol("main".to_string()))
+                .parents())
+        );
+        assert_eq!(
+            parse(r#"author(exact:"foo")"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "author".to_string(),
+                message: "Expected function argument of type string".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"heads(exact:"main")"#),
+            parse(r#"heads((exact:"main"))"#)
+        );
+        // Ranges can't be chained
+        assert_eq!(
+            parse(r#"parents(i:exact:"main")"#),
+            Err(RevsetParseErrorKind::UnexpectedStringPattern)
+        );
+        assert_eq!(
+            parse(r#"exact:"main""#),
+            Ok(RevsetExpression::symbol("exact".to_string())
+                .dag_range_to(&RevsetExpression::symbol("main".to_string())))
+        );
+        assert_eq!(
+            parse(r#"ci:"foo""#),
+            Ok(RevsetExpression::symbol("ci".to_string())
+                .dag_range_to(&RevsetExpression::symbol("foo".to_string())))
+        );
+        assert_eq!(
+            parse(r#"parents(exact:"main" | foo)"#),
+            Ok(RevsetExpression::symbol("exact".to_string())
+                .dag_range_to(&RevsetExpression::symbol("main".to_string()))
+                .union(&RevsetExpression::symbol("foo".to_string()))
+                .parents())
+        );
         assert_eq!(
             parse("empty()"),
             Ok(RevsetExpression::filter(RevsetFilterPredicate::File(None)).negated())
@@ -2651,6 +3719,34 @@ mod tests {
                 ]
             ))))
         );
+        assert_eq!(
+            parse(r#"file(ci:"Foo")"#),
+            Ok(RevsetExpression::filter(
+                RevsetFilterPredicate::FileCaseInsensitive(vec![RepoPath::from_internal_string(
+                    "Foo"
+                )])
+            ))
+        );
+        assert_eq!(
+            parse(r#"file(foo, ci:"Bar")"#),
+            Ok(
+                RevsetExpression::filter(RevsetFilterPredicate::File(Some(vec![
+                    RepoPath::from_internal_string("foo")
+                ])))
+                .union(&RevsetExpression::filter(
+                    RevsetFilterPredicate::FileCaseInsensitive(vec![
+                        RepoPath::from_internal_string("Bar")
+                    ])
+                ))
+            )
+        );
+        assert_eq!(
+            parse(r#"file(exact:"foo")"#),
+            Err(RevsetParseErrorKind::InvalidFunctionArguments {
+                name: "file".to_string(),
+                message: "Expected function argument of type path".to_string()
+            })
+        );
     }
 
     #[test]
@@ -2720,6 +3816,13 @@ mod tests {
             parse("a|A").unwrap()
         );
 
+        // A range in a function argument is expanded as before string patterns
+        // were introduced.
+        assert_eq!(
+            parse_with_aliases(r#"heads(AB:"c")"#, [("AB", "a|b")]).unwrap(),
+            parse("heads((a|b):c)").unwrap()
+        );
+
         // Alias can be substituted to string literal.
         assert_eq!(
             parse_with_aliases("author(A)", [("A", "a")]).unwrap(),
@@ -3235,7 +4338,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "foo",
+                    [
+                        "foo",
+                    ],
                 ),
             ),
         )
@@ -3254,7 +4359,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "bar",
+                    [
+                        "bar",
+                    ],
                 ),
             ),
         )
@@ -3273,7 +4380,9 @@ mod tests {
                 Union(
                     Filter(
                         Author(
-                            "bar",
+                            [
+                                "bar",
+                            ],
                         ),
                     ),
                     CommitRef(
@@ -3299,7 +4408,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "foo",
+                    [
+                        "foo",
+                    ],
                 ),
             ),
         )
@@ -3311,10 +4422,21 @@ mod tests {
         insta::assert_debug_snapshot!(optimize(parse("author(foo)").unwrap()), @r###"
         Filter(
             Author(
-                "foo",
+                [
+                    "foo",
+                ],
             ),
         )
         "###);
+        // all() is dropped, so the filter scans the candidates only once
+        assert_eq!(
+            optimize(parse("all() & author(foo)").unwrap()),
+            optimize(parse("author(foo)").unwrap())
+        );
+        assert_eq!(
+            optimize(parse("author(foo) & all()").unwrap()),
+            optimize(parse("author(foo)").unwrap())
+        );
 
         insta::assert_debug_snapshot!(optimize(parse("foo & description(bar)").unwrap()), @r###"
         Intersection(
@@ -3330,6 +4452,22 @@ mod tests {
             ),
         )
         "###);
+        insta::assert_debug_snapshot!(optimize(parse("same_tree_as(foo) & bar").unwrap()), @r###"
+        Intersection(
+            CommitRef(
+                Symbol(
+                    "bar",
+                ),
+            ),
+            SameTreeAs(
+                CommitRef(
+                    Symbol(
+                        "foo",
+                    ),
+                ),
+            ),
+        )
+        "###);
         insta::assert_debug_snapshot!(optimize(parse("author(foo) & bar").unwrap()), @r###"
         Intersection(
             CommitRef(
@@ -3339,7 +4477,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "foo",
+                    [
+                        "foo",
+                    ],
                 ),
             ),
         )
@@ -3349,12 +4489,16 @@ mod tests {
         Intersection(
             Filter(
                 Author(
-                    "foo",
+                    [
+                        "foo",
+                    ],
                 ),
             ),
             Filter(
                 Committer(
-                    "bar",
+                    [
+                        "bar",
+                    ],
                 ),
             ),
         )
@@ -3377,7 +4521,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "baz",
+                    [
+                        "baz",
+                    ],
                 ),
             ),
         )
@@ -3393,13 +4539,17 @@ mod tests {
                 ),
                 Filter(
                     Committer(
-                        "foo",
+                        [
+                            "foo",
+                        ],
                     ),
                 ),
             ),
             Filter(
                 Author(
-                    "baz",
+                    [
+                        "baz",
+                    ],
                 ),
             ),
         )
@@ -3415,7 +4565,9 @@ mod tests {
                 ),
                 Filter(
                     Committer(
-                        "foo",
+                        [
+                            "foo",
+                        ],
                     ),
                 ),
             ),
@@ -3436,7 +4588,9 @@ mod tests {
             Intersection(
                 Filter(
                     Committer(
-                        "foo",
+                        [
+                            "foo",
+                        ],
                     ),
                 ),
                 Filter(
@@ -3451,7 +4605,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "baz",
+                    [
+                        "baz",
+                    ],
                 ),
             ),
         )
@@ -3506,7 +4662,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "baz",
+                    [
+                        "baz",
+                    ],
                 ),
             ),
         )
@@ -3524,7 +4682,9 @@ mod tests {
                     Ancestors {
                         heads: Filter(
                             Author(
-                                "baz",
+                                [
+                                    "baz",
+                                ],
                             ),
                         ),
                         generation: 1..2,
@@ -3561,7 +4721,9 @@ mod tests {
                         ),
                         Filter(
                             Author(
-                                "baz",
+                                [
+                                    "baz",
+                                ],
                             ),
                         ),
                     ),
@@ -3603,19 +4765,25 @@ mod tests {
                     ),
                     Filter(
                         Author(
-                            "A",
+                            [
+                                "A",
+                            ],
                         ),
                     ),
                 ),
                 Filter(
                     Author(
-                        "B",
+                        [
+                            "B",
+                        ],
                     ),
                 ),
             ),
             Filter(
                 Author(
-                    "C",
+                    [
+                        "C",
+                    ],
                 ),
             ),
         )
@@ -3654,19 +4822,25 @@ mod tests {
                     ),
                     Filter(
                         Author(
-                            "A",
+                            [
+                                "A",
+                            ],
                         ),
                     ),
                 ),
                 Filter(
                     Author(
-                        "B",
+                        [
+                            "B",
+                        ],
                     ),
                 ),
             ),
             Filter(
                 Author(
-                    "C",
+                    [
+                        "C",
+                    ],
                 ),
             ),
         )
@@ -3691,7 +4865,9 @@ mod tests {
             ),
             Filter(
                 Author(
-                    "baz",
+                    [
+                        "baz",
+                    ],
                 ),
             ),
         )
@@ -3712,7 +4888,9 @@ mod tests {
                 Union(
                     Filter(
                         Author(
-                            "foo",
+                            [
+                                "foo",
+                            ],
                         ),
                     ),
                     CommitRef(
@@ -3743,7 +4921,9 @@ mod tests {
                         ),
                         Filter(
                             Committer(
-                                "bar",
+                                [
+                                    "bar",
+                                ],
                             ),
                         ),
                     ),
@@ -3779,7 +4959,9 @@ mod tests {
                                         ),
                                         Filter(
                                             Author(
-                                                "foo",
+                                                [
+                                                    "foo",
+                                                ],
                                             ),
                                         ),
                                     ),
@@ -3828,7 +5010,9 @@ mod tests {
                         Union(
                             Filter(
                                 Author(
-                                    "A",
+                                    [
+                                        "A",
+                                    ],
                                 ),
                             ),
                             CommitRef(
@@ -3843,7 +5027,9 @@ mod tests {
                     Union(
                         Filter(
                             Author(
-                                "B",
+                                [
+                                    "B",
+                                ],
                             ),
                         ),
                         CommitRef(
@@ -3858,7 +5044,9 @@ mod tests {
                 Union(
                     Filter(
                         Author(
-                            "C",
+                            [
+                                "C",
+                            ],
                         ),
                     ),
                     CommitRef(
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use chrono::{Datelike as _, Timelike as _};
 use jujutsu_lib::backend::{ChangeId, MillisSinceEpoch, ObjectId, Signature, Timestamp};
 use jujutsu_lib::matchers::EverythingMatcher;
 use jujutsu_lib::repo::Repo;
@@ -95,6 +96,43 @@ fn test_initial(use_git: bool) {
     );
 }
 
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_signature_local_time(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    // 2001-02-03 04:05:06 UTC
+    let timestamp = Timestamp {
+        timestamp: MillisSinceEpoch(981173106000),
+        tz_offset: 5 * 60 + 30,
+    };
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commit = testutils::create_random_commit(tx.mut_repo(), &settings)
+        .set_author(Signature {
+            name: "author name".to_string(),
+            email: "author email".to_string(),
+            timestamp,
+        })
+        .write()
+        .unwrap();
+    tx.commit();
+
+    // The offset survives the round trip through the backend
+    let commit = repo.store().get_commit(commit.id()).unwrap();
+    let datetime = commit.author().timestamp.to_datetime().unwrap();
+    assert_eq!(
+        (datetime.year(), datetime.month(), datetime.day()),
+        (2001, 2, 3)
+    );
+    assert_eq!(
+        (datetime.hour(), datetime.minute(), datetime.second()),
+        (9, 35, 6)
+    );
+    assert_eq!(datetime.offset().local_minus_utc(), 5 * 3600 + 30 * 60);
+}
+
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
 fn test_rewrite(use_git: bool) {
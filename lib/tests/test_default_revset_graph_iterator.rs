@@ -16,6 +16,7 @@ use itertools::Itertools;
 use jujutsu_lib::commit::Commit;
 use jujutsu_lib::default_index_store::ReadonlyIndexWrapper;
 use jujutsu_lib::default_revset_engine::{evaluate, RevsetImpl};
+use jujutsu_lib::default_revset_graph_iterator::RevsetGraphMergedRow;
 use jujutsu_lib::repo::{ReadonlyRepo, Repo as _};
 use jujutsu_lib::revset::{ResolvedExpression, RevsetGraphEdge};
 use test_case::test_case;
@@ -332,3 +333,90 @@ fn test_graph_iterator_edge_escapes_from_(skip_transitive_edges: bool) {
     assert_eq!(commits[3].1, vec![indirect(&commit_a)]);
     assert_eq!(commits[4].1, vec![missing(&root_commit)]);
 }
+
+#[test]
+fn test_graph_iterator_with_change_ids() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    // C
+    // b
+    // A
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut graph_builder = CommitGraphBuilder::new(&settings, tx.mut_repo());
+    let commit_a = graph_builder.initial_commit();
+    let commit_b = graph_builder.commit_with_parents(&[&commit_a]);
+    let commit_c = graph_builder.commit_with_parents(&[&commit_b]);
+    let repo = tx.commit();
+    let root_commit = repo.store().root_commit();
+
+    let revset = revset_for_commits(repo.as_ref(), &[&commit_a, &commit_c]);
+    let commits = revset.iter_graph_with_change_ids().collect_vec();
+    assert_eq!(
+        commits,
+        vec![
+            (
+                commit_c.id().clone(),
+                commit_c.change_id().clone(),
+                vec![indirect(&commit_a)]
+            ),
+            (
+                commit_a.id().clone(),
+                commit_a.change_id().clone(),
+                vec![missing(&root_commit)]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_graph_iterator_merged() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    // D
+    // C
+    // B
+    // A
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut graph_builder = CommitGraphBuilder::new(&settings, tx.mut_repo());
+    let commit_a = graph_builder.initial_commit();
+    let commit_b = graph_builder.commit_with_parents(&[&commit_a]);
+    let commit_c = graph_builder.commit_with_parents(&[&commit_b]);
+    let commit_d = graph_builder.commit_with_parents(&[&commit_c]);
+    let repo = tx.commit();
+    let root_commit = repo.store().root_commit();
+
+    let left = revset_for_commits(repo.as_ref(), &[&commit_a, &commit_b, &commit_d]);
+    let right = revset_for_commits(repo.as_ref(), &[&commit_b, &commit_c]);
+    let rows = left.iter_graph_merged(&right).collect_vec();
+    assert_eq!(
+        rows.iter()
+            .map(|(_, row)| (row.in_left(), row.in_right()))
+            .collect_vec(),
+        vec![(true, false), (false, true), (true, true), (true, false)]
+    );
+    assert_eq!(
+        rows,
+        vec![
+            (
+                commit_d.id().clone(),
+                RevsetGraphMergedRow::Left(vec![indirect(&commit_b)])
+            ),
+            (
+                commit_c.id().clone(),
+                RevsetGraphMergedRow::Right(vec![direct(&commit_b)])
+            ),
+            (
+                commit_b.id().clone(),
+                RevsetGraphMergedRow::Both(vec![direct(&commit_a)], vec![missing(&commit_a)])
+            ),
+            (
+                commit_a.id().clone(),
+                RevsetGraphMergedRow::Left(vec![missing(&root_commit)])
+            ),
+        ]
+    );
+}
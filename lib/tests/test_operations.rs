@@ -14,7 +14,9 @@
 
 use std::path::Path;
 
+use itertools::Itertools as _;
 use jujutsu_lib::backend::CommitId;
+use jujutsu_lib::operation::{self, Operation};
 use jujutsu_lib::repo::Repo;
 use test_case::test_case;
 use testutils::{create_random_commit, write_random_commit, TestRepo};
@@ -182,3 +184,49 @@ fn test_isolation(use_git: bool) {
     let repo = repo.reload_at_head(&settings).unwrap();
     assert_heads(repo.as_ref(), vec![rewrite1.id(), rewrite2.id()]);
 }
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_walk_ancestors_of_heads_missing_parent(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    // Two concurrent operations on top of the initial one
+    let op_id0 = repo.op_id().clone();
+    let mut tx1 = repo.start_transaction(&settings, "transaction 1");
+    write_random_commit(tx1.mut_repo(), &settings);
+    let op1 = tx1.commit().operation().clone();
+    let mut tx2 = repo.start_transaction(&settings, "transaction 2");
+    write_random_commit(tx2.mut_repo(), &settings);
+    let op2 = tx2.commit().operation().clone();
+
+    let walk_ids = |head_ops: &[Operation]| {
+        operation::walk_ancestors_of_heads(head_ops)
+            .map(|op| op.map(|op| op.id().clone()).map_err(|err| err.id))
+            .collect_vec()
+    };
+    assert_eq!(
+        walk_ids(&[op1.clone(), op2.clone()]).last(),
+        Some(&Ok(op_id0.clone()))
+    );
+
+    // The missing operation is yielded after its children, and its ancestors
+    // aren't walked
+    std::fs::remove_file(
+        repo.repo_path()
+            .join("op_store")
+            .join("operations")
+            .join(op_id0.hex()),
+    )
+    .unwrap();
+    let ids = walk_ids(&[op1.clone(), op2.clone()]);
+    assert_eq!(ids.len(), 3);
+    assert!(ids[..2].contains(&Ok(op1.id().clone())));
+    assert!(ids[..2].contains(&Ok(op2.id().clone())));
+    assert_eq!(ids[2], Err(op_id0.clone()));
+    assert_eq!(
+        walk_ids(&[op1.clone()]),
+        [Ok(op1.id().clone()), Err(op_id0)]
+    );
+}
@@ -17,26 +17,37 @@
 #![allow(clippy::items_after_test_module)]
 #![deny(unknown_lints)]
 
+use std::any::Any;
+use std::io::Read;
 use std::path::Path;
+use std::rc::Rc;
+use std::sync::Mutex;
 
 use assert_matches::assert_matches;
 use itertools::Itertools;
-use jujutsu_lib::backend::{ChangeId, CommitId, MillisSinceEpoch, ObjectId, Signature, Timestamp};
+use jujutsu_lib::backend::{
+    self, Backend, BackendResult, ChangeId, CommitId, Conflict, ConflictId, FileId,
+    MillisSinceEpoch, ObjectId, Signature, SymlinkId, Timestamp, TreeId,
+};
 use jujutsu_lib::commit::Commit;
-use jujutsu_lib::git;
+use jujutsu_lib::default_index_store::{CompositeIndex, MutableIndexImpl};
 use jujutsu_lib::git_backend::GitBackend;
 use jujutsu_lib::index::{HexPrefix, PrefixResolution};
+use jujutsu_lib::local_backend::LocalBackend;
 use jujutsu_lib::op_store::{BranchTarget, RefTarget, WorkspaceId};
-use jujutsu_lib::repo::Repo;
+use jujutsu_lib::repo::{MutableRepo, Repo};
 use jujutsu_lib::repo_path::RepoPath;
 use jujutsu_lib::revset::{
-    optimize, parse, DefaultSymbolResolver, ReverseRevsetGraphIterator, Revset, RevsetAliasesMap,
-    RevsetExpression, RevsetFilterPredicate, RevsetGraphEdge, RevsetResolutionError,
-    RevsetWorkspaceContext, SymbolResolver as _,
+    optimize, parse, DefaultSymbolResolver, PreparedRevsetExpression, ResolvedExpression,
+    ReverseRevsetGraphIterator, Revset, RevsetAliasesMap, RevsetEvaluationError, RevsetExpression,
+    RevsetFilterPredicate, RevsetGraphEdge, RevsetResolutionError, RevsetWorkspaceContext,
+    SymbolResolver as _, GENERATION_RANGE_FULL,
 };
 use jujutsu_lib::settings::GitSettings;
+use jujutsu_lib::store::Store;
 use jujutsu_lib::tree::merge_trees;
 use jujutsu_lib::workspace::Workspace;
+use jujutsu_lib::{default_revset_engine, git};
 use test_case::test_case;
 use testutils::{
     create_random_commit, write_random_commit, CommitGraphBuilder, TestRepo, TestWorkspace,
@@ -50,6 +61,14 @@ fn resolve_symbol(
     DefaultSymbolResolver::new(repo, workspace_id).resolve_symbol(symbol)
 }
 
+fn composite_index(repo: &MutableRepo) -> CompositeIndex<'_> {
+    repo.mutable_index()
+        .as_any()
+        .downcast_ref::<MutableIndexImpl>()
+        .unwrap()
+        .as_composite()
+}
+
 fn revset_for_commits<'index>(
     repo: &'index dyn Repo,
     commits: &[&Commit],
@@ -199,6 +218,20 @@ fn test_resolve_symbol_commit_id() {
         resolve_commit_ids(repo.as_ref(), "present(046)"),
         vec![commits[2].id().clone()]
     );
+    // Test present() of none() and all() resolves to the inner expression
+    for inner in [RevsetExpression::none(), RevsetExpression::all()] {
+        assert_eq!(
+            Rc::new(RevsetExpression::Present(inner.clone()))
+                .resolve(repo.as_ref())
+                .unwrap(),
+            inner.resolve(repo.as_ref()).unwrap()
+        );
+    }
+    assert_eq!(resolve_commit_ids(repo.as_ref(), "present(none())"), []);
+    assert_eq!(
+        resolve_commit_ids(repo.as_ref(), "present(all())"),
+        resolve_commit_ids(repo.as_ref(), "all()")
+    );
 }
 
 #[test_case(false ; "mutable")]
@@ -649,6 +682,60 @@ fn test_evaluate_expression_heads(use_git: bool) {
     );
 }
 
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_merge_base(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit4 = graph_builder.commit_with_parents(&[&commit2, &commit3]);
+    let commit5 = graph_builder.commit_with_parents(&[&commit3, &commit2]);
+
+    // A simple fork has a single merge base
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("merge_base({}, {})", commit2.id().hex(), commit3.id().hex())
+        ),
+        vec![commit1.id().clone()]
+    );
+
+    // An ancestor is the merge base of itself and its descendant
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("merge_base({}, {})", commit1.id().hex(), commit4.id().hex())
+        ),
+        vec![commit1.id().clone()]
+    );
+
+    // Criss-cross merges have several merge bases
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("merge_base({}, {})", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![commit3.id().clone(), commit2.id().clone()]
+    );
+
+    // Unrelated sets only have the root commit in common
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("merge_base({}, root)", commit4.id().hex())
+        ),
+        vec![mut_repo.store().root_commit_id().clone()]
+    );
+}
+
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
 fn test_evaluate_expression_roots(use_git: bool) {
@@ -786,6 +873,36 @@ fn test_evaluate_expression_parents(use_git: bool) {
         ),
         vec![commit1.id().clone(), root_commit.id().clone()]
     );
+
+    // Can select the nth parent of a merge commit
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("parent({}, 1)", commit4.id().hex())),
+        vec![commit2.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("parent({}, 2)", commit4.id().hex())),
+        vec![commit3.id().clone()]
+    );
+
+    // Commits without the nth parent are skipped
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("parent({}, 3)", commit4.id().hex())),
+        vec![]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("parent({} | {}, 2)", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![commit3.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("parent({} | {}, 1)", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![commit2.id().clone()]
+    );
 }
 
 #[test_case(false ; "local backend")]
@@ -875,6 +992,145 @@ fn test_evaluate_expression_children(use_git: bool) {
     assert_eq!(resolve_commit_ids(mut_repo, "none()+"), vec![]);
 }
 
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_children_within(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let commit4 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit3.id().clone()])
+        .write()
+        .unwrap();
+
+    // The parents of listed candidates are tested instead of walking the
+    // descendants from the visible heads
+    let expression = RevsetExpression::commit(commit1.id().clone())
+        .children()
+        .intersection(&RevsetExpression::commit(commit3.id().clone()));
+    assert_eq!(
+        expression.clone().resolve(mut_repo).unwrap(),
+        ResolvedExpression::ChildrenWithin {
+            roots: Box::new(ResolvedExpression::Commits(vec![commit1.id().clone()])),
+            candidates: Box::new(ResolvedExpression::Intersection(
+                Box::new(ResolvedExpression::Commits(vec![commit3.id().clone()])),
+                Box::new(ResolvedExpression::Ancestors {
+                    heads: Box::new(ResolvedExpression::Commits(
+                        mut_repo.view().heads().iter().cloned().collect()
+                    )),
+                    generation: GENERATION_RANGE_FULL,
+                }),
+            )),
+        }
+    );
+    // Other candidates are intersected lazily
+    assert_matches!(
+        RevsetExpression::commit(commit1.id().clone())
+            .children()
+            .intersection(&RevsetExpression::commit(commit4.id().clone()).ancestors())
+            .resolve(mut_repo)
+            .unwrap(),
+        ResolvedExpression::Intersection(..)
+    );
+
+    // Either side of the intersection can be the children
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{}+ & {}", commit1.id().hex(), commit3.id().hex())
+        ),
+        vec![commit3.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "({} | {}) & {}+",
+                commit2.id().hex(),
+                commit4.id().hex(),
+                commit1.id().hex()
+            )
+        ),
+        vec![commit2.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{}+ & {}", commit1.id().hex(), commit4.id().hex())
+        ),
+        vec![]
+    );
+
+    // A hidden child isn't included, as it wouldn't be in children()
+    let commit5 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    mut_repo.remove_head(commit5.id());
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("{}+", commit1.id().hex())),
+        vec![commit3.id().clone(), commit2.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{}+ & {}", commit1.id().hex(), commit5.id().hex())
+        ),
+        vec![]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "children_in({}, {})",
+                commit1.id().hex(),
+                commit5.id().hex()
+            )
+        ),
+        vec![]
+    );
+
+    // children_in() only finds the children in the candidate pool
+    assert_eq!(
+        RevsetExpression::commit(commit1.id().clone())
+            .children_within(&RevsetExpression::commit(commit3.id().clone()))
+            .resolve(mut_repo)
+            .unwrap(),
+        expression.resolve(mut_repo).unwrap()
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "children_in({}, {}:)",
+                commit1.id().hex(),
+                commit3.id().hex()
+            )
+        ),
+        vec![commit3.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("children_in({}, all())", commit1.id().hex())
+        ),
+        vec![commit3.id().clone(), commit2.id().clone()]
+    );
+}
+
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
 fn test_evaluate_expression_ancestors(use_git: bool) {
@@ -1005,6 +1261,48 @@ fn test_evaluate_expression_range(use_git: bool) {
     );
 }
 
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_range_inverted_generation(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let mut_repo = &*mut_repo;
+
+    let evaluate = |generation| {
+        let expression = Rc::new(RevsetExpression::Range {
+            roots: RevsetExpression::none(),
+            heads: RevsetExpression::commit(commit2.id().clone()),
+            generation,
+        });
+        let symbol_resolver = DefaultSymbolResolver::new(mut_repo, None);
+        expression
+            .resolve_user_expression(mut_repo, &symbol_resolver)
+            .unwrap()
+            .evaluate(mut_repo)
+            .map(|revset| revset.iter().collect_vec())
+    };
+
+    assert_eq!(evaluate(1..2).unwrap(), vec![commit1.id().clone()]);
+    // Empty range is valid
+    assert_eq!(evaluate(1..1).unwrap(), vec![]);
+    #[allow(clippy::reversed_empty_ranges)]
+    let inverted = 2..1;
+    assert_matches!(
+        evaluate(inverted),
+        Err(RevsetEvaluationError::Other(message))
+            if message == "Lower bound of generation (2) is greater than upper bound (1)"
+    );
+}
+
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
 fn test_evaluate_expression_dag_range(use_git: bool) {
@@ -1288,71 +1586,308 @@ fn test_evaluate_expression_none(use_git: bool) {
     assert_eq!(resolve_commit_ids(repo.as_ref(), "none()"), vec![]);
 }
 
-#[test_case(false ; "local backend")]
-#[test_case(true ; "git backend")]
-fn test_evaluate_expression_all(use_git: bool) {
+#[test]
+fn test_evaluate_expression_none_short_circuit() {
     let settings = testutils::user_settings();
-    let test_repo = TestRepo::init(use_git);
+    let test_repo = TestRepo::init(false);
     let repo = &test_repo.repo;
 
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
-    let root_commit_id = repo.store().root_commit_id().clone();
-    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
-    let commit1 = graph_builder.initial_commit();
-    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
-    let commit3 = graph_builder.commit_with_parents(&[&commit1]);
-    let commit4 = graph_builder.commit_with_parents(&[&commit2, &commit3]);
-
-    assert_eq!(
-        resolve_commit_ids(mut_repo, "all()"),
-        vec![
-            commit4.id().clone(),
-            commit3.id().clone(),
-            commit2.id().clone(),
-            commit1.id().clone(),
-            root_commit_id,
-        ]
-    );
-}
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_description("foo")
+        .write()
+        .unwrap();
+    let repo = tx.commit();
 
-#[test_case(false ; "local backend")]
-#[test_case(true ; "git backend")]
-fn test_evaluate_expression_visible_heads(use_git: bool) {
-    let settings = testutils::user_settings();
-    let test_repo = TestRepo::init(use_git);
-    let repo = &test_repo.repo;
+    // Evaluates against a fresh store so that no read is hidden by its cache
+    let evaluate = |revset_str: &str| {
+        let expression = optimize(parse(revset_str, &RevsetAliasesMap::new(), None).unwrap());
+        let symbol_resolver = DefaultSymbolResolver::new(repo.as_ref(), None);
+        let expression = expression
+            .resolve_user_expression(repo.as_ref(), &symbol_resolver)
+            .unwrap();
+        let backend = CommitReadRecordingBackend {
+            inner: LocalBackend::load(&repo.repo_path().join("store")),
+            read_commit_ids: Mutex::new(vec![]),
+        };
+        let store = Store::new(Box::new(backend));
+        let revset = repo.index().evaluate_revset(&expression, &store).unwrap();
+        let commit_ids = revset.iter().collect_vec();
+        let backend: &CommitReadRecordingBackend = store.backend_impl().downcast_ref().unwrap();
+        let read_commit_ids = backend.read_commit_ids.lock().unwrap().clone();
+        (commit_ids, read_commit_ids)
+    };
 
-    let mut tx = repo.start_transaction(&settings, "test");
-    let mut_repo = tx.mut_repo();
-    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
-    let commit1 = graph_builder.initial_commit();
-    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
-    let commit3 = graph_builder.commit_with_parents(&[&commit1]);
+    // latest() reads the candidate commits as soon as it's evaluated
+    let (commit_ids, read_commit_ids) = evaluate("latest(description(foo))");
+    assert_eq!(commit_ids, vec![commit2.id().clone()]);
+    assert!(!read_commit_ids.is_empty());
+
+    // The other side isn't evaluated
+    for revset_str in [
+        "latest(description(foo)) & none()",
+        "none() & latest(description(foo))",
+        "none() ~ latest(description(foo))",
+        "description(foo) & none()",
+        "none() & description(foo)",
+        "none() ~ description(foo)",
+    ] {
+        assert_eq!(evaluate(revset_str), (vec![], vec![]), "{revset_str}");
+    }
 
-    assert_eq!(
-        resolve_commit_ids(mut_repo, "visible_heads()"),
-        vec![commit3.id().clone(), commit2.id().clone()]
-    );
+    // The non-empty side is evaluated as is
+    for revset_str in [
+        "latest(description(foo)) | none()",
+        "none() | latest(description(foo))",
+        "latest(description(foo)) ~ none()",
+    ] {
+        let (commit_ids, read_commit_ids) = evaluate(revset_str);
+        assert_eq!(commit_ids, vec![commit2.id().clone()], "{revset_str}");
+        assert!(!read_commit_ids.is_empty(), "{revset_str}");
+    }
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_git_refs(use_git: bool) {
+fn test_evaluate_expression_ancestors_of_single_commit(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
 
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
-
+    let root_commit_id = mut_repo.store().root_commit_id().clone();
     let commit1 = write_random_commit(mut_repo, &settings);
-    let commit2 = write_random_commit(mut_repo, &settings);
-    let commit3 = write_random_commit(mut_repo, &settings);
-    let commit4 = write_random_commit(mut_repo, &settings);
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let commit4 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit3.id().clone()])
+        .write()
+        .unwrap();
+    let mut_repo = &*mut_repo;
 
-    // Can get git refs when there are none
-    assert_eq!(resolve_commit_ids(mut_repo, "git_refs()"), vec![]);
+    // Either side can be the ancestors
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{} & :{}", commit2.id().hex(), commit3.id().hex())
+        ),
+        vec![]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} & {}", commit4.id().hex(), commit3.id().hex())
+        ),
+        vec![commit3.id().clone()]
+    );
+
+    // The other side can have more commits than the ancestors, and commits
+    // below the head
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("(root:) & :{}", commit4.id().hex())),
+        vec![
+            commit4.id().clone(),
+            commit3.id().clone(),
+            commit1.id().clone(),
+            root_commit_id.clone(),
+        ]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("({} | root) & :{}", commit2.id().hex(), commit3.id().hex())
+        ),
+        vec![root_commit_id]
+    );
+
+    // Descendants of the head aren't included
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{}: & :{}", commit3.id().hex(), commit3.id().hex())
+        ),
+        vec![commit3.id().clone()]
+    );
+
+    // The ancestors are walked once, only as deep as the other side needs, no
+    // matter how many times the revset is iterated
+    let evaluate = |revset_str: &str| {
+        let expression = optimize(parse(revset_str, &RevsetAliasesMap::new(), None).unwrap());
+        let symbol_resolver = DefaultSymbolResolver::new(mut_repo, None);
+        let expression = expression
+            .resolve_user_expression(mut_repo, &symbol_resolver)
+            .unwrap();
+        expression.evaluate(mut_repo).unwrap()
+    };
+    let revset = evaluate(&format!("{} & :{}", commit3.id().hex(), commit4.id().hex()));
+    for _ in 0..2 {
+        assert_eq!(revset.iter().collect_vec(), vec![commit3.id().clone()]);
+    }
+    let debug_string = format!("{revset:?}");
+    assert!(
+        debug_string.contains("AncestorsPositionSetPredicate { len: 2 }"),
+        "{debug_string}"
+    );
+    assert!(!debug_string.contains("RevWalkRevset"), "{debug_string}");
+    let revset = evaluate(&format!(":{} & (root:)", commit4.id().hex()));
+    for _ in 0..2 {
+        assert_eq!(revset.iter().count(), 4);
+    }
+    let debug_string = format!("{revset:?}");
+    assert!(
+        debug_string.contains("AncestorsPositionSetPredicate { len: 4 }"),
+        "{debug_string}"
+    );
+
+    // Ancestors of multiple heads are still merged with the other side
+    let revset = evaluate(&format!(
+        "{} & :({} | {})",
+        commit1.id().hex(),
+        commit2.id().hex(),
+        commit4.id().hex()
+    ));
+    let debug_string = format!("{revset:?}");
+    assert!(
+        debug_string.contains("IntersectionRevset"),
+        "{debug_string}"
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_all(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let root_commit_id = repo.store().root_commit_id().clone();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit4 = graph_builder.commit_with_parents(&[&commit2, &commit3]);
+
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "all()"),
+        vec![
+            commit4.id().clone(),
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone(),
+            root_commit_id,
+        ]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_all_with_visible_heads(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let root_commit_id = repo.store().root_commit_id().clone();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit1]);
+    mut_repo.remove_head(commit3.id());
+
+    let all_heads = composite_index(mut_repo).all_heads();
+    assert_eq!(all_heads, vec![commit3.id().clone(), commit2.id().clone()]);
+    let resolve_with_visible_heads = |revset_str: &str, visible_heads: &[CommitId]| {
+        let expression = optimize(parse(revset_str, &RevsetAliasesMap::new(), None).unwrap());
+        let symbol_resolver = DefaultSymbolResolver::new(mut_repo, None);
+        let expression = expression
+            .resolve_with_visible_heads(mut_repo, &symbol_resolver, visible_heads)
+            .unwrap();
+        expression.evaluate(mut_repo).unwrap().iter().collect_vec()
+    };
+
+    // The hidden commit is excluded by default
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "all()"),
+        vec![
+            commit2.id().clone(),
+            commit1.id().clone(),
+            root_commit_id.clone(),
+        ]
+    );
+    assert_eq!(
+        resolve_with_visible_heads("all()", &[commit2.id().clone()]),
+        resolve_commit_ids(mut_repo, "all()")
+    );
+    // The hidden commit is included if its head is supplied
+    assert_eq!(
+        resolve_with_visible_heads("all()", &all_heads),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone(),
+            root_commit_id,
+        ]
+    );
+    assert_eq!(
+        resolve_with_visible_heads(&format!("{}:", commit1.id().hex()), &all_heads),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone(),
+        ]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_visible_heads(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit1]);
+
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "visible_heads()"),
+        vec![commit3.id().clone(), commit2.id().clone()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_git_refs(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = write_random_commit(mut_repo, &settings);
+    let commit3 = write_random_commit(mut_repo, &settings);
+    let commit4 = write_random_commit(mut_repo, &settings);
+
+    // Can get git refs when there are none
+    assert_eq!(resolve_commit_ids(mut_repo, "git_refs()"), vec![]);
     // Can get a mix of git refs
     mut_repo.set_git_ref(
         "refs/heads/branch1".to_string(),
@@ -1423,6 +1958,57 @@ fn test_evaluate_expression_git_head(use_git: bool) {
     );
 }
 
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_tags(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = write_random_commit(mut_repo, &settings);
+    let commit3 = write_random_commit(mut_repo, &settings);
+
+    // Can get tags when there are none
+    assert_eq!(resolve_commit_ids(mut_repo, "tags()"), vec![]);
+    // Only the tagged commit is included, not the untagged one
+    mut_repo.set_tag("tag1".to_string(), RefTarget::Normal(commit1.id().clone()));
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "tags()"),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("{} & ~tags()", commit2.id().hex())),
+        vec![commit2.id().clone()]
+    );
+    // Two tags pointing to the same commit does not result in a duplicate in the
+    // revset
+    mut_repo.set_tag("tag2".to_string(), RefTarget::Normal(commit1.id().clone()));
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "tags()"),
+        vec![commit1.id().clone()]
+    );
+    // Can get tags when there are conflicted refs
+    mut_repo.set_tag(
+        "tag2".to_string(),
+        RefTarget::Conflict {
+            removes: vec![commit1.id().clone()],
+            adds: vec![commit2.id().clone(), commit3.id().clone()],
+        },
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "tags()"),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
+}
+
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
 fn test_evaluate_expression_branches(use_git: bool) {
@@ -1502,70 +2088,309 @@ fn test_evaluate_expression_branches(use_git: bool) {
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_remote_branches(use_git: bool) {
+fn test_evaluate_expression_coalesce(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
 
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
-
     let commit1 = write_random_commit(mut_repo, &settings);
     let commit2 = write_random_commit(mut_repo, &settings);
-    let commit3 = write_random_commit(mut_repo, &settings);
-    let commit4 = write_random_commit(mut_repo, &settings);
-
-    // Can get branches when there are none
-    assert_eq!(resolve_commit_ids(mut_repo, "remote_branches()"), vec![]);
-    // Can get a few branches
-    mut_repo.set_remote_branch(
+    mut_repo.set_local_branch(
         "branch1".to_string(),
-        "origin".to_string(),
         RefTarget::Normal(commit1.id().clone()),
     );
-    mut_repo.set_remote_branch(
+    mut_repo.set_local_branch(
         "branch2".to_string(),
-        "private".to_string(),
         RefTarget::Normal(commit2.id().clone()),
     );
+
+    // The first non-empty set is selected
     assert_eq!(
-        resolve_commit_ids(mut_repo, "remote_branches()"),
-        vec![commit2.id().clone(), commit1.id().clone()]
+        resolve_commit_ids(mut_repo, "coalesce(branch1, branch2)"),
+        vec![commit1.id().clone()]
     );
-    // Can get branches with matching names
     assert_eq!(
-        resolve_commit_ids(mut_repo, "remote_branches(branch1)"),
-        vec![commit1.id().clone()]
+        resolve_commit_ids(mut_repo, "coalesce(none(), branch2, branch1)"),
+        vec![commit2.id().clone()]
     );
     assert_eq!(
-        resolve_commit_ids(mut_repo, "remote_branches(branch)"),
+        resolve_commit_ids(mut_repo, "coalesce(branch1 & branch2, branch2 | branch1)"),
         vec![commit2.id().clone(), commit1.id().clone()]
     );
-    // Can get branches from matching remotes
+    // Unknown symbols are treated as empty sets
     assert_eq!(
-        resolve_commit_ids(mut_repo, r#"remote_branches("", origin)"#),
-        vec![commit1.id().clone()]
+        resolve_commit_ids(mut_repo, "coalesce(main, master, branch2)"),
+        vec![commit2.id().clone()]
     );
+    // All empty
     assert_eq!(
-        resolve_commit_ids(mut_repo, r#"remote_branches("", ri)"#),
+        resolve_commit_ids(mut_repo, "coalesce(none(), main, branch1 & branch2)"),
+        vec![]
+    );
+}
+
+#[test]
+fn test_evaluate_expression_change_id() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let root_commit = repo.store().root_commit();
+    let mut commit_number = 0;
+    let mut commit_with_change_id = |change_id: &str| {
+        commit_number += 1;
+        tx.mut_repo()
+            .new_commit(
+                &settings,
+                vec![root_commit.id().clone()],
+                root_commit.tree_id().clone(),
+            )
+            .set_change_id(ChangeId::from_hex(change_id))
+            .set_description(format!("commit {commit_number}"))
+            .write()
+            .unwrap()
+    };
+    let commit1 = commit_with_change_id("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+    let commit2 = commit_with_change_id("aaaaabbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+    let commit3 = commit_with_change_id("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+    let commit4 = commit_with_change_id("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+    let mut_repo = tx.mut_repo();
+
+    // Change ids are written in reverse hex, so "p" is "a" and "o" is "b".
+    // An ambiguous prefix selects all matching changes
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "change_id(ppppp)"),
         vec![commit2.id().clone(), commit1.id().clone()]
     );
-    // Can get branches with matching names from matching remotes
     assert_eq!(
-        resolve_commit_ids(mut_repo, "remote_branches(branch1, ri)"),
+        resolve_commit_ids(mut_repo, "change_id(pppppp)"),
         vec![commit1.id().clone()]
     );
+    // Divergent commits of the same change are all selected
     assert_eq!(
-        resolve_commit_ids(mut_repo, r#"remote_branches(branch, private)"#),
-        vec![commit2.id().clone()]
+        resolve_commit_ids(mut_repo, "change_id(o)"),
+        vec![commit4.id().clone(), commit3.id().clone()]
     );
-    // Can silently resolve to an empty set if there's no matches
     assert_eq!(
-        resolve_commit_ids(mut_repo, "remote_branches(branch3)"),
-        vec![]
+        resolve_commit_ids(
+            mut_repo,
+            &format!("change_id(o) & root..{}", commit3.id().hex())
+        ),
+        vec![commit3.id().clone()]
     );
-    assert_eq!(
-        resolve_commit_ids(mut_repo, r#"remote_branches("", upstream)"#),
+    assert_eq!(resolve_commit_ids(mut_repo, "change_id(op)"), vec![]);
+
+    // The visible commits are indexed by change id once instead of testing
+    // the prefix against each of them
+    let expression = RevsetExpression::filter(RevsetFilterPredicate::ChangeIdPrefix(
+        HexPrefix::new("bb").unwrap(),
+    ));
+    assert_matches!(
+        expression.resolve(mut_repo).unwrap(),
+        ResolvedExpression::ChangeIdPrefix { .. }
+    );
+}
+
+#[test]
+fn test_evaluate_prepared_expression() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    let expression = PreparedRevsetExpression::new(
+        parse("heads(all())", &RevsetAliasesMap::new(), None).unwrap(),
+    );
+    let evaluate = |repo: &dyn Repo| -> Vec<CommitId> {
+        let symbol_resolver = DefaultSymbolResolver::new(repo, None);
+        expression
+            .evaluate(repo, &symbol_resolver)
+            .unwrap()
+            .iter()
+            .collect()
+    };
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commit1 = write_random_commit(tx.mut_repo(), &settings);
+    let repo1 = tx.commit();
+    assert_eq!(evaluate(repo1.as_ref()), vec![commit1.id().clone()]);
+
+    // The same expression picks up commits added to the newer snapshot
+    let mut tx = repo1.start_transaction(&settings, "test");
+    let commit2 = create_random_commit(tx.mut_repo(), &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let repo2 = tx.commit();
+    assert_eq!(evaluate(repo2.as_ref()), vec![commit2.id().clone()]);
+    assert_eq!(evaluate(repo1.as_ref()), vec![commit1.id().clone()]);
+}
+
+#[test]
+fn test_evaluate_expression_commit_id() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.initial_commit();
+    let commit3 = graph_builder.initial_commit();
+
+    for commit in [&commit1, &commit2, &commit3] {
+        let hex = commit.id().hex();
+        assert_eq!(
+            resolve_commit_ids(mut_repo, &format!("commit_id({hex})")),
+            vec![commit.id().clone()]
+        );
+    }
+    // An ambiguous prefix selects all matching commits, and the root commit's
+    // id is all zeros
+    let all_commit_ids = [&commit3, &commit2, &commit1]
+        .iter()
+        .map(|commit| commit.id().clone())
+        .chain([repo.store().root_commit_id().clone()])
+        .collect_vec();
+    let prefix = &commit1.id().hex()[..1];
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("commit_id({prefix})")),
+        all_commit_ids
+            .iter()
+            .filter(|id| id.hex().starts_with(prefix))
+            .cloned()
+            .collect_vec()
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "commit_id(000000000000)"),
+        vec![repo.store().root_commit_id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "commit_id({}) & ~{}",
+                commit1.id().hex(),
+                commit1.id().hex()
+            )
+        ),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_at_operation(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let commit1 = write_random_commit(mut_repo, &settings);
+    mut_repo.set_local_branch(
+        "branch1".to_string(),
+        RefTarget::Normal(commit1.id().clone()),
+    );
+    let repo1 = tx.commit();
+
+    let mut tx = repo1.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    mut_repo.set_local_branch(
+        "branch1".to_string(),
+        RefTarget::Normal(commit2.id().clone()),
+    );
+    let repo2 = tx.commit();
+    assert_eq!(
+        resolve_commit_ids(repo2.as_ref(), "branch1"),
+        vec![commit2.id().clone()]
+    );
+
+    // The branch and the index are loaded as of the old operation
+    let old_repo = repo2.loader().load_at(repo1.operation());
+    assert_eq!(
+        resolve_commit_ids(old_repo.as_ref(), "branch1"),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(old_repo.as_ref(), "branch1:"),
+        vec![commit1.id().clone()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_remote_branches(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = write_random_commit(mut_repo, &settings);
+    let commit3 = write_random_commit(mut_repo, &settings);
+    let commit4 = write_random_commit(mut_repo, &settings);
+
+    // Can get branches when there are none
+    assert_eq!(resolve_commit_ids(mut_repo, "remote_branches()"), vec![]);
+    // Can get a few branches
+    mut_repo.set_remote_branch(
+        "branch1".to_string(),
+        "origin".to_string(),
+        RefTarget::Normal(commit1.id().clone()),
+    );
+    mut_repo.set_remote_branch(
+        "branch2".to_string(),
+        "private".to_string(),
+        RefTarget::Normal(commit2.id().clone()),
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "remote_branches()"),
+        vec![commit2.id().clone(), commit1.id().clone()]
+    );
+    // Can get branches with matching names
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "remote_branches(branch1)"),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "remote_branches(branch)"),
+        vec![commit2.id().clone(), commit1.id().clone()]
+    );
+    // Can get branches from matching remotes
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r#"remote_branches("", origin)"#),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r#"remote_branches("", ri)"#),
+        vec![commit2.id().clone(), commit1.id().clone()]
+    );
+    // Can get branches with matching names from matching remotes
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "remote_branches(branch1, ri)"),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r#"remote_branches(branch, private)"#),
+        vec![commit2.id().clone()]
+    );
+    // Can silently resolve to an empty set if there's no matches
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "remote_branches(branch3)"),
+        vec![]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r#"remote_branches("", upstream)"#),
         vec![]
     );
     assert_eq!(
@@ -1703,35 +2528,60 @@ fn test_evaluate_expression_latest(use_git: bool) {
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_merges(use_git: bool) {
+fn test_evaluate_expression_latest_order(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
 
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
-    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
-    let commit1 = graph_builder.initial_commit();
-    let commit2 = graph_builder.initial_commit();
-    let commit3 = graph_builder.initial_commit();
-    let commit4 = graph_builder.commit_with_parents(&[&commit1, &commit2]);
-    let commit5 = graph_builder.commit_with_parents(&[&commit1, &commit2, &commit3]);
 
-    // Finds all merges by default
+    let mut write_commit_with_committer_timestamp = |sec: i64| {
+        let builder = create_random_commit(mut_repo, &settings);
+        let mut committer = builder.committer().clone();
+        committer.timestamp.timestamp = MillisSinceEpoch(sec * 1000);
+        builder.set_committer(committer).write().unwrap()
+    };
+    let commit1_t4 = write_commit_with_committer_timestamp(4);
+    let commit2_t1 = write_commit_with_committer_timestamp(1);
+    let commit3_t3 = write_commit_with_committer_timestamp(3);
+    let commit4_t2 = write_commit_with_committer_timestamp(2);
+    let mut_repo = &*mut_repo;
+
+    // The selected commits are emitted in index position order like any
+    // other revset, not by timestamp
     assert_eq!(
-        resolve_commit_ids(mut_repo, "merges()"),
-        vec![commit5.id().clone(), commit4.id().clone(),]
+        resolve_commit_ids(mut_repo, "latest(~root, 3)"),
+        vec![
+            commit4_t2.id().clone(),
+            commit3_t3.id().clone(),
+            commit1_t4.id().clone(),
+        ]
     );
-    // Searches only among candidates if specified
+
+    // iter_by_date() lists the same commits newest first
+    let expression = optimize(parse("latest(~root, 3)", &RevsetAliasesMap::new(), None).unwrap());
+    let symbol_resolver = DefaultSymbolResolver::new(mut_repo, None);
+    let expression = expression
+        .resolve_user_expression(mut_repo, &symbol_resolver)
+        .unwrap();
+    let revset =
+        default_revset_engine::evaluate(&expression, mut_repo.store(), composite_index(mut_repo))
+            .unwrap();
     assert_eq!(
-        resolve_commit_ids(mut_repo, &format!(":{} & merges()", commit5.id().hex())),
-        vec![commit5.id().clone()]
+        revset.iter_by_date(mut_repo.store()).unwrap(),
+        vec![
+            commit1_t4.id().clone(),
+            commit3_t3.id().clone(),
+            commit4_t2.id().clone(),
+        ]
     );
+    assert!(!revset.iter().any(|id| &id == commit2_t1.id()));
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_description(use_git: bool) {
+fn test_evaluate_expression_latest_by_author(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
@@ -1739,45 +2589,53 @@ fn test_evaluate_expression_description(use_git: bool) {
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
 
-    let commit1 = create_random_commit(mut_repo, &settings)
-        .set_description("commit 1")
-        .write()
-        .unwrap();
-    let commit2 = create_random_commit(mut_repo, &settings)
-        .set_parents(vec![commit1.id().clone()])
-        .set_description("commit 2")
-        .write()
-        .unwrap();
-    let commit3 = create_random_commit(mut_repo, &settings)
-        .set_parents(vec![commit2.id().clone()])
-        .set_description("commit 3")
-        .write()
-        .unwrap();
+    let mut write_commit_with_timestamps = |author_sec: i64, committer_sec: i64| {
+        let builder = create_random_commit(mut_repo, &settings);
+        let mut author = builder.author().clone();
+        author.timestamp.timestamp = MillisSinceEpoch(author_sec * 1000);
+        let mut committer = builder.committer().clone();
+        committer.timestamp.timestamp = MillisSinceEpoch(committer_sec * 1000);
+        builder
+            .set_author(author)
+            .set_committer(committer)
+            .write()
+            .unwrap()
+    };
+    let commit1_a1_c3 = write_commit_with_timestamps(1, 3);
+    let commit2_a3_c1 = write_commit_with_timestamps(3, 1);
+    let commit3_a2_c2 = write_commit_with_timestamps(2, 2);
 
-    // Can find multiple matches
+    // Committer timestamp is used by default
     assert_eq!(
-        resolve_commit_ids(mut_repo, "description(commit)"),
-        vec![
-            commit3.id().clone(),
-            commit2.id().clone(),
-            commit1.id().clone()
-        ]
+        resolve_commit_ids(mut_repo, "latest(~root)"),
+        vec![commit1_a1_c3.id().clone()],
     );
-    // Can find a unique match
     assert_eq!(
-        resolve_commit_ids(mut_repo, "description(\"commit 2\")"),
-        vec![commit2.id().clone()]
+        resolve_commit_ids(mut_repo, "latest(~root, 1, committer)"),
+        vec![commit1_a1_c3.id().clone()],
     );
-    // Searches only among candidates if specified
+
     assert_eq!(
-        resolve_commit_ids(mut_repo, "visible_heads() & description(\"commit 2\")"),
-        vec![]
+        resolve_commit_ids(mut_repo, "latest(~root, 1, author)"),
+        vec![commit2_a3_c1.id().clone()],
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "latest(~root, by=author)"),
+        vec![commit2_a3_c1.id().clone()],
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "latest(~root, 2, author)"),
+        vec![commit3_a2_c2.id().clone(), commit2_a3_c1.id().clone()],
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "latest(~root, 2, committer)"),
+        vec![commit3_a2_c2.id().clone(), commit1_a1_c3.id().clone()],
     );
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_author(use_git: bool) {
+fn test_evaluate_expression_latest_per_change(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
@@ -1785,73 +2643,260 @@ fn test_evaluate_expression_author(use_git: bool) {
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
 
-    let timestamp = Timestamp {
-        timestamp: MillisSinceEpoch(0),
-        tz_offset: 0,
+    let mut write_commit = |change_id: &str, sec: i64| {
+        let builder = create_random_commit(mut_repo, &settings)
+            .set_change_id(ChangeId::from_hex(change_id))
+            .set_description(format!("commit {change_id} at {sec}"));
+        let mut committer = builder.committer().clone();
+        committer.timestamp.timestamp = MillisSinceEpoch(sec * 1000);
+        builder.set_committer(committer).write().unwrap()
     };
-    let commit1 = create_random_commit(mut_repo, &settings)
-        .set_author(Signature {
-            name: "name1".to_string(),
-            email: "email1".to_string(),
-            timestamp: timestamp.clone(),
-        })
-        .write()
-        .unwrap();
-    let commit2 = create_random_commit(mut_repo, &settings)
-        .set_parents(vec![commit1.id().clone()])
-        .set_author(Signature {
-            name: "name2".to_string(),
-            email: "email2".to_string(),
-            timestamp: timestamp.clone(),
-        })
-        .write()
-        .unwrap();
-    let commit3 = create_random_commit(mut_repo, &settings)
-        .set_parents(vec![commit2.id().clone()])
-        .set_author(Signature {
-            name: "name3".to_string(),
-            email: "email3".to_string(),
-            timestamp,
-        })
-        .write()
-        .unwrap();
+    let change_a = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    let change_b = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+    let commit1_a_t2 = write_commit(change_a, 2);
+    let commit2_a_t1 = write_commit(change_a, 1);
+    let commit3_b_t1 = write_commit(change_b, 1);
+    let commit4_b_t1 = write_commit(change_b, 1);
 
-    // Can find multiple matches
+    // Picks the latest commit of divergent change, and ties are broken by
+    // position
     assert_eq!(
-        resolve_commit_ids(mut_repo, "author(name)"),
-        vec![
+        resolve_commit_ids(mut_repo, "latest_per_change(~root)"),
+        vec![commit4_b_t1.id().clone(), commit1_a_t2.id().clone()],
+    );
+    // Only commits in the set are considered
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "latest_per_change({} | {})",
+                commit2_a_t1.id().hex(),
+                commit3_b_t1.id().hex()
+            )
+        ),
+        vec![commit3_b_t1.id().clone(), commit2_a_t1.id().clone()],
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "latest_per_change(none())"),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_change_heads(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let mut write_commit = |change_id: &str, parent: &CommitId, description: &str| {
+        create_random_commit(mut_repo, &settings)
+            .set_change_id(ChangeId::from_hex(change_id))
+            .set_parents(vec![parent.clone()])
+            .set_description(description)
+            .write()
+            .unwrap()
+    };
+    let change_a = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    let change_b = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+    let change_c = "cccccccccccccccccccccccccccccccc";
+    let root_id = repo.store().root_commit_id().clone();
+    // commit3_a is a descendant of the superseded commit1_a, and commit4_b and
+    // commit5_b are divergent
+    let commit1_a = write_commit(change_a, &root_id, "a superseded");
+    let commit2_c = write_commit(change_c, commit1_a.id(), "c");
+    let commit3_a = write_commit(change_a, commit2_c.id(), "a current");
+    let commit4_b = write_commit(change_b, &root_id, "b 1");
+    let commit5_b = write_commit(change_b, &root_id, "b 2");
+
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "change_heads(~root)"),
+        vec![
+            commit5_b.id().clone(),
+            commit4_b.id().clone(),
+            commit3_a.id().clone(),
+            commit2_c.id().clone(),
+        ],
+    );
+    // Only commits in the set are considered
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "change_heads({} | {})",
+                commit1_a.id().hex(),
+                commit2_c.id().hex()
+            )
+        ),
+        vec![commit2_c.id().clone(), commit1_a.id().clone()],
+    );
+    assert_eq!(resolve_commit_ids(mut_repo, "change_heads(none())"), vec![]);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_merges(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.initial_commit();
+    let commit3 = graph_builder.initial_commit();
+    let commit4 = graph_builder.commit_with_parents(&[&commit1, &commit2]);
+    let commit5 = graph_builder.commit_with_parents(&[&commit1, &commit2, &commit3]);
+
+    // Finds all merges by default
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "merges()"),
+        vec![commit5.id().clone(), commit4.id().clone(),]
+    );
+    // Searches only among candidates if specified
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!(":{} & merges()", commit5.id().hex())),
+        vec![commit5.id().clone()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_description(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_description("commit 1")
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_description("commit 2")
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_description("commit 3")
+        .write()
+        .unwrap();
+    let commit4 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit3.id().clone()])
+        .set_description("")
+        .write()
+        .unwrap();
+
+    // Can find multiple matches
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "description(commit)"),
+        vec![
             commit3.id().clone(),
             commit2.id().clone(),
             commit1.id().clone()
         ]
     );
-    // Can find a unique match by either name or email
+    // Can find a unique match
     assert_eq!(
-        resolve_commit_ids(mut_repo, "author(\"name2\")"),
+        resolve_commit_ids(mut_repo, "description(\"commit 2\")"),
         vec![commit2.id().clone()]
     );
-    assert_eq!(
-        resolve_commit_ids(mut_repo, "author(\"name3\")"),
-        vec![commit3.id().clone()]
-    );
     // Searches only among candidates if specified
     assert_eq!(
-        resolve_commit_ids(mut_repo, "visible_heads() & author(\"name2\")"),
+        resolve_commit_ids(mut_repo, "visible_heads() & description(\"commit 2\")"),
         vec![]
     );
-    // Filter by union of pure predicate and set
+    // Can find commits with and without description
     assert_eq!(
         resolve_commit_ids(
             mut_repo,
-            &format!("root.. & (author(name1) | {})", commit3.id().hex())
+            &format!("{}: & description(empty)", commit1.id().hex())
         ),
-        vec![commit3.id().clone(), commit1.id().clone()]
+        vec![commit4.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{}: & description(nonempty)", commit1.id().hex())
+        ),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
     );
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_committer(use_git: bool) {
+fn test_evaluate_expression_trailer(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_description("commit 1\n\nBody.\n\nSigned-off-by: Some One <one@example.com>\n")
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_description("commit 2\n\nBody.\n")
+        .write()
+        .unwrap();
+    // Not a trailer block since it isn't the last paragraph
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_description("commit 3\n\nSigned-off-by: Some One <one@example.com>\n\nBody.\n")
+        .write()
+        .unwrap();
+    // The subject is never a trailer
+    let commit4 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit3.id().clone()])
+        .set_description("Signed-off-by: Some One <one@example.com>\n")
+        .write()
+        .unwrap();
+    let commit5 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit4.id().clone()])
+        .set_description("commit 5\n\nSigned-off-by: Other One <other@example.com>\nFixes: #123\n")
+        .write()
+        .unwrap();
+
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r#"trailer("Signed-off-by")"#),
+        vec![commit5.id().clone(), commit1.id().clone()]
+    );
+    // Keys are case-insensitive
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r#"trailer("signed-off-by")"#),
+        vec![commit5.id().clone(), commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            r#"trailer("Signed-off-by", "Some One <one@example.com>")"#
+        ),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, r##"trailer(Fixes, "#123")"##),
+        vec![commit5.id().clone()]
+    );
+    assert_eq!(resolve_commit_ids(mut_repo, "trailer(Body)"), vec![]);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_author(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
@@ -1864,7 +2909,7 @@ fn test_evaluate_expression_committer(use_git: bool) {
         tz_offset: 0,
     };
     let commit1 = create_random_commit(mut_repo, &settings)
-        .set_committer(Signature {
+        .set_author(Signature {
             name: "name1".to_string(),
             email: "email1".to_string(),
             timestamp: timestamp.clone(),
@@ -1873,7 +2918,7 @@ fn test_evaluate_expression_committer(use_git: bool) {
         .unwrap();
     let commit2 = create_random_commit(mut_repo, &settings)
         .set_parents(vec![commit1.id().clone()])
-        .set_committer(Signature {
+        .set_author(Signature {
             name: "name2".to_string(),
             email: "email2".to_string(),
             timestamp: timestamp.clone(),
@@ -1882,7 +2927,7 @@ fn test_evaluate_expression_committer(use_git: bool) {
         .unwrap();
     let commit3 = create_random_commit(mut_repo, &settings)
         .set_parents(vec![commit2.id().clone()])
-        .set_committer(Signature {
+        .set_author(Signature {
             name: "name3".to_string(),
             email: "email3".to_string(),
             timestamp,
@@ -1892,7 +2937,7 @@ fn test_evaluate_expression_committer(use_git: bool) {
 
     // Can find multiple matches
     assert_eq!(
-        resolve_commit_ids(mut_repo, "committer(name)"),
+        resolve_commit_ids(mut_repo, "author(name)"),
         vec![
             commit3.id().clone(),
             commit2.id().clone(),
@@ -1901,223 +2946,1394 @@ fn test_evaluate_expression_committer(use_git: bool) {
     );
     // Can find a unique match by either name or email
     assert_eq!(
-        resolve_commit_ids(mut_repo, "committer(\"name2\")"),
+        resolve_commit_ids(mut_repo, "author(\"name2\")"),
         vec![commit2.id().clone()]
     );
     assert_eq!(
-        resolve_commit_ids(mut_repo, "committer(\"name3\")"),
+        resolve_commit_ids(mut_repo, "author(\"name3\")"),
         vec![commit3.id().clone()]
     );
-    // Searches only among candidates if specified
-    assert_eq!(
-        resolve_commit_ids(mut_repo, "visible_heads() & committer(\"name2\")"),
-        vec![]
-    );
-}
-
-#[test_case(false ; "local backend")]
-#[test_case(true ; "git backend")]
-fn test_evaluate_expression_union(use_git: bool) {
-    let settings = testutils::user_settings();
-    let test_repo = TestRepo::init(use_git);
-    let repo = &test_repo.repo;
-
-    let root_commit = repo.store().root_commit();
-    let mut tx = repo.start_transaction(&settings, "test");
-    let mut_repo = tx.mut_repo();
-    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
-    let commit1 = graph_builder.initial_commit();
-    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
-    let commit3 = graph_builder.commit_with_parents(&[&commit2]);
-    let commit4 = graph_builder.commit_with_parents(&[&commit3]);
-    let commit5 = graph_builder.commit_with_parents(&[&commit2]);
-
-    // Union between ancestors
+    // Can match any of multiple needles
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(":{} | :{}", commit4.id().hex(), commit5.id().hex())
-        ),
-        vec![
-            commit5.id().clone(),
-            commit4.id().clone(),
-            commit3.id().clone(),
-            commit2.id().clone(),
-            commit1.id().clone(),
-            root_commit.id().clone()
-        ]
+        resolve_commit_ids(mut_repo, "author(alice, email3, name1)"),
+        vec![commit3.id().clone(), commit1.id().clone()]
     );
-
-    // Unioning can add back commits removed by difference
+    assert_eq!(resolve_commit_ids(mut_repo, "author(alice, bob)"), vec![]);
+    // Searches only among candidates if specified
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(
-                "(:{} ~ :{}) | :{}",
-                commit4.id().hex(),
-                commit2.id().hex(),
-                commit5.id().hex()
-            )
-        ),
-        vec![
-            commit5.id().clone(),
-            commit4.id().clone(),
-            commit3.id().clone(),
-            commit2.id().clone(),
-            commit1.id().clone(),
-            root_commit.id().clone(),
-        ]
+        resolve_commit_ids(mut_repo, "visible_heads() & author(\"name2\")"),
+        vec![]
     );
-
-    // Unioning of disjoint sets
+    // Filter by union of pure predicate and set
     assert_eq!(
         resolve_commit_ids(
             mut_repo,
-            &format!(
-                "(:{} ~ :{}) | {}",
-                commit4.id().hex(),
-                commit2.id().hex(),
-                commit5.id().hex(),
-            )
+            &format!("root.. & (author(name1) | {})", commit3.id().hex())
         ),
-        vec![
-            commit5.id().clone(),
-            commit4.id().clone(),
-            commit3.id().clone()
-        ]
+        vec![commit3.id().clone(), commit1.id().clone()]
     );
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_intersection(use_git: bool) {
+fn test_evaluate_expression_commit_prefetch(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    // More commits than the lookahead of the threads, on two branches
+    let mut parent_ids = vec![repo.store().root_commit_id().clone()];
+    for i in 0..80 {
+        let commit = create_random_commit(mut_repo, &settings)
+            .set_parents(vec![parent_ids[i / 2].clone()])
+            .set_description(format!("commit {}\n", i % 3))
+            .write()
+            .unwrap();
+        parent_ids.push(commit.id().clone());
+    }
+
+    let index = composite_index(mut_repo);
+    let evaluate_revset = |revset_str: &str, threads: usize| {
+        let expression = optimize(parse(revset_str, &RevsetAliasesMap::new(), None).unwrap());
+        let symbol_resolver = DefaultSymbolResolver::new(mut_repo, None);
+        let expression = expression
+            .resolve_user_expression(mut_repo, &symbol_resolver)
+            .unwrap();
+        let options = default_revset_engine::EvaluationOptions {
+            commit_prefetch_threads: threads,
+            ..Default::default()
+        };
+        default_revset_engine::evaluate_with_options(&expression, mut_repo.store(), index, &options)
+            .unwrap()
+    };
+    let evaluate = |revset_str: &str, threads: usize| {
+        evaluate_revset(revset_str, threads).iter().collect_vec()
+    };
+    for revset_str in [
+        "description(1)",
+        "~description(2)",
+        "description(0) | merges()",
+        "empty() | description(1)",
+        "description(0) | author(test)",
+    ] {
+        let expected = evaluate(revset_str, 0);
+        assert!(!expected.is_empty());
+        assert_eq!(evaluate(revset_str, 1), expected, "{revset_str}");
+        assert_eq!(evaluate(revset_str, 3), expected, "{revset_str}");
+        assert_eq!(evaluate(revset_str, 8), expected, "{revset_str}");
+    }
+
+    // Only predicates reading nothing but the commit objects are tested on
+    // the threads
+    let is_prefetched = |revset_str: &str, threads: usize| {
+        format!("{:?}", evaluate_revset(revset_str, threads)).contains("CommitPrefetchFilterRevset")
+    };
+    assert!(is_prefetched("~description(2)", 3));
+    assert!(is_prefetched("description(0) | author(test)", 3));
+    assert!(!is_prefetched("description(0) | merges()", 3));
+    assert!(!is_prefetched("empty() | description(1)", 3));
+    assert!(!is_prefetched("description(1)", 1));
+
+    // The iteration can be stopped before the workers run out of candidates
+    let revset = evaluate_revset("description(1)", 3);
+    assert_eq!(
+        revset.iter().take(2).collect_vec(),
+        evaluate("description(1)", 0)[..2]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_strict_ranges(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    // 3
+    // | 2
+    // |/
+    // 1
+    let commit1 = write_random_commit(mut_repo, &settings);
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .write()
+        .unwrap();
+
+    let index = composite_index(mut_repo);
+    let evaluate = |revset_str: &str, strict_ranges: bool| {
+        let expression = optimize(parse(revset_str, &RevsetAliasesMap::new(), None).unwrap());
+        let symbol_resolver = DefaultSymbolResolver::new(mut_repo, None);
+        let expression = expression
+            .resolve_user_expression(mut_repo, &symbol_resolver)
+            .unwrap();
+        let options = default_revset_engine::EvaluationOptions {
+            strict_ranges,
+            ..Default::default()
+        };
+        default_revset_engine::evaluate_with_options(&expression, mut_repo.store(), index, &options)
+            .map(|revset| revset.iter().collect_vec())
+    };
+
+    // Disjoint roots and heads only exclude the common ancestors by default
+    let disjoint = format!("{}..{}", commit2.id().hex(), commit3.id().hex());
+    assert_eq!(
+        evaluate(&disjoint, false).unwrap(),
+        vec![commit3.id().clone()]
+    );
+    assert_matches!(
+        evaluate(&disjoint, true),
+        Err(RevsetEvaluationError::Other(message))
+            if message == "None of the range roots are ancestors of its heads"
+    );
+
+    // Ranges with roots among the ancestors are the same in both modes
+    for revset_str in [
+        format!("{}..{}", commit1.id().hex(), commit3.id().hex()),
+        format!(
+            "({} | {})..{}",
+            commit1.id().hex(),
+            commit2.id().hex(),
+            commit3.id().hex()
+        ),
+        format!("{}..{}", commit3.id().hex(), commit3.id().hex()),
+        format!("..{}", commit3.id().hex()),
+    ] {
+        assert_eq!(
+            evaluate(&revset_str, true).unwrap(),
+            evaluate(&revset_str, false).unwrap(),
+            "{revset_str}"
+        );
+    }
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_revset_count_authors(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let signature = |name: &str, email: &str| Signature {
+        name: name.to_string(),
+        email: email.to_string(),
+        timestamp: Timestamp {
+            timestamp: MillisSinceEpoch(0),
+            tz_offset: 0,
+        },
+    };
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_author(signature("name1", "email1"))
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_author(signature("name2", "email2"))
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_author(signature("name1", "email1"))
+        .write()
+        .unwrap();
+
+    let expression = RevsetExpression::commits(vec![
+        commit1.id().clone(),
+        commit2.id().clone(),
+        commit3.id().clone(),
+    ])
+    .resolve(mut_repo)
+    .unwrap();
+    let revset =
+        default_revset_engine::evaluate(&expression, mut_repo.store(), composite_index(mut_repo))
+            .unwrap();
+    let author1 = ("name1".to_owned(), "email1".to_owned());
+    let author2 = ("name2".to_owned(), "email2".to_owned());
+    assert_eq!(
+        revset.count_authors(mut_repo.store(), None).unwrap(),
+        vec![(author1.clone(), 2), (author2.clone(), 1)]
+    );
+    // Only the first commits are visited
+    assert_eq!(
+        revset.count_authors(mut_repo.store(), Some(2)).unwrap(),
+        vec![(author1, 1), (author2, 1)]
+    );
+    assert_eq!(
+        revset.count_authors(mut_repo.store(), Some(0)).unwrap(),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_revset_iter_by_date(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let signature = |timestamp: i64| Signature {
+        name: "name".to_string(),
+        email: "email".to_string(),
+        timestamp: Timestamp {
+            timestamp: MillisSinceEpoch(timestamp),
+            tz_offset: 0,
+        },
+    };
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_committer(signature(2000))
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_committer(signature(3000))
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_committer(signature(1000))
+        .write()
+        .unwrap();
+    let commit4 = create_random_commit(mut_repo, &settings)
+        .set_committer(signature(3000))
+        .write()
+        .unwrap();
+
+    let expression = RevsetExpression::commits(vec![
+        commit1.id().clone(),
+        commit2.id().clone(),
+        commit3.id().clone(),
+        commit4.id().clone(),
+    ])
+    .resolve(mut_repo)
+    .unwrap();
+    let revset =
+        default_revset_engine::evaluate(&expression, mut_repo.store(), composite_index(mut_repo))
+            .unwrap();
+    // Commits with the same timestamp stay in index position order
+    assert_eq!(
+        revset.iter_by_date(mut_repo.store()).unwrap(),
+        vec![
+            commit4.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone(),
+            commit3.id().clone(),
+        ]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_author_email(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let timestamp = Timestamp {
+        timestamp: MillisSinceEpoch(0),
+        tz_offset: 0,
+    };
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_author(Signature {
+            name: "Alice Liddell".to_string(),
+            email: "alice@example.com".to_string(),
+            timestamp: timestamp.clone(),
+        })
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_author(Signature {
+            name: "Alice Liddell".to_string(),
+            email: "alice@example.com.evil".to_string(),
+            timestamp: timestamp.clone(),
+        })
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_author(Signature {
+            name: "Alice Liddell".to_string(),
+            email: "Alice@Example.com".to_string(),
+            timestamp,
+        })
+        .write()
+        .unwrap();
+
+    // Substring match by default
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "author_email(\"alice@example.com\")"),
+        vec![commit2.id().clone(), commit1.id().clone()]
+    );
+    // Only matches the email, not the name
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "author_email(\"Liddell\")"),
+        vec![]
+    );
+    // Exact match doesn't match a superstring
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "author_email(exact:\"alice@example.com\")"),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "author_email(exact:\"alice@example\")"),
+        vec![]
+    );
+    // Case-insensitive match
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "author_email(ci:\"ALICE@example.com\")"),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "author_email(i:exact:\"ALICE@example.com\")"),
+        vec![commit3.id().clone(), commit1.id().clone()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_committer(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let timestamp = Timestamp {
+        timestamp: MillisSinceEpoch(0),
+        tz_offset: 0,
+    };
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_committer(Signature {
+            name: "name1".to_string(),
+            email: "email1".to_string(),
+            timestamp: timestamp.clone(),
+        })
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_committer(Signature {
+            name: "name2".to_string(),
+            email: "email2".to_string(),
+            timestamp: timestamp.clone(),
+        })
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_committer(Signature {
+            name: "name3".to_string(),
+            email: "email3".to_string(),
+            timestamp,
+        })
+        .write()
+        .unwrap();
+
+    // Can find multiple matches
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "committer(name)"),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
+    // Can find a unique match by either name or email
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "committer(\"name2\")"),
+        vec![commit2.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "committer(\"name3\")"),
+        vec![commit3.id().clone()]
+    );
+    // Can match any of multiple needles
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "committer(name2, email1)"),
+        vec![commit2.id().clone(), commit1.id().clone()]
+    );
+    // Searches only among candidates if specified
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "visible_heads() & committer(\"name2\")"),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_union(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let root_commit = repo.store().root_commit();
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit2]);
+    let commit4 = graph_builder.commit_with_parents(&[&commit3]);
+    let commit5 = graph_builder.commit_with_parents(&[&commit2]);
+
+    // Union between ancestors
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} | :{}", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![
+            commit5.id().clone(),
+            commit4.id().clone(),
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone(),
+            root_commit.id().clone()
+        ]
+    );
+
+    // Unioning can add back commits removed by difference
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "(:{} ~ :{}) | :{}",
+                commit4.id().hex(),
+                commit2.id().hex(),
+                commit5.id().hex()
+            )
+        ),
+        vec![
+            commit5.id().clone(),
+            commit4.id().clone(),
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone(),
+            root_commit.id().clone(),
+        ]
+    );
+
+    // Unioning of disjoint sets
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "(:{} ~ :{}) | {}",
+                commit4.id().hex(),
+                commit2.id().hex(),
+                commit5.id().hex(),
+            )
+        ),
+        vec![
+            commit5.id().clone(),
+            commit4.id().clone(),
+            commit3.id().clone()
+        ]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_intersection(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let root_commit = repo.store().root_commit();
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit2]);
+    let commit4 = graph_builder.commit_with_parents(&[&commit3]);
+    let commit5 = graph_builder.commit_with_parents(&[&commit2]);
+
+    // Intersection between ancestors
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} & :{}", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![
+            commit2.id().clone(),
+            commit1.id().clone(),
+            root_commit.id().clone()
+        ]
+    );
+
+    // Intersection of disjoint sets
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{} & {}", commit4.id().hex(), commit2.id().hex())
+        ),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_difference(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let root_commit = repo.store().root_commit();
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.commit_with_parents(&[&commit2]);
+    let commit4 = graph_builder.commit_with_parents(&[&commit3]);
+    let commit5 = graph_builder.commit_with_parents(&[&commit2]);
+
+    // Difference from all
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("~:{}", commit5.id().hex())),
+        vec![commit4.id().clone(), commit3.id().clone()]
+    );
+
+    // Difference between ancestors
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} ~ :{}", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![commit4.id().clone(), commit3.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} ~ :{}", commit5.id().hex(), commit4.id().hex())
+        ),
+        vec![commit5.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("~:{} & :{}", commit4.id().hex(), commit5.id().hex())
+        ),
+        vec![commit5.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} ~ :{}", commit4.id().hex(), commit2.id().hex())
+        ),
+        vec![commit4.id().clone(), commit3.id().clone()]
+    );
+
+    // Associativity
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                ":{} ~ {} ~ {}",
+                commit4.id().hex(),
+                commit2.id().hex(),
+                commit3.id().hex()
+            )
+        ),
+        vec![
+            commit4.id().clone(),
+            commit1.id().clone(),
+            root_commit.id().clone(),
+        ]
+    );
+
+    // Subtracting a difference does not add back any commits
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "(:{} ~ :{}) ~ (:{} ~ :{})",
+                commit4.id().hex(),
+                commit1.id().hex(),
+                commit3.id().hex(),
+                commit1.id().hex(),
+            )
+        ),
+        vec![commit4.id().clone()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_symmetric_difference(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
 
-    let root_commit = repo.store().root_commit();
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
     let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
     let commit1 = graph_builder.initial_commit();
     let commit2 = graph_builder.commit_with_parents(&[&commit1]);
     let commit3 = graph_builder.commit_with_parents(&[&commit2]);
-    let commit4 = graph_builder.commit_with_parents(&[&commit3]);
-    let commit5 = graph_builder.commit_with_parents(&[&commit2]);
+    let commit4 = graph_builder.commit_with_parents(&[&commit2]);
+
+    // Commits in exactly one of the sets
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} % :{}", commit3.id().hex(), commit4.id().hex())
+        ),
+        vec![commit4.id().clone(), commit3.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(":{} % :{}", commit3.id().hex(), commit1.id().hex())
+        ),
+        vec![commit3.id().clone(), commit2.id().clone()]
+    );
+    // Same as the union of the differences
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{} % :{}", commit4.id().hex(), commit2.id().hex())
+        ),
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "({} ~ :{}) | (:{} ~ {})",
+                commit4.id().hex(),
+                commit2.id().hex(),
+                commit2.id().hex(),
+                commit4.id().hex()
+            )
+        ),
+    );
+    // Symmetric difference with itself or none()
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!("{} % {}", commit3.id().hex(), commit3.id().hex())
+        ),
+        vec![]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("{} % none()", commit3.id().hex())),
+        vec![commit3.id().clone()]
+    );
+    // Mixed with a filter
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                ":{} & (merges() % {})",
+                commit4.id().hex(),
+                commit4.id().hex()
+            )
+        ),
+        vec![commit4.id().clone()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_filter_combinator(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let root_commit_id = repo.store().root_commit_id();
+    let commit1 = create_random_commit(mut_repo, &settings)
+        .set_description("commit 1")
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit1.id().clone()])
+        .set_description("commit 2")
+        .write()
+        .unwrap();
+    let commit3 = create_random_commit(mut_repo, &settings)
+        .set_parents(vec![commit2.id().clone()])
+        .set_description("commit 3")
+        .write()
+        .unwrap();
+
+    // Not intersected with a set node
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "~description(1)"),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            root_commit_id.clone(),
+        ],
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "description(1) | description(2)"),
+        vec![commit2.id().clone(), commit1.id().clone()],
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            "description(commit) ~ (description(2) | description(3))",
+        ),
+        vec![commit1.id().clone()],
+    );
+
+    // Intersected with a set node
+    assert_eq!(
+        resolve_commit_ids(mut_repo, "root.. & ~description(1)"),
+        vec![commit3.id().clone(), commit2.id().clone()],
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "{}.. & (description(1) | description(2))",
+                commit1.id().hex(),
+            )
+        ),
+        vec![commit2.id().clone()],
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_file(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_workspace = TestWorkspace::init(&settings, use_git);
+    let repo = &test_workspace.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let added_clean_clean = RepoPath::from_internal_string("added_clean_clean");
+    let added_modified_clean = RepoPath::from_internal_string("added_modified_clean");
+    let added_modified_removed = RepoPath::from_internal_string("added_modified_removed");
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&added_clean_clean, "1"),
+            (&added_modified_clean, "1"),
+            (&added_modified_removed, "1"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&added_clean_clean, "1"),
+            (&added_modified_clean, "2"),
+            (&added_modified_removed, "2"),
+        ],
+    );
+    let tree3 = testutils::create_tree(
+        repo,
+        &[
+            (&added_clean_clean, "1"),
+            (&added_modified_clean, "2"),
+            // added_modified_removed,
+        ],
+    );
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit2 = mut_repo
+        .new_commit(&settings, vec![commit1.id().clone()], tree2.id().clone())
+        .write()
+        .unwrap();
+    let commit3 = mut_repo
+        .new_commit(&settings, vec![commit2.id().clone()], tree3.id().clone())
+        .write()
+        .unwrap();
+    let commit4 = mut_repo
+        .new_commit(&settings, vec![commit3.id().clone()], tree3.id().clone())
+        .write()
+        .unwrap();
+
+    let resolve = |file_path: &RepoPath| -> Vec<CommitId> {
+        let mut_repo = &*mut_repo;
+        let expression =
+            RevsetExpression::filter(RevsetFilterPredicate::File(Some(vec![file_path.clone()])));
+        let revset = expression
+            .resolve(mut_repo)
+            .unwrap()
+            .evaluate(mut_repo)
+            .unwrap();
+        revset.iter().collect()
+    };
+
+    assert_eq!(resolve(&added_clean_clean), vec![commit1.id().clone()]);
+    assert_eq!(
+        resolve(&added_modified_clean),
+        vec![commit2.id().clone(), commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve(&added_modified_removed),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
+
+    // file() revset:
+    assert_eq!(
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            r#"file("repo/added_clean_clean")"#,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root().parent().unwrap()),
+        ),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            &format!(r#"{}: & file("added_modified_clean")"#, commit2.id().hex()),
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        ),
+        vec![commit2.id().clone()]
+    );
+
+    // Paths are matched case-sensitively by default
+    assert_eq!(
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            r#"file("Added_Clean_Clean")"#,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        ),
+        vec![]
+    );
+    assert_eq!(
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            r#"file(ci:"Added_Clean_Clean")"#,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        ),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            r#"file("added_clean_clean", ci:"ADDED_MODIFIED_REMOVED")"#,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        ),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
+
+    // empty() revset, which is identical to ~file(".")
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("{}: & empty()", commit1.id().hex())),
+        vec![commit4.id().clone()]
+    );
+}
 
-    // Intersection between ancestors
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_same_tree_as(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let file_path = RepoPath::from_internal_string("file");
+    let tree1 = testutils::create_tree(repo, &[(&file_path, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&file_path, "2")]);
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit2 = mut_repo
+        .new_commit(&settings, vec![commit1.id().clone()], tree2.id().clone())
+        .write()
+        .unwrap();
+    let commit3 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .set_description("cherry-picked")
+        .write()
+        .unwrap();
+
+    // The commit itself and the one with an identical tree match, but not the
+    // one with a different tree
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("same_tree_as({})", commit1.id().hex())),
+        vec![commit3.id().clone(), commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(mut_repo, &format!("same_tree_as({})", commit2.id().hex())),
+        vec![commit2.id().clone()]
+    );
+    // Any of the trees of the given commits can match
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "same_tree_as({} | {})",
+                commit2.id().hex(),
+                commit3.id().hex()
+            )
+        ),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
+    // Can be intersected and negated like other filters
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "{}: & same_tree_as({})",
+                commit1.id().hex(),
+                commit3.id().hex()
+            )
+        ),
+        vec![commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve_commit_ids(
+            mut_repo,
+            &format!(
+                "{}: & ~same_tree_as({})",
+                commit1.id().hex(),
+                commit3.id().hex()
+            )
+        ),
+        vec![commit2.id().clone()]
+    );
+    assert_eq!(resolve_commit_ids(mut_repo, "same_tree_as(none())"), vec![]);
+}
+
+/// Backend recording which commits are read.
+#[derive(Debug)]
+struct CommitReadRecordingBackend {
+    inner: LocalBackend,
+    read_commit_ids: Mutex<Vec<CommitId>>,
+}
+
+impl Backend for CommitReadRecordingBackend {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn commit_id_length(&self) -> usize {
+        self.inner.commit_id_length()
+    }
+
+    fn change_id_length(&self) -> usize {
+        self.inner.change_id_length()
+    }
+
+    fn read_file(&self, path: &RepoPath, id: &FileId) -> BackendResult<Box<dyn Read>> {
+        self.inner.read_file(path, id)
+    }
+
+    fn write_file(&self, path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
+        self.inner.write_file(path, contents)
+    }
+
+    fn read_symlink(&self, path: &RepoPath, id: &SymlinkId) -> BackendResult<String> {
+        self.inner.read_symlink(path, id)
+    }
+
+    fn write_symlink(&self, path: &RepoPath, target: &str) -> BackendResult<SymlinkId> {
+        self.inner.write_symlink(path, target)
+    }
+
+    fn root_commit_id(&self) -> &CommitId {
+        self.inner.root_commit_id()
+    }
+
+    fn root_change_id(&self) -> &ChangeId {
+        self.inner.root_change_id()
+    }
+
+    fn empty_tree_id(&self) -> &TreeId {
+        self.inner.empty_tree_id()
+    }
+
+    fn read_tree(&self, path: &RepoPath, id: &TreeId) -> BackendResult<backend::Tree> {
+        self.inner.read_tree(path, id)
+    }
+
+    fn write_tree(&self, path: &RepoPath, contents: &backend::Tree) -> BackendResult<TreeId> {
+        self.inner.write_tree(path, contents)
+    }
+
+    fn read_conflict(&self, path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict> {
+        self.inner.read_conflict(path, id)
+    }
+
+    fn write_conflict(&self, path: &RepoPath, contents: &Conflict) -> BackendResult<ConflictId> {
+        self.inner.write_conflict(path, contents)
+    }
+
+    fn read_commit(&self, id: &CommitId) -> BackendResult<backend::Commit> {
+        self.read_commit_ids.lock().unwrap().push(id.clone());
+        self.inner.read_commit(id)
+    }
+
+    fn write_commit(
+        &self,
+        contents: backend::Commit,
+    ) -> BackendResult<(CommitId, backend::Commit)> {
+        self.inner.write_commit(contents)
+    }
+}
+
+#[test]
+fn test_evaluate_expression_merges_and_file() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(false);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+    let file_path = RepoPath::from_internal_string("file");
+    let tree1 = testutils::create_tree(repo, &[(&file_path, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&file_path, "2")]);
+    let tree3 = testutils::create_tree(repo, &[(&file_path, "3")]);
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit2 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree2.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit3 = mut_repo
+        .new_commit(
+            &settings,
+            vec![commit1.id().clone(), commit2.id().clone()],
+            tree3.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit4 = mut_repo
+        .new_commit(&settings, vec![commit3.id().clone()], tree1.id().clone())
+        .write()
+        .unwrap();
+    let repo = tx.commit();
+
+    // file() is written first, but the cheaper merges() should be tested first
+    let expression = optimize(
+        RevsetExpression::filter(RevsetFilterPredicate::File(Some(vec![file_path]))).intersection(
+            &RevsetExpression::filter(RevsetFilterPredicate::ParentCount(2..u32::MAX)),
+        ),
+    );
+    let backend = CommitReadRecordingBackend {
+        inner: LocalBackend::load(&repo.repo_path().join("store")),
+        read_commit_ids: Mutex::new(vec![]),
+    };
+    let store = Store::new(Box::new(backend));
+    let resolved = expression.resolve(repo.as_ref()).unwrap();
+    let revset = repo.index().evaluate_revset(&resolved, &store).unwrap();
+    assert_eq!(revset.iter().collect_vec(), vec![commit3.id().clone()]);
+
+    // The non-merge commit4 isn't read. The others are needed to diff the merge
+    // commit against its parents and their merge base (the root).
+    let backend: &CommitReadRecordingBackend = store.backend_impl().downcast_ref().unwrap();
+    let read_commit_ids = backend.read_commit_ids.lock().unwrap();
+    assert!(!read_commit_ids.contains(commit4.id()));
+    assert_eq!(
+        read_commit_ids.iter().sorted().dedup().collect_vec(),
+        [
+            repo.store().root_commit_id(),
+            commit1.id(),
+            commit2.id(),
+            commit3.id()
+        ]
+        .into_iter()
+        .sorted()
+        .collect_vec()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_not_file(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let file_path1 = RepoPath::from_internal_string("file1");
+    let file_path2 = RepoPath::from_internal_string("file2");
+    let tree1 = testutils::create_tree(repo, &[(&file_path1, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&file_path1, "1"), (&file_path2, "2")]);
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit2 = mut_repo
+        .new_commit(&settings, vec![commit1.id().clone()], tree2.id().clone())
+        .write()
+        .unwrap();
+    let mut_repo = &*mut_repo;
+
+    let not_file1 =
+        RevsetExpression::filter(RevsetFilterPredicate::File(Some(vec![file_path1]))).negated();
+    let evaluate = |expression: Rc<RevsetExpression>| {
+        optimize(expression)
+            .resolve(mut_repo)
+            .unwrap()
+            .evaluate(mut_repo)
+            .unwrap()
+    };
+
+    // commit1 touches file1, commit2 doesn't
+    let revset = evaluate(not_file1.minus(&RevsetExpression::commit(
+        mut_repo.store().root_commit_id().clone(),
+    )));
+    assert_eq!(revset.iter().collect_vec(), vec![commit2.id().clone()]);
+    let revset = evaluate(
+        RevsetExpression::commit(commit1.id().clone())
+            .descendants()
+            .intersection(&not_file1),
+    );
+    assert_eq!(revset.iter().collect_vec(), vec![commit2.id().clone()]);
+
+    // The negated predicate filters the candidates in place instead of
+    // evaluating file() as a separate set to subtract.
+    let debug_string = format!("{:?}", revset);
+    assert!(debug_string.contains("FilterRevset"), "{debug_string}");
+    assert!(debug_string.contains("NotInPredicate"), "{debug_string}");
+    assert!(!debug_string.contains("DifferenceRevset"), "{debug_string}");
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_file_root_commit(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let added = RepoPath::from_internal_string("added");
+    let tree1 = testutils::create_tree(repo, &[(&added, "1")]);
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let mut_repo = &*mut_repo;
+
+    let resolve = |file_path: &RepoPath| -> Vec<CommitId> {
+        let expression =
+            RevsetExpression::filter(RevsetFilterPredicate::File(Some(vec![file_path.clone()])));
+        let revset = expression
+            .resolve(mut_repo)
+            .unwrap()
+            .evaluate(mut_repo)
+            .unwrap();
+        revset.iter().collect()
+    };
+
+    // The root commit has no parents and an empty tree, so it never matches
+    assert_eq!(resolve(&added), vec![commit1.id().clone()]);
+    assert_eq!(resolve(&RepoPath::root()), vec![commit1.id().clone()]);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_evaluate_expression_file_all(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_workspace = TestWorkspace::init(&settings, use_git);
+    let repo = &test_workspace.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut_repo = tx.mut_repo();
+
+    let file_path1 = RepoPath::from_internal_string("dir/file1");
+    let file_path2 = RepoPath::from_internal_string("file2");
+    let tree1 = testutils::create_tree(repo, &[(&file_path1, "1"), (&file_path2, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&file_path1, "2"), (&file_path2, "1")]);
+    let tree3 = testutils::create_tree(repo, &[(&file_path1, "3"), (&file_path2, "3")]);
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit2 = mut_repo
+        .new_commit(&settings, vec![commit1.id().clone()], tree2.id().clone())
+        .write()
+        .unwrap();
+    let commit3 = mut_repo
+        .new_commit(&settings, vec![commit2.id().clone()], tree3.id().clone())
+        .write()
+        .unwrap();
+
+    let resolve = |revset_str: &str| -> Vec<CommitId> {
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            revset_str,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        )
+    };
+
+    // Commits touching both paths match, but not commit2 touching only one
+    assert_eq!(
+        resolve("file_all(dir, file2)"),
+        vec![commit3.id().clone(), commit1.id().clone()]
+    );
+    assert_eq!(
+        resolve("file(dir, file2)"),
+        vec![
+            commit3.id().clone(),
+            commit2.id().clone(),
+            commit1.id().clone()
+        ]
+    );
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(":{} & :{}", commit4.id().hex(), commit5.id().hex())
-        ),
+        resolve("file_all(dir)"),
         vec![
+            commit3.id().clone(),
             commit2.id().clone(),
-            commit1.id().clone(),
-            root_commit.id().clone()
+            commit1.id().clone()
         ]
     );
-
-    // Intersection of disjoint sets
+    assert_eq!(resolve("file_all(dir, nonexistent)"), vec![]);
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!("{} & {}", commit4.id().hex(), commit2.id().hex())
-        ),
-        vec![]
+        resolve(&format!("{}: & ~file_all(file2, dir)", commit1.id().hex())),
+        vec![commit2.id().clone()]
     );
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_difference(use_git: bool) {
+fn test_evaluate_expression_file_added(use_git: bool) {
     let settings = testutils::user_settings();
-    let test_repo = TestRepo::init(use_git);
-    let repo = &test_repo.repo;
+    let test_workspace = TestWorkspace::init(&settings, use_git);
+    let repo = &test_workspace.repo;
 
-    let root_commit = repo.store().root_commit();
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
-    let mut graph_builder = CommitGraphBuilder::new(&settings, mut_repo);
-    let commit1 = graph_builder.initial_commit();
-    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
-    let commit3 = graph_builder.commit_with_parents(&[&commit2]);
-    let commit4 = graph_builder.commit_with_parents(&[&commit3]);
-    let commit5 = graph_builder.commit_with_parents(&[&commit2]);
 
-    // Difference from all
-    assert_eq!(
-        resolve_commit_ids(mut_repo, &format!("~:{}", commit5.id().hex())),
-        vec![commit4.id().clone(), commit3.id().clone()]
-    );
+    let file_path1 = RepoPath::from_internal_string("file1");
+    let file_path2 = RepoPath::from_internal_string("dir/file2");
+    let file_path3 = RepoPath::from_internal_string("file3");
+    let tree1 = testutils::create_tree(repo, &[(&file_path1, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&file_path1, "2"), (&file_path2, "2")]);
+    let tree3 = testutils::create_tree(repo, &[(&file_path1, "2"), (&file_path3, "2")]);
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
+        .write()
+        .unwrap();
+    let commit2 = mut_repo
+        .new_commit(&settings, vec![commit1.id().clone()], tree2.id().clone())
+        .write()
+        .unwrap();
+    let commit3 = mut_repo
+        .new_commit(&settings, vec![commit2.id().clone()], tree3.id().clone())
+        .write()
+        .unwrap();
 
-    // Difference between ancestors
-    assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(":{} ~ :{}", commit4.id().hex(), commit5.id().hex())
-        ),
-        vec![commit4.id().clone(), commit3.id().clone()]
-    );
-    assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(":{} ~ :{}", commit5.id().hex(), commit4.id().hex())
-        ),
-        vec![commit5.id().clone()]
-    );
-    assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!("~:{} & :{}", commit4.id().hex(), commit5.id().hex())
-        ),
-        vec![commit5.id().clone()]
-    );
-    assert_eq!(
-        resolve_commit_ids(
+    let resolve = |revset_str: &str| -> Vec<CommitId> {
+        resolve_commit_ids_in_workspace(
             mut_repo,
-            &format!(":{} ~ :{}", commit4.id().hex(), commit2.id().hex())
-        ),
-        vec![commit4.id().clone(), commit3.id().clone()]
-    );
+            revset_str,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        )
+    };
 
-    // Associativity
+    // Only the commit introducing the file matches, not the one modifying it
+    assert_eq!(resolve("file_added(file1)"), vec![commit1.id().clone()]);
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(
-                ":{} ~ {} ~ {}",
-                commit4.id().hex(),
-                commit2.id().hex(),
-                commit3.id().hex()
-            )
-        ),
-        vec![
-            commit4.id().clone(),
-            commit1.id().clone(),
-            root_commit.id().clone(),
-        ]
+        resolve("file(file1)"),
+        vec![commit2.id().clone(), commit1.id().clone()]
     );
-
-    // Subtracting a difference does not add back any commits
+    // A directory is added with its first file
+    assert_eq!(resolve("file_added(dir)"), vec![commit2.id().clone()]);
+    // A renamed file is added at the new path, but removing it doesn't match
+    assert_eq!(resolve("file_added(file3)"), vec![commit3.id().clone()]);
+    assert_eq!(resolve("file_added(dir/file2)"), vec![commit2.id().clone()]);
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            &format!(
-                "(:{} ~ :{}) ~ (:{} ~ :{})",
-                commit4.id().hex(),
-                commit1.id().hex(),
-                commit3.id().hex(),
-                commit1.id().hex(),
-            )
-        ),
-        vec![commit4.id().clone()]
+        resolve("file_added(file1, file3)"),
+        vec![commit3.id().clone(), commit1.id().clone()]
     );
+    assert_eq!(resolve("file_added(nonexistent)"), vec![]);
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_filter_combinator(use_git: bool) {
+fn test_evaluate_expression_file_count(use_git: bool) {
     let settings = testutils::user_settings();
     let test_repo = TestRepo::init(use_git);
     let repo = &test_repo.repo;
@@ -2125,63 +4341,56 @@ fn test_evaluate_expression_filter_combinator(use_git: bool) {
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
 
-    let root_commit_id = repo.store().root_commit_id();
-    let commit1 = create_random_commit(mut_repo, &settings)
-        .set_description("commit 1")
+    let file_path1 = RepoPath::from_internal_string("file1");
+    let file_path2 = RepoPath::from_internal_string("dir/file2");
+    let file_path3 = RepoPath::from_internal_string("dir/file3");
+    let tree1 = testutils::create_tree(repo, &[(&file_path1, "1")]);
+    let tree2 = testutils::create_tree(
+        repo,
+        &[(&file_path1, "2"), (&file_path2, "2"), (&file_path3, "2")],
+    );
+    // The root commit's child counts all files in its tree
+    let commit1 = mut_repo
+        .new_commit(
+            &settings,
+            vec![repo.store().root_commit_id().clone()],
+            tree1.id().clone(),
+        )
         .write()
         .unwrap();
-    let commit2 = create_random_commit(mut_repo, &settings)
-        .set_parents(vec![commit1.id().clone()])
-        .set_description("commit 2")
+    let commit2 = mut_repo
+        .new_commit(&settings, vec![commit1.id().clone()], tree2.id().clone())
         .write()
         .unwrap();
-    let commit3 = create_random_commit(mut_repo, &settings)
-        .set_parents(vec![commit2.id().clone()])
-        .set_description("commit 3")
+    let commit3 = mut_repo
+        .new_commit(&settings, vec![commit2.id().clone()], tree2.id().clone())
         .write()
         .unwrap();
 
-    // Not intersected with a set node
-    assert_eq!(
-        resolve_commit_ids(mut_repo, "~description(1)"),
-        vec![
-            commit3.id().clone(),
-            commit2.id().clone(),
-            root_commit_id.clone(),
-        ],
-    );
     assert_eq!(
-        resolve_commit_ids(mut_repo, "description(1) | description(2)"),
-        vec![commit2.id().clone(), commit1.id().clone()],
+        resolve_commit_ids(mut_repo, "file_count(2..)"),
+        vec![commit2.id().clone()]
     );
     assert_eq!(
-        resolve_commit_ids(
-            mut_repo,
-            "description(commit) ~ (description(2) | description(3))",
-        ),
-        vec![commit1.id().clone()],
+        resolve_commit_ids(mut_repo, "file_count(1..3)"),
+        vec![commit1.id().clone()]
     );
-
-    // Intersected with a set node
     assert_eq!(
-        resolve_commit_ids(mut_repo, "root.. & ~description(1)"),
-        vec![commit3.id().clone(), commit2.id().clone()],
+        resolve_commit_ids(mut_repo, "file_count(3)"),
+        vec![commit2.id().clone()]
     );
     assert_eq!(
         resolve_commit_ids(
             mut_repo,
-            &format!(
-                "{}.. & (description(1) | description(2))",
-                commit1.id().hex(),
-            )
+            &format!("{}: & file_count(..1)", commit1.id().hex())
         ),
-        vec![commit2.id().clone()],
+        vec![commit3.id().clone()]
     );
 }
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
-fn test_evaluate_expression_file(use_git: bool) {
+fn test_evaluate_expression_file_within(use_git: bool) {
     let settings = testutils::user_settings();
     let test_workspace = TestWorkspace::init(&settings, use_git);
     let repo = &test_workspace.repo;
@@ -2189,33 +4398,12 @@ fn test_evaluate_expression_file(use_git: bool) {
     let mut tx = repo.start_transaction(&settings, "test");
     let mut_repo = tx.mut_repo();
 
-    let added_clean_clean = RepoPath::from_internal_string("added_clean_clean");
-    let added_modified_clean = RepoPath::from_internal_string("added_modified_clean");
-    let added_modified_removed = RepoPath::from_internal_string("added_modified_removed");
-    let tree1 = testutils::create_tree(
-        repo,
-        &[
-            (&added_clean_clean, "1"),
-            (&added_modified_clean, "1"),
-            (&added_modified_removed, "1"),
-        ],
-    );
-    let tree2 = testutils::create_tree(
-        repo,
-        &[
-            (&added_clean_clean, "1"),
-            (&added_modified_clean, "2"),
-            (&added_modified_removed, "2"),
-        ],
-    );
-    let tree3 = testutils::create_tree(
-        repo,
-        &[
-            (&added_clean_clean, "1"),
-            (&added_modified_clean, "2"),
-            // added_modified_removed,
-        ],
-    );
+    // Only the middle commit touches file_path1
+    let file_path1 = RepoPath::from_internal_string("file1");
+    let file_path2 = RepoPath::from_internal_string("file2");
+    let tree1 = testutils::create_tree(repo, &[(&file_path2, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&file_path1, "2"), (&file_path2, "1")]);
+    let tree3 = testutils::create_tree(repo, &[(&file_path1, "2"), (&file_path2, "3")]);
     let commit1 = mut_repo
         .new_commit(
             &settings,
@@ -2232,61 +4420,61 @@ fn test_evaluate_expression_file(use_git: bool) {
         .new_commit(&settings, vec![commit2.id().clone()], tree3.id().clone())
         .write()
         .unwrap();
-    let commit4 = mut_repo
-        .new_commit(&settings, vec![commit3.id().clone()], tree3.id().clone())
-        .write()
-        .unwrap();
 
-    let resolve = |file_path: &RepoPath| -> Vec<CommitId> {
-        let mut_repo = &*mut_repo;
-        let expression =
-            RevsetExpression::filter(RevsetFilterPredicate::File(Some(vec![file_path.clone()])));
-        let revset = expression
-            .resolve(mut_repo)
-            .unwrap()
-            .evaluate(mut_repo)
-            .unwrap();
-        revset.iter().collect()
+    let resolve = |revset_str: &str| -> Vec<CommitId> {
+        resolve_commit_ids_in_workspace(
+            mut_repo,
+            revset_str,
+            &test_workspace.workspace,
+            Some(test_workspace.workspace.workspace_root()),
+        )
     };
 
-    assert_eq!(resolve(&added_clean_clean), vec![commit1.id().clone()]);
+    // Same as file() if all ancestors are included
+    assert_eq!(resolve("file_within(file1)"), vec![commit2.id().clone()]);
     assert_eq!(
-        resolve(&added_modified_clean),
-        vec![commit2.id().clone(), commit1.id().clone()]
+        resolve(&format!(
+            "({} | {} | {}) & file_within(file1)",
+            commit1.id().hex(),
+            commit2.id().hex(),
+            commit3.id().hex()
+        )),
+        vec![commit2.id().clone()]
     );
+    // The change in commit2 is attributed to commit3 if commit2 is excluded
     assert_eq!(
-        resolve(&added_modified_removed),
-        vec![
-            commit3.id().clone(),
-            commit2.id().clone(),
-            commit1.id().clone()
-        ]
+        resolve(&format!(
+            "({} | {}) & file(file1)",
+            commit1.id().hex(),
+            commit3.id().hex()
+        )),
+        vec![]
     );
-
-    // file() revset:
     assert_eq!(
-        resolve_commit_ids_in_workspace(
-            mut_repo,
-            r#"file("repo/added_clean_clean")"#,
-            &test_workspace.workspace,
-            Some(test_workspace.workspace.workspace_root().parent().unwrap()),
-        ),
-        vec![commit1.id().clone()]
+        resolve(&format!(
+            "({} | {}) & file_within(file1)",
+            commit1.id().hex(),
+            commit3.id().hex()
+        )),
+        vec![commit3.id().clone()]
     );
+    // Compared to the root tree if no ancestor is included
     assert_eq!(
-        resolve_commit_ids_in_workspace(
-            mut_repo,
-            &format!(r#"{}: & file("added_modified_clean")"#, commit2.id().hex()),
-            &test_workspace.workspace,
-            Some(test_workspace.workspace.workspace_root()),
-        ),
-        vec![commit2.id().clone()]
+        resolve(&format!("{} & file_within(file1)", commit3.id().hex())),
+        vec![commit3.id().clone()]
     );
-
-    // empty() revset, which is identical to ~file(".")
     assert_eq!(
-        resolve_commit_ids(mut_repo, &format!("{}: & empty()", commit1.id().hex())),
-        vec![commit4.id().clone()]
+        resolve(&format!("{} & file_within(file1)", commit1.id().hex())),
+        vec![]
+    );
+    // Can be negated within the candidate set
+    assert_eq!(
+        resolve(&format!(
+            "({} | {}) ~ file_within(file1)",
+            commit1.id().hex(),
+            commit3.id().hex()
+        )),
+        vec![commit1.id().clone()]
     );
 }
 
@@ -2447,6 +4635,17 @@ fn test_change_id_index() {
     assert_eq!(prefix_len(&commit_3), 2);
     assert_eq!(prefix_len(&commit_4), 1);
     assert_eq!(prefix_len(&commit_5), 1);
+    // The batch computation agrees with the per-commit lookup
+    assert_eq!(
+        change_id_index.shortest_unique_prefix_lens(),
+        revset
+            .iter()
+            .map(|id| {
+                let len = prefix_len(&repo.store().get_commit(&id).unwrap());
+                (id, len)
+            })
+            .collect_vec()
+    );
     let resolve_prefix =
         |prefix: &str| change_id_index.resolve_prefix(&HexPrefix::new(prefix).unwrap());
     // Ambiguous matches
@@ -1,8 +1,25 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{fs, io};
+
 use clap::Subcommand;
+use itertools::Itertools as _;
+use jujutsu_lib::matchers::EverythingMatcher;
+use jujutsu_lib::op_store::{OperationId, RefTarget, WorkspaceId};
 use jujutsu_lib::operation;
+use jujutsu_lib::operation::Operation;
+use jujutsu_lib::repo::Repo as _;
+use jujutsu_lib::store::Store;
+use jujutsu_lib::tree::Tree;
+use jujutsu_lib::view::View;
 
-use crate::cli_util::{user_error, CommandError, CommandHelper, LogContentFormat};
-use crate::graphlog::{get_graphlog, Edge};
+use crate::cli_util::{
+    short_commit_hash, short_operation_hash, user_error, CommandError, CommandHelper,
+    LogContentFormat,
+};
+use crate::diff_util::{self, DiffFormatArgs};
+use crate::graphlog::{get_compact_graphlog, get_graphlog, Edge};
 use crate::operation_templater;
 use crate::templater::Template as _;
 use crate::ui::Ui;
@@ -26,6 +43,33 @@ pub struct OperationLogArgs {
     /// For the syntax, see https://github.com/martinvonz/jj/blob/main/docs/templates.md
     #[arg(long, short = 'T')]
     template: Option<String>,
+    /// Render each operation using the template read from the given file
+    #[arg(long, conflicts_with = "template", value_hint = clap::ValueHint::FilePath)]
+    template_file: Option<PathBuf>,
+    /// Show changes to the working-copy commit made by each operation
+    #[arg(long, short = 'p')]
+    patch: bool,
+    #[command(flatten)]
+    diff_format: DiffFormatArgs,
+    /// Show only operations whose description contains the given text
+    #[arg(long, value_name = "TEXT")]
+    grep: Option<String>,
+    /// Show only operations run by the given user name
+    #[arg(long, value_name = "NAME")]
+    user: Option<String>,
+    /// Show only operations run on the given host name
+    #[arg(long, value_name = "NAME")]
+    host: Option<String>,
+    /// Draw a narrower graph, leaving more room for the operation text
+    #[arg(long)]
+    compact: bool,
+    /// Show all operation heads, not only the current one
+    ///
+    /// Concurrent operations leave multiple heads until they are merged by
+    /// the next command. The current operation is marked with `@`, and the
+    /// other heads with `*`.
+    #[arg(long)]
+    all_heads: bool,
 }
 
 /// Create a new operation that restores the repo to an earlier state
@@ -40,6 +84,13 @@ pub struct OperationRestoreArgs {
     /// --at-op=<operation ID> log` before restoring to an operation to see the
     /// state of the repo at that operation.
     operation: String,
+    /// Keep the current working-copy commit instead of restoring it
+    ///
+    /// Branches and other commits are still restored from the target
+    /// operation, but the working copy stays at its current commit so that
+    /// changes made since the target operation aren't lost.
+    #[arg(long)]
+    preserve_working_copy: bool,
 }
 
 /// Create a new operation that undoes an earlier operation
@@ -53,6 +104,9 @@ pub struct OperationUndoArgs {
     /// Use `jj op log` to find an operation to undo.
     #[arg(default_value = "@")]
     operation: String,
+    /// Only display what would change, without creating a new operation
+    #[arg(long)]
+    dry_run: bool,
 }
 
 fn cmd_op_log(
@@ -64,10 +118,27 @@ fn cmd_op_log(
     let repo = workspace_command.repo();
     let head_op = repo.operation().clone();
     let head_op_id = head_op.id().clone();
+    let mut head_ops = vec![head_op];
+    if args.all_heads {
+        for op_id in repo.op_heads_store().get_op_heads() {
+            if op_id != head_op_id {
+                let data = repo.op_store().read_operation(&op_id).map_err(|e| {
+                    CommandError::InternalError(format!("Failed to read operation: {e}"))
+                })?;
+                head_ops.push(Operation::new(repo.op_store().clone(), op_id, data));
+            }
+        }
+    }
 
-    let template_string = match &args.template {
-        Some(value) => value.to_owned(),
-        None => command.settings().config().get_string("templates.op_log")?,
+    let template_string = match (&args.template, &args.template_file) {
+        (Some(value), _) => value.to_owned(),
+        (None, Some(path)) => fs::read_to_string(path).map_err(|err| {
+            user_error(format!(
+                "Failed to read template file {path}: {err}",
+                path = path.display()
+            ))
+        })?,
+        (None, None) => command.settings().config().get_string("templates.op_log")?,
     };
     let template = operation_templater::parse(
         repo,
@@ -75,29 +146,107 @@ fn cmd_op_log(
         workspace_command.template_aliases_map(),
     )?;
     let with_content_format = LogContentFormat::new(ui, command.settings())?;
+    let diff_formats =
+        diff_util::diff_formats_for_log(command.settings(), &args.diff_format, args.patch);
 
     ui.request_pager();
     let mut formatter = ui.stdout_formatter();
     let formatter = formatter.as_mut();
-    let mut graph = get_graphlog(command.settings(), formatter.raw());
+    let mut graph = if args.compact {
+        get_compact_graphlog(command.settings(), formatter.raw())
+    } else {
+        get_graphlog(command.settings(), formatter.raw())
+    };
     let default_node_symbol = graph.default_node_symbol().to_owned();
-    for op in operation::walk_ancestors(&head_op) {
+    let op_matches = |op: &Operation| {
+        let metadata = &op.store_operation().metadata;
+        args.grep
+            .as_ref()
+            .map_or(true, |text| metadata.description.contains(text.as_str()))
+            && args
+                .user
+                .as_ref()
+                .map_or(true, |name| &metadata.username == name)
+            && args
+                .host
+                .as_ref()
+                .map_or(true, |name| &metadata.hostname == name)
+    };
+    let elided_op_targets = if args.grep.is_some() || args.user.is_some() || args.host.is_some() {
+        nearest_matching_ancestors(&head_ops, op_matches)
+    } else {
+        HashMap::new()
+    };
+    for op in operation::walk_ancestors_of_heads(&head_ops) {
+        let op = match op {
+            Ok(op) => op,
+            Err(err) => {
+                // Parents that can't be loaded are rendered as placeholders.
+                // All operations pointing to them have been rendered already.
+                let op_hash = short_operation_hash(&err.id);
+                writeln!(ui.warning(), "Warning: Operation {op_hash} is missing")?;
+                graph.add_node(
+                    &err.id,
+                    &[],
+                    &default_node_symbol,
+                    &format!("missing operation {op_hash}\n"),
+                )?;
+                continue;
+            }
+        };
+        if !op_matches(&op) {
+            continue;
+        }
         let mut edges = vec![];
-        for parent in op.parents() {
-            edges.push(Edge::direct(parent.id().clone()));
+        for (parent_id, parent) in op.parent_ids().iter().zip(op.try_parents()) {
+            match parent {
+                Ok(parent) if op_matches(&parent) => {
+                    edges.push(Edge::direct(parent_id.clone()));
+                }
+                Ok(_) => {
+                    for target in &elided_op_targets[parent_id] {
+                        edges.push(Edge::indirect(target.clone()));
+                    }
+                }
+                Err(_) => edges.push(Edge::direct(parent_id.clone())),
+            }
         }
         let is_head_op = op.id() == &head_op_id;
         let mut buffer = vec![];
         with_content_format.write_graph_text(
             ui.new_formatter(&mut buffer).as_mut(),
-            |formatter| formatter.with_label("op_log", |formatter| template.format(&op, formatter)),
+            |formatter| {
+                formatter.with_label("op_log", |formatter| match operation_kind_label(&op) {
+                    Some(kind) => {
+                        formatter.with_label(kind, |formatter| template.format(&op, formatter))
+                    }
+                    None => template.format(&op, formatter),
+                })
+            },
             || graph.width(op.id(), &edges),
         )?;
         if !buffer.ends_with(b"\n") {
             buffer.push(b'\n');
         }
+        if !diff_formats.is_empty() {
+            if let Some((from_tree, to_tree)) =
+                working_copy_trees_for_op(repo.store(), workspace_command.workspace_id(), &op)
+            {
+                let mut formatter = ui.new_formatter(&mut buffer);
+                diff_util::show_diff(
+                    formatter.as_mut(),
+                    &workspace_command,
+                    &from_tree,
+                    &to_tree,
+                    &EverythingMatcher,
+                    &diff_formats,
+                )?;
+            }
+        }
         let node_symbol = if is_head_op {
             "@"
+        } else if head_ops.iter().any(|head_op| head_op.id() == op.id()) {
+            "*"
         } else {
             &default_node_symbol
         };
@@ -108,10 +257,92 @@ fn cmd_op_log(
             &String::from_utf8_lossy(&buffer),
         )?;
     }
-
     Ok(())
 }
 
+/// Maps each ancestor of `head_ops` not matching `op_matches` to its nearest
+/// ancestors that do match, so the graph can connect them with indirect edges.
+///
+/// Parents that can't be loaded are treated as matching.
+fn nearest_matching_ancestors(
+    head_ops: &[Operation],
+    op_matches: impl Fn(&Operation) -> bool,
+) -> HashMap<OperationId, Vec<OperationId>> {
+    // Missing operations have no parents to map
+    let ops = operation::walk_ancestors_of_heads(head_ops)
+        .filter_map(Result::ok)
+        .collect_vec();
+    let mut targets_map: HashMap<OperationId, Vec<OperationId>> = HashMap::new();
+    // Visit parents before children
+    for op in ops.iter().rev() {
+        if op_matches(op) {
+            continue;
+        }
+        let mut targets = vec![];
+        for (parent_id, parent) in op.parent_ids().iter().zip(op.try_parents()) {
+            match parent {
+                Ok(parent) if op_matches(&parent) => targets.push(parent_id.clone()),
+                Ok(_) => targets.extend(targets_map[parent_id].iter().cloned()),
+                Err(_) => targets.push(parent_id.clone()),
+            }
+        }
+        let targets = targets.into_iter().unique().collect_vec();
+        targets_map.insert(op.id().clone(), targets);
+    }
+    targets_map
+}
+
+/// Returns the trees of the working-copy commit before and after the
+/// operation, or `None` if the operation didn't move the working copy.
+///
+/// Merge operations have no single state to compare with, so `None` is
+/// returned for them too.
+fn working_copy_trees_for_op(
+    store: &Arc<Store>,
+    workspace_id: &WorkspaceId,
+    op: &Operation,
+) -> Option<(Tree, Tree)> {
+    let [parent_op]: [_; 1] = op.try_parents().try_into().ok()?;
+    let parent_op = parent_op.ok()?;
+    let to_id = op
+        .view()
+        .store_view()
+        .wc_commit_ids
+        .get(workspace_id)?
+        .clone();
+    let from_id = parent_op
+        .view()
+        .store_view()
+        .wc_commit_ids
+        .get(workspace_id)?
+        .clone();
+    if from_id == to_id {
+        return None;
+    }
+    let from_commit = store.get_commit(&from_id).ok()?;
+    let to_commit = store.get_commit(&to_id).ok()?;
+    Some((from_commit.tree(), to_commit.tree()))
+}
+
+/// Returns a label for the kind of the operation.
+///
+/// Operations don't record their kind, so it's inferred from the description
+/// set by the command that created the operation.
+fn operation_kind_label(op: &Operation) -> Option<&'static str> {
+    const KINDS: &[(&str, &str)] = &[
+        ("snapshot working copy", "snapshot"),
+        ("undo operation ", "undo"),
+        ("restore to operation ", "restore"),
+        ("import git refs", "git_import"),
+        ("export git refs", "git_export"),
+    ];
+    let description = &op.store_operation().metadata.description;
+    KINDS
+        .iter()
+        .find(|(prefix, _)| description.starts_with(prefix))
+        .map(|&(_, label)| label)
+}
+
 pub fn cmd_op_undo(
     ui: &mut Ui,
     command: &CommandHelper,
@@ -133,6 +364,16 @@ pub fn cmd_op_undo(
     let bad_repo = repo_loader.load_at(&bad_op);
     let parent_repo = repo_loader.load_at(&parent_ops[0]);
     tx.mut_repo().merge(&bad_repo, &parent_repo);
+    if args.dry_run {
+        writeln!(
+            ui,
+            "Undoing operation {} would change:",
+            short_operation_hash(bad_op.id())
+        )?;
+        write_view_changes(ui, tx.base_repo().view(), tx.repo().view())?;
+        writeln!(ui, "Dry-run requested, not undoing.")?;
+        return Ok(());
+    }
     tx.finish(ui)?;
 
     Ok(())
@@ -147,12 +388,95 @@ fn cmd_op_restore(
     let target_op = workspace_command.resolve_single_op(&args.operation)?;
     let mut tx = workspace_command
         .start_transaction(&format!("restore to operation {}", target_op.id().hex()));
+    let wc_commit = if args.preserve_working_copy {
+        tx.base_workspace_helper().get_wc_commit_id().cloned()
+    } else {
+        None
+    };
     tx.mut_repo().set_view(target_op.view().take_store_view());
+    if let Some(wc_commit_id) = wc_commit {
+        let wc_commit = tx.base_repo().store().get_commit(&wc_commit_id)?;
+        let workspace_id = tx.base_workspace_helper().workspace_id().clone();
+        tx.mut_repo().add_head(&wc_commit);
+        tx.mut_repo().set_wc_commit(workspace_id, wc_commit_id)?;
+    }
     tx.finish(ui)?;
 
     Ok(())
 }
 
+/// Prints the heads, working-copy commits, and branches that differ between the
+/// two views.
+fn write_view_changes(ui: &mut Ui, old_view: &View, new_view: &View) -> io::Result<()> {
+    let mut changed = false;
+    for id in old_view.heads().difference(new_view.heads()).sorted() {
+        writeln!(ui, "  Remove head {}", short_commit_hash(id))?;
+        changed = true;
+    }
+    for id in new_view.heads().difference(old_view.heads()).sorted() {
+        writeln!(ui, "  Add head {}", short_commit_hash(id))?;
+        changed = true;
+    }
+    let workspace_ids: BTreeSet<_> = old_view
+        .wc_commit_ids()
+        .keys()
+        .chain(new_view.wc_commit_ids().keys())
+        .collect();
+    for workspace_id in workspace_ids {
+        let old_id = old_view.get_wc_commit_id(workspace_id);
+        let new_id = new_view.get_wc_commit_id(workspace_id);
+        if old_id != new_id {
+            let workspace_name = workspace_id.as_str();
+            match new_id {
+                Some(id) => writeln!(
+                    ui,
+                    "  Move working copy of workspace {workspace_name} to {}",
+                    short_commit_hash(id)
+                )?,
+                None => writeln!(ui, "  Forget workspace {workspace_name}")?,
+            }
+            changed = true;
+        }
+    }
+    let branch_names: BTreeSet<&str> = old_view
+        .branches()
+        .keys()
+        .chain(new_view.branches().keys())
+        .map(String::as_str)
+        .collect();
+    for name in branch_names {
+        let old_target = old_view.get_local_branch(name);
+        let new_target = new_view.get_local_branch(name);
+        if old_target == new_target {
+            continue;
+        }
+        match (old_target, new_target) {
+            (None, Some(target)) => {
+                writeln!(ui, "  Add branch {name} to {}", format_ref_target(&target))?;
+            }
+            (Some(_), Some(target)) => {
+                writeln!(ui, "  Move branch {name} to {}", format_ref_target(&target))?;
+            }
+            (Some(_), None) => {
+                writeln!(ui, "  Delete branch {name}")?;
+            }
+            (None, None) => unreachable!(),
+        }
+        changed = true;
+    }
+    if !changed {
+        writeln!(ui, "  Nothing")?;
+    }
+    Ok(())
+}
+
+fn format_ref_target(target: &RefTarget) -> String {
+    match target {
+        RefTarget::Normal(id) => short_commit_hash(id),
+        RefTarget::Conflict { .. } => "a conflicted target".to_owned(),
+    }
+}
+
 pub fn cmd_operation(
     ui: &mut Ui,
     command: &CommandHelper,
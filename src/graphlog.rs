@@ -150,6 +150,77 @@ pub fn get_graphlog<'a, K: Clone + Eq + Hash + 'a>(
     }
 }
 
+/// Like `get_graphlog()`, but the text is drawn right after the graph instead
+/// of one column apart from it.
+pub fn get_compact_graphlog<'a, K: Clone + Eq + Hash + 'a>(
+    settings: &UserSettings,
+    formatter: &'a mut dyn Write,
+) -> Box<dyn GraphLog<K> + 'a> {
+    let builder = GraphRowRenderer::new().output().with_min_row_height(0);
+
+    match settings.graph_style().as_str() {
+        "curved" => {
+            SaplingGraphLog::create(CompactRenderer(builder.build_box_drawing()), formatter, "◉")
+        }
+        "square" => SaplingGraphLog::create(
+            CompactRenderer(builder.build_box_drawing().with_square_glyphs()),
+            formatter,
+            "◉",
+        ),
+        "ascii" => SaplingGraphLog::create(CompactRenderer(builder.build_ascii()), formatter, "o"),
+        "ascii-large" => {
+            SaplingGraphLog::create(CompactRenderer(builder.build_ascii_large()), formatter, "o")
+        }
+        // The legacy drawer already puts the text right after the graph
+        _ => Box::new(AsciiGraphDrawer::new(formatter)),
+    }
+}
+
+/// Removes the space separating the graph from the text in each row rendered
+/// by the inner renderer.
+struct CompactRenderer<R>(R);
+
+impl<K, R> Renderer<K> for CompactRenderer<R>
+where
+    R: Renderer<K, Output = String>,
+{
+    type Output = String;
+
+    fn width(&self, node: Option<&K>, parents: Option<&Vec<Ancestor<K>>>) -> u64 {
+        self.0.width(node, parents).saturating_sub(1)
+    }
+
+    fn reserve(&mut self, node: K) {
+        self.0.reserve(node);
+    }
+
+    fn next_row(
+        &mut self,
+        node: K,
+        parents: Vec<Ancestor<K>>,
+        glyph: String,
+        message: String,
+    ) -> String {
+        // The width includes the separator after the graph columns of the row.
+        // Lines without text are trimmed, so they don't reach the separator.
+        let width = self.0.width(Some(&node), Some(&parents));
+        let separator_index = usize::try_from(width).unwrap().saturating_sub(1);
+        let row = self.0.next_row(node, parents, glyph, message);
+        row.split_inclusive('\n')
+            .map(|line| {
+                let mut chars = line.chars();
+                let graph: String = chars.by_ref().take(separator_index).collect();
+                let mut rest = chars.clone();
+                if rest.next() == Some(' ') && !rest.as_str().trim_end().is_empty() {
+                    graph + rest.as_str()
+                } else {
+                    graph + chars.as_str()
+                }
+            })
+            .collect()
+    }
+}
+
 pub struct AsciiGraphDrawer<'writer, K> {
     writer: &'writer mut dyn Write,
     edges: Vec<Edge<K>>,
@@ -467,6 +538,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn compact_renderer() {
+        let render = |renderer: &mut dyn Renderer<i32, Output = String>| {
+            let mut output = String::new();
+            output += &renderer.next_row(
+                3,
+                vec![Ancestor::Parent(2), Ancestor::Parent(1)],
+                "@".to_owned(),
+                "merge".to_owned(),
+            );
+            output += &renderer.next_row(
+                2,
+                vec![Ancestor::Parent(1)],
+                "o".to_owned(),
+                "left\n  indented".to_owned(),
+            );
+            output += &renderer.next_row(1, vec![], "o".to_owned(), "base".to_owned());
+            output
+        };
+        let builder = || GraphRowRenderer::new().output().with_min_row_height(0);
+
+        insta::assert_snapshot!(render(&mut builder().build_box_drawing()), @r###"
+        @    merge
+        ├─╮
+        o │  left
+        ├─╯    indented
+        o  base
+        "###);
+        insta::assert_snapshot!(
+            render(&mut CompactRenderer(builder().build_box_drawing())), @r###"
+        @   merge
+        ├─╮
+        o │ left
+        ├─╯   indented
+        o base
+        "###);
+        insta::assert_snapshot!(render(&mut builder().build_ascii_large()), @r###"
+        @     merge
+        |\
+        | \
+        o  |  left
+        | /     indented
+        |/
+        o  base
+        "###);
+        insta::assert_snapshot!(
+            render(&mut CompactRenderer(builder().build_ascii_large())), @r###"
+        @    merge
+        |\
+        | \
+        o  | left
+        | /    indented
+        |/
+        o base
+        "###);
+    }
+
     #[test]
     fn single_node() -> io::Result<()> {
         let mut buffer = vec![];
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashMap;
 use std::io;
 
 use itertools::Itertools as _;
@@ -27,12 +28,12 @@ use crate::template_parser::{
     self, FunctionCallNode, TemplateAliasesMap, TemplateParseError, TemplateParseResult,
 };
 use crate::templater::{
-    IntoTemplate, PlainTextFormattedProperty, Template, TemplateFunction, TemplateProperty,
+    self, IntoTemplate, PlainTextFormattedProperty, Template, TemplateFunction, TemplateProperty,
     TemplatePropertyFn, TimestampRange,
 };
 
 struct OperationTemplateLanguage<'b> {
-    head_op_id: &'b OperationId,
+    head_op: &'b Operation,
 }
 
 impl TemplateLanguage<'static> for OperationTemplateLanguage<'_> {
@@ -58,6 +59,15 @@ impl TemplateLanguage<'static> for OperationTemplateLanguage<'_> {
             OperationTemplatePropertyKind::OperationId(property) => {
                 build_operation_id_method(self, build_ctx, property, function)
             }
+            OperationTemplatePropertyKind::OperationIdList(property) => {
+                template_builder::build_formattable_list_method(
+                    self,
+                    build_ctx,
+                    property,
+                    function,
+                    |item| self.wrap_operation_id(item),
+                )
+            }
         }
     }
 }
@@ -69,11 +79,19 @@ impl OperationTemplateLanguage<'_> {
     ) -> OperationTemplatePropertyKind {
         OperationTemplatePropertyKind::OperationId(Box::new(property))
     }
+
+    fn wrap_operation_id_list(
+        &self,
+        property: impl TemplateProperty<Operation, Output = Vec<OperationId>> + 'static,
+    ) -> OperationTemplatePropertyKind {
+        OperationTemplatePropertyKind::OperationIdList(Box::new(property))
+    }
 }
 
 enum OperationTemplatePropertyKind {
     Core(CoreTemplatePropertyKind<'static, Operation>),
     OperationId(Box<dyn TemplateProperty<Operation, Output = OperationId>>),
+    OperationIdList(Box<dyn TemplateProperty<Operation, Output = Vec<OperationId>>>),
 }
 
 impl IntoTemplateProperty<'static, Operation> for OperationTemplatePropertyKind {
@@ -105,6 +123,9 @@ impl IntoTemplateProperty<'static, Operation> for OperationTemplatePropertyKind
         match self {
             OperationTemplatePropertyKind::Core(property) => property.try_into_template(),
             OperationTemplatePropertyKind::OperationId(property) => Some(property.into_template()),
+            OperationTemplatePropertyKind::OperationIdList(property) => {
+                Some(property.into_template())
+            }
         }
     }
 }
@@ -124,14 +145,25 @@ fn build_operation_keyword(
     }
 
     let property = match name {
+        "command" => language.wrap_string(wrap_metadata_fn(|metadata| {
+            metadata.tags.get("args").cloned().unwrap_or_default()
+        })),
         "current_operation" => {
-            let head_op_id = language.head_op_id.clone();
+            let head_op_id = language.head_op.id().clone();
             language.wrap_boolean(wrap_fn(move |op| op.id() == &head_op_id))
         }
         "description" => {
             language.wrap_string(wrap_metadata_fn(|metadata| metadata.description.clone()))
         }
         "id" => language.wrap_operation_id(wrap_fn(|op| op.id().clone())),
+        "parent_ids" => language.wrap_operation_id_list(wrap_fn(|op| op.parent_ids().to_vec())),
+        "relative_position" => {
+            let distances = first_parent_distances(language.head_op);
+            language.wrap_string(wrap_fn(move |op| match distances.get(op.id()) {
+                Some(&distance) => format!("@{}", "-".repeat(distance)),
+                None => "".to_owned(),
+            }))
+        }
         "tags" => language.wrap_string(wrap_metadata_fn(|metadata| {
             // TODO: introduce map type
             metadata
@@ -153,12 +185,34 @@ fn build_operation_keyword(
     Ok(property)
 }
 
+/// Maps operations on the first-parent chain of `head_op` to their distance
+/// from it. The chain ends at the first parent that can't be loaded.
+fn first_parent_distances(head_op: &Operation) -> HashMap<OperationId, usize> {
+    let mut distances = HashMap::new();
+    let mut op = Some(head_op.clone());
+    while let Some(current_op) = op {
+        distances.insert(current_op.id().clone(), distances.len());
+        op = current_op
+            .try_parents()
+            .into_iter()
+            .next()
+            .and_then(Result::ok);
+    }
+    distances
+}
+
 impl Template<()> for OperationId {
     fn format(&self, _: &(), formatter: &mut dyn Formatter) -> io::Result<()> {
         formatter.write_str(&self.hex())
     }
 }
 
+impl Template<()> for Vec<OperationId> {
+    fn format(&self, _: &(), formatter: &mut dyn Formatter) -> io::Result<()> {
+        templater::format_joined(&(), formatter, self, " ")
+    }
+}
+
 fn build_operation_id_method(
     language: &OperationTemplateLanguage,
     build_ctx: &BuildContext<OperationTemplatePropertyKind>,
@@ -190,8 +244,8 @@ pub fn parse(
     template_text: &str,
     aliases_map: &TemplateAliasesMap,
 ) -> TemplateParseResult<Box<dyn Template<Operation>>> {
-    let head_op_id = repo.op_id();
-    let language = OperationTemplateLanguage { head_op_id };
+    let head_op = repo.operation();
+    let language = OperationTemplateLanguage { head_op };
     let node = template_parser::parse(template_text, aliases_map)?;
     template_builder::build(&language, &node)
 }
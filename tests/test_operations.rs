@@ -17,7 +17,7 @@ use std::path::Path;
 use itertools::Itertools;
 use regex::Regex;
 
-use crate::common::{get_stdout_string, TestEnvironment};
+use crate::common::{get_stderr_string, get_stdout_string, TestEnvironment};
 
 pub mod common;
 
@@ -154,6 +154,434 @@ fn test_op_log_template() {
     "###);
 }
 
+#[test]
+fn test_op_log_relative_position() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "description 0"]);
+    // Create a concurrent operation, which is merged by the next command
+    test_env.jj_cmd_success(
+        &repo_path,
+        &["describe", "--at-op", "@-", "-m", "description 1"],
+    );
+    let stdout = test_env.jj_cmd_success(
+        &repo_path,
+        &[
+            "op",
+            "log",
+            "-T",
+            r#"separate(" ", relative_position, description.first_line()) ++ "\n""#,
+        ],
+    );
+    insta::assert_snapshot!(stdout, @r###"
+    Concurrent modification detected, resolving automatically.
+    @    @ resolve concurrent operations
+    ├─╮
+    ◉ │  @- describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    │ ◉  describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    ├─╯
+    ◉  @-- add workspace 'default'
+    ◉  @--- initialize repo
+    "###);
+}
+
+#[test]
+fn test_op_log_kind_labels() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    std::fs::write(repo_path.join("file"), "contents").unwrap();
+    test_env.jj_cmd_success(&repo_path, &["status"]);
+    test_env.jj_cmd_success(&repo_path, &["undo"]);
+    test_env.add_config(
+        r#"
+        colors."op_log snapshot" = "green"
+        colors."op_log undo" = "red"
+        "#,
+    );
+
+    let stdout = test_env.jj_cmd_success(
+        &repo_path,
+        &[
+            "op",
+            "log",
+            "--color=always",
+            "--ignore-working-copy",
+            "-T",
+            r#"description.first_line() ++ "\n""#,
+        ],
+    );
+    insta::assert_snapshot!(stdout, @r###"
+    @  [38;5;1mundo operation 02474e6ef288246be3d8d1b9d65fa5ccd6c32784ab3d2f7cd662db5bfad6a0f3079749b62284b30bf6196e33c7eb9c3b261f2c11d113ba1a9af1e94eb28a5e7b[39m
+    ◉  [38;5;2msnapshot working copy[39m
+    ◉  add workspace 'default'
+    ◉  initialize repo
+    "###);
+}
+
+#[test]
+fn test_op_log_patch() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    std::fs::write(repo_path.join("file"), "a\n").unwrap();
+    test_env.jj_cmd_success(&repo_path, &["status"]);
+    std::fs::write(repo_path.join("file"), "b\n").unwrap();
+    test_env.jj_cmd_success(&repo_path, &["status"]);
+    let render = |extra_args: &[&str]| {
+        let mut args = vec![
+            "op",
+            "log",
+            "--ignore-working-copy",
+            "-T",
+            r#"description.first_line() ++ "\n""#,
+        ];
+        args.extend_from_slice(extra_args);
+        test_env.jj_cmd_success(&repo_path, &args)
+    };
+    insta::assert_snapshot!(render(&["--summary"]), @r###"
+    @  snapshot working copy
+    │  M file
+    ◉  snapshot working copy
+    │  A file
+    ◉  add workspace 'default'
+    ◉  initialize repo
+    "###);
+    insta::assert_snapshot!(render(&["--patch"]), @r###"
+    @  snapshot working copy
+    │  Modified regular file file:
+    │     1    1: ab
+    ◉  snapshot working copy
+    │  Added regular file file:
+    │          1: a
+    ◉  add workspace 'default'
+    ◉  initialize repo
+    "###);
+    insta::assert_snapshot!(render(&["--patch", "--git"]), @r###"
+    @  snapshot working copy
+    │  diff --git a/file b/file
+    │  index 7898192261...6178079822 100644
+    │  --- a/file
+    │  +++ b/file
+    │  @@ -1,1 +1,1 @@
+    │  -a
+    │  +b
+    ◉  snapshot working copy
+    │  diff --git a/file b/file
+    │  new file mode 100644
+    │  index 0000000000..7898192261
+    │  --- /dev/null
+    │  +++ b/file
+    │  @@ -1,0 +1,1 @@
+    │  +a
+    ◉  add workspace 'default'
+    ◉  initialize repo
+    "###);
+}
+
+#[test]
+fn test_op_log_patch_color_words() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    std::fs::write(repo_path.join("file"), "foo bar baz\n").unwrap();
+    test_env.jj_cmd_success(&repo_path, &["status"]);
+    std::fs::write(repo_path.join("file"), "foo qux baz\n").unwrap();
+    test_env.jj_cmd_success(&repo_path, &["status"]);
+    // Only the changed word is highlighted
+    let stdout = test_env.jj_cmd_success(
+        &repo_path,
+        &[
+            "op",
+            "log",
+            "--ignore-working-copy",
+            "--color=always",
+            "-T",
+            r#"description.first_line() ++ "\n""#,
+            "--patch",
+            "--color-words",
+        ],
+    );
+    insta::assert_snapshot!(stdout, @r###"
+    @  snapshot working copy
+    │  [38;5;3mModified regular file file:[39m
+    │  [38;5;1m   1[39m [38;5;2m   1[39m: foo [38;5;1mbar[38;5;2mqux[39m baz
+    ◉  snapshot working copy
+    │  [38;5;3mAdded regular file file:[39m
+    │       [38;5;2m   1[39m: [38;5;2mfoo bar baz[39m
+    ◉  add workspace 'default'
+    ◉  initialize repo
+    "###);
+}
+
+#[test]
+fn test_op_log_all_heads() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "message 1"]);
+    test_env.jj_cmd_success(
+        &repo_path,
+        &["describe", "-m", "message 2", "--at-op", "@-"],
+    );
+    // The concurrent operations haven't been merged yet
+    let op_head_ids = std::fs::read_dir(repo_path.join(".jj/repo/op_heads/heads"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .sorted()
+        .collect_vec();
+    assert_eq!(op_head_ids.len(), 2);
+
+    let render = |extra_args: &[&str]| {
+        let mut args = vec![
+            "op",
+            "log",
+            "--at-op",
+            &op_head_ids[0],
+            "-T",
+            r#"id.short() ++ " " ++ description.first_line() ++ "\n""#,
+        ];
+        args.extend_from_slice(extra_args);
+        test_env.jj_cmd_success(&repo_path, &args)
+    };
+    insta::assert_snapshot!(render(&[]), @r###"
+    @  7d9d48e269df describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    ◉  a99a3fd5c51e add workspace 'default'
+    ◉  56b94dfc38e7 initialize repo
+    "###);
+    insta::assert_snapshot!(render(&["--all-heads"]), @r###"
+    @  7d9d48e269df describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    │ *  d674026aac30 describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    ├─╯
+    ◉  a99a3fd5c51e add workspace 'default'
+    ◉  56b94dfc38e7 initialize repo
+    "###);
+}
+
+#[test]
+fn test_op_log_parent_ids() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "message 1"]);
+    test_env.jj_cmd_success(
+        &repo_path,
+        &["describe", "-m", "message 2", "--at-op", "@-"],
+    );
+    // Merges the concurrent operations
+    test_env.jj_cmd_success(&repo_path, &["log"]);
+
+    let template =
+        r#"id.short(4) ++ " parents: [" ++ parent_ids.map(|id| id.short(4)).join(", ") ++ "]\n""#;
+    let stdout = test_env.jj_cmd_success(&repo_path, &["op", "log", "-T", template]);
+    insta::assert_snapshot!(stdout, @r###"
+    @    c60c parents: [7d9d, d674]
+    ├─╮
+    ◉ │  7d9d parents: [a99a]
+    │ ◉  d674 parents: [a99a]
+    ├─╯
+    ◉  a99a parents: [56b9]
+    ◉  56b9 parents: []
+    "###);
+}
+
+#[test]
+fn test_op_log_command() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "message 1"]);
+
+    let template = r#"description.first_line() ++ " [" ++ command ++ "]\n""#;
+    let stdout = test_env.jj_cmd_success(&repo_path, &["op", "log", "-T", template]);
+    insta::assert_snapshot!(stdout, @r###"
+    @  describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22 [jj describe -m 'message 1']
+    ◉  add workspace 'default' []
+    ◉  initialize repo []
+    "###);
+}
+
+#[test]
+fn test_op_log_template_file() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    let template_path = test_env.env_root().join("op_log.template");
+    std::fs::write(
+        &template_path,
+        r#"id.short(4) ++ " " ++ description.first_line() ++ "\n""#,
+    )
+    .unwrap();
+    let template_path_str = template_path.to_str().unwrap();
+
+    let stdout = test_env.jj_cmd_success(
+        &repo_path,
+        &["op", "log", "--template-file", template_path_str],
+    );
+    insta::assert_snapshot!(stdout, @r###"
+    @  a99a add workspace 'default'
+    ◉  56b9 initialize repo
+    "###);
+
+    let stderr = test_env.jj_cmd_failure(
+        &repo_path,
+        &["op", "log", "--template-file", "nonexistent.template"],
+    );
+    // The OS error message is platform-specific
+    assert!(
+        stderr.starts_with("Error: Failed to read template file nonexistent.template: "),
+        "{stderr}"
+    );
+
+    let stderr = test_env.jj_cmd_cli_error(
+        &repo_path,
+        &[
+            "op",
+            "log",
+            "-T",
+            "id",
+            "--template-file",
+            template_path_str,
+        ],
+    );
+    insta::assert_snapshot!(stderr.lines().next().unwrap(), @r###"
+    error: the argument '--template <TEMPLATE>' cannot be used with '--template-file <TEMPLATE_FILE>'
+    "###);
+}
+
+#[test]
+fn test_op_log_grep() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "description 0"]);
+    test_env.jj_cmd_success(&repo_path, &["new"]);
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "description 1"]);
+    test_env.jj_cmd_success(&repo_path, &["undo"]);
+    let render = |text: &str| {
+        test_env.jj_cmd_success(
+            &repo_path,
+            &[
+                "op",
+                "log",
+                "-T",
+                r#"description.first_line() ++ "\n" ++ tags ++ "\n""#,
+                "--grep",
+                text,
+            ],
+        )
+    };
+    insta::assert_snapshot!(render("undo"), @r###"
+    @  undo operation 6c9c27354986b5c6cf45d3e703f2df31d726de5ba87ecd93b587cead58399d47f0417d4ca7d366943f0bc14d0f379e1f79405020fe888e49561825661b35372f
+       args: jj undo
+    "###);
+    // Elided operations are replaced by indirect edges
+    insta::assert_snapshot!(render("describe"), @r###"
+    ◉  describe commit 089a2cc08673269650f0851a7498eeae498928a8
+    ╷  args: jj describe -m 'description 1'
+    ◉  describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+       args: jj describe -m 'description 0'
+    "###);
+    insta::assert_snapshot!(render("initialize"), @r###"
+    ◉  initialize repo
+
+    "###);
+    insta::assert_snapshot!(render("nonexistent"), @"");
+}
+
+#[test]
+fn test_op_log_user_host() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(
+        &repo_path,
+        &[
+            "describe",
+            "-m",
+            "description 0",
+            "--config-toml",
+            r#"operation.username = "other-username""#,
+        ],
+    );
+    test_env.jj_cmd_success(
+        &repo_path,
+        &[
+            "describe",
+            "-m",
+            "description 1",
+            "--config-toml",
+            r#"operation.hostname = "other.example.com""#,
+        ],
+    );
+    let render = |extra_args: &[&str]| {
+        let mut args = vec![
+            "op",
+            "log",
+            "-T",
+            r#"description.first_line() ++ "\n" ++ user ++ "\n""#,
+        ];
+        args.extend_from_slice(extra_args);
+        test_env.jj_cmd_success(&repo_path, &args)
+    };
+    insta::assert_snapshot!(render(&["--user", "other-username"]), @r###"
+    ◉  describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+       other-username@host.example.com
+    "###);
+    // Elided operations are replaced by indirect edges
+    insta::assert_snapshot!(render(&["--user", "test-username"]), @r###"
+    @  describe commit bc8f18aa6f396a93572811632313cbb5625d475d
+    ╷  test-username@other.example.com
+    ◉  add workspace 'default'
+    │  test-username@host.example.com
+    ◉  initialize repo
+       test-username@host.example.com
+    "###);
+    insta::assert_snapshot!(render(&["--host", "other.example.com"]), @r###"
+    @  describe commit bc8f18aa6f396a93572811632313cbb5625d475d
+       test-username@other.example.com
+    "###);
+    insta::assert_snapshot!(
+        render(&["--user", "test-username", "--host", "host.example.com"]), @r###"
+    ◉  add workspace 'default'
+    │  test-username@host.example.com
+    ◉  initialize repo
+       test-username@host.example.com
+    "###);
+    insta::assert_snapshot!(render(&["--user", "nonexistent"]), @"");
+}
+
+#[test]
+fn test_op_log_compact() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "description 0"]);
+    let render = |extra_args: &[&str]| {
+        let mut args = vec!["op", "log", "-T", r#"description.first_line() ++ "\n""#];
+        args.extend_from_slice(extra_args);
+        test_env.jj_cmd_success(&repo_path, &args)
+    };
+    let default_output = render(&[]);
+    let compact_output = render(&["--compact"]);
+    insta::assert_snapshot!(compact_output, @r###"
+    @ describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    ◉ add workspace 'default'
+    ◉ initialize repo
+    "###);
+    let max_width = |output: &str| output.lines().map(|line| line.chars().count()).max();
+    assert!(max_width(&compact_output) < max_width(&default_output));
+
+    // The configured graph style is kept
+    insta::assert_snapshot!(
+        render(&["--compact", "--config-toml=ui.graph.style='ascii'"]), @r###"
+    @ describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    o add workspace 'default'
+    o initialize repo
+    "###);
+}
+
 #[test]
 fn test_op_log_builtin_templates() {
     let test_env = TestEnvironment::default();
@@ -226,6 +654,101 @@ fn test_op_log_word_wrap() {
     "###);
 }
 
+#[test]
+fn test_op_log_missing_parent() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_success(&repo_path, &["describe", "-m", "description 0"]);
+    let op_log = test_env.jj_cmd_success(&repo_path, &["op", "log", "-T", r#""[" ++ id ++ "]""#]);
+    let op_ids = op_log
+        .lines()
+        .filter_map(|line| line.split_once('[')?.1.strip_suffix(']'))
+        .collect_vec();
+    // Remove the "add workspace" operation from the op store
+    let missing_op_id = op_ids[1];
+    std::fs::remove_file(
+        repo_path
+            .join(".jj")
+            .join("repo")
+            .join("op_store")
+            .join("operations")
+            .join(missing_op_id),
+    )
+    .unwrap();
+
+    let assert = test_env
+        .jj_cmd(&repo_path, &["op", "log", "-T", "description"])
+        .assert()
+        .success();
+    insta::assert_snapshot!(get_stdout_string(&assert), @r###"
+    @  describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    ◉  missing operation a99a3fd5c51e
+    "###);
+    insta::assert_snapshot!(get_stderr_string(&assert), @r###"
+    Warning: Operation a99a3fd5c51e is missing
+    "###);
+
+    // The relative positions stop at the missing operation
+    let assert = test_env
+        .jj_cmd(
+            &repo_path,
+            &[
+                "op",
+                "log",
+                "-T",
+                r#"separate(" ", relative_position, description.first_line()) ++ "\n""#,
+            ],
+        )
+        .assert()
+        .success();
+    insta::assert_snapshot!(get_stdout_string(&assert), @r###"
+    @  @ describe commit 230dd059e1b059aefc0da06a2e5a7dbf22362f22
+    ◉  missing operation a99a3fd5c51e
+    "###);
+}
+
+#[test]
+fn test_op_restore_preserve_working_copy() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+    let stdout = test_env.jj_cmd_success(&repo_path, &["op", "log", "-T", r#"id ++ "\n""#]);
+    let add_workspace_id = stdout
+        .lines()
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .nth(1)
+        .unwrap();
+    std::fs::write(repo_path.join("file1"), "a\n").unwrap();
+    test_env.jj_cmd_success(&repo_path, &["branch", "create", "branch1"]);
+    // Uncommitted change, which was never snapshotted before the restore
+    std::fs::write(repo_path.join("file2"), "b\n").unwrap();
+
+    test_env.jj_cmd_success(
+        &repo_path,
+        &["op", "restore", "--preserve-working-copy", add_workspace_id],
+    );
+    // The branch is restored, but the working-copy changes are kept
+    let stdout = test_env.jj_cmd_success(&repo_path, &["branch", "list"]);
+    insta::assert_snapshot!(stdout, @"");
+    let stdout = test_env.jj_cmd_success(&repo_path, &["diff", "-s"]);
+    insta::assert_snapshot!(stdout, @r###"
+    A file1
+    A file2
+    "###);
+    assert!(repo_path.join("file1").exists());
+    assert!(repo_path.join("file2").exists());
+
+    // Without the flag, the working copy is restored too
+    test_env.jj_cmd_success(&repo_path, &["op", "restore", add_workspace_id]);
+    let stdout = test_env.jj_cmd_success(&repo_path, &["diff", "-s"]);
+    insta::assert_snapshot!(stdout, @"");
+    assert!(!repo_path.join("file1").exists());
+    assert!(!repo_path.join("file2").exists());
+}
+
 #[test]
 fn test_op_log_configurable() {
     let test_env = TestEnvironment::default();
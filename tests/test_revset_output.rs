@@ -231,7 +231,7 @@ fn test_function_name_hint() {
       | ^-----^
       |
       = Revset function "author_" doesn't exist
-    Hint: Did you mean "author", "my_author"?
+    Hint: Did you mean "author", "author_email", "my_author"?
     "###);
 }
 
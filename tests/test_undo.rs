@@ -46,3 +46,33 @@ fn test_undo_rewrite_with_child() {
     ◉
     "###);
 }
+
+#[test]
+fn test_undo_dry_run() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_success(test_env.env_root(), &["init", "repo", "--git"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    test_env.jj_cmd_success(&repo_path, &["new", "-m", "first"]);
+    test_env.jj_cmd_success(&repo_path, &["branch", "create", "foo"]);
+    let op_log_before = test_env.jj_cmd_success(&repo_path, &["op", "log"]);
+
+    let stdout = test_env.jj_cmd_success(&repo_path, &["undo", "--dry-run"]);
+    insta::assert_snapshot!(stdout, @r###"
+    Undoing operation 4e87a6005566 would change:
+      Delete branch foo
+    Dry-run requested, not undoing.
+    "###);
+
+    // No new operation is recorded
+    let op_log_after = test_env.jj_cmd_success(&repo_path, &["op", "log"]);
+    assert_eq!(op_log_before, op_log_after);
+
+    // Undoing an operation that moved the working copy
+    let stdout = test_env.jj_cmd_success(&repo_path, &["op", "undo", "--dry-run", "@-"]);
+    insta::assert_snapshot!(stdout, @r###"
+    Undoing operation 37a5a32ea48b would change:
+      Move working copy of workspace default to 230dd059e1b0
+    Dry-run requested, not undoing.
+    "###);
+}